@@ -0,0 +1,153 @@
+//! Static analysis of a ROM: which instructions can be reached from the entry point by following
+//! jumps, skips and calls, and how the program is divided into subroutines.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::chip8::{
+    self,
+    cpu::{Operation, OPCODE_SIZE},
+    mem::RAM_START,
+};
+
+/// An instruction that can be reached from the entry point
+pub struct Instruction {
+    pub addr: u16,
+    /// `None` if the address can't be read
+    pub opcode: Option<u16>,
+    pub op: Result<Operation, chip8::Error>,
+}
+
+/// How control can pass from an instruction to the next one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Edge {
+    /// Falling through to the following instruction
+    Next,
+    /// Skipping the following instruction
+    Skip,
+    Jump,
+    /// Entering a subroutine. The instruction after the call is reached through a `Next` edge,
+    /// assuming the subroutine returns.
+    Call,
+}
+
+#[derive(Default)]
+pub struct Subroutine {
+    /// The instructions reachable from the entry without entering other subroutines
+    pub instructions: BTreeSet<u16>,
+    /// The call sites in the subroutine and the subroutines they call
+    pub calls: BTreeMap<u16, u16>,
+    /// Whether any of the instructions is a `Return`
+    pub returns: bool,
+}
+
+pub struct Analysis {
+    pub instructions: BTreeMap<u16, Instruction>,
+    /// The subroutines by entry address, including the main program at `RAM_START`
+    pub subroutines: BTreeMap<u16, Subroutine>,
+    /// Addresses loaded into I by `SetAddr`, along with the instructions loading them
+    pub data: BTreeMap<u16, BTreeSet<u16>>,
+    /// `JumpWithOffset` instructions, whose targets can't be followed
+    pub indirect: BTreeSet<u16>,
+    /// The address after the last byte of the ROM
+    pub end: u16,
+}
+
+impl Analysis {
+    pub fn new(rom: &[u8]) -> Analysis {
+        let mut emulator = chip8::Emulator::new(0);
+        let size = emulator.load(rom);
+
+        let mut analysis = Analysis {
+            instructions: BTreeMap::new(),
+            subroutines: BTreeMap::new(),
+            data: BTreeMap::new(),
+            indirect: BTreeSet::new(),
+            end: RAM_START + size as u16,
+        };
+
+        let mut entries = vec![RAM_START];
+        while let Some(entry) = entries.pop() {
+            if analysis.subroutines.contains_key(&entry) {
+                continue;
+            }
+            let mut subroutine = Subroutine::default();
+            let mut pending = vec![entry];
+            while let Some(addr) = pending.pop() {
+                if !subroutine.instructions.insert(addr) {
+                    continue;
+                }
+                let instruction = analysis.instructions.entry(addr).or_insert_with(|| {
+                    let opcode = emulator.mem.read_word(addr);
+                    let op = opcode.and_then(chip8::try_decode);
+                    Instruction { addr, opcode: opcode.ok(), op }
+                });
+                match instruction.op {
+                    Ok(Operation::Return) => subroutine.returns = true,
+                    Ok(Operation::SetAddr(data)) => {
+                        analysis.data.entry(data).or_default().insert(addr);
+                    }
+                    Ok(Operation::JumpWithOffset(_)) => {
+                        analysis.indirect.insert(addr);
+                    }
+                    _ => {}
+                }
+                for (to, edge) in successors(instruction) {
+                    match edge {
+                        Edge::Call => {
+                            subroutine.calls.insert(addr, to);
+                            entries.push(to);
+                        }
+                        _ => pending.push(to),
+                    }
+                }
+            }
+            analysis.subroutines.insert(entry, subroutine);
+        }
+        analysis
+    }
+}
+
+/// Where control can go after `instruction`. Returns and indirect jumps have no successors, and
+/// neither do instructions that fault.
+pub fn successors(instruction: &Instruction) -> Vec<(u16, Edge)> {
+    let addr = instruction.addr;
+    let next = addr.wrapping_add(OPCODE_SIZE);
+    let skip = addr.wrapping_add(2 * OPCODE_SIZE);
+    match instruction.op {
+        Ok(Operation::Jump(to)) => vec![(to, Edge::Jump)],
+        Ok(Operation::Call(to)) => vec![(to, Edge::Call), (next, Edge::Next)],
+        Ok(
+            Operation::SkipIfEq(..)
+            | Operation::SkipIfNotEq(..)
+            | Operation::SkipIfKeyPressed(_)
+            | Operation::SkipIfKeyNotPressed(_),
+        ) => vec![(next, Edge::Next), (skip, Edge::Skip)],
+        Ok(Operation::Return | Operation::JumpWithOffset(_) | Operation::CallRCA(_)) | Err(_) => {
+            vec![]
+        }
+        Ok(_) => vec![(next, Edge::Next)],
+    }
+}
+
+#[test]
+fn test_analysis() {
+    // Call a subroutine that draws the sprite at 0x210, skip a jump once V0 is 3, then halt
+    let rom = [
+        0x22, 0x0A, 0x30, 0x03, 0x12, 0x00, 0x12, 0x06, 0x00, 0x00, 0xA2, 0x10, 0xD0, 0x01, 0x00,
+        0xEE, 0x80,
+    ];
+    let analysis = Analysis::new(&rom);
+    assert_eq!(analysis.end, 0x211);
+    let addrs: Vec<_> = analysis.instructions.keys().copied().collect();
+    assert_eq!(addrs, [0x200, 0x202, 0x204, 0x206, 0x20a, 0x20c, 0x20e]);
+    assert_eq!(analysis.subroutines.keys().copied().collect::<Vec<_>>(), [0x200, 0x20a]);
+
+    let main = &analysis.subroutines[&0x200];
+    assert_eq!(main.calls, BTreeMap::from([(0x200, 0x20a)]));
+    assert!(!main.returns);
+    assert!(analysis.subroutines[&0x20a].returns);
+    assert_eq!(analysis.data[&0x210], BTreeSet::from([0x20a]));
+
+    let skip = &analysis.instructions[&0x202];
+    assert_eq!(successors(skip), [(0x204, Edge::Next), (0x206, Edge::Skip)]);
+}
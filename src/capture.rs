@@ -0,0 +1,186 @@
+//! Recording of gameplay, either as an animated GIF or as a raw sequence of RGBA frames that can be
+//! piped into an encoder, e.g:
+//!
+//! ```text
+//! chip8_emu game.ch8 --headless 600 --video - |
+//!     ffmpeg -f rawvideo -pix_fmt rgba -s 512x256 -r 60 -i - game.mp4
+//! ```
+
+use std::{
+    borrow::Cow,
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::display::{self, Palette};
+
+pub enum VideoRecorder {
+    Gif(GifRecorder),
+    Raw(RawRecorder),
+}
+
+impl VideoRecorder {
+    /// Start a recording, the format is chosen from the extension of `path`: `.gif` for an animated
+    /// GIF, anything else for raw frames. A path of `-` writes raw frames to stdout.
+    pub fn create(path: &Path, palette: Palette, scale: u32) -> Result<VideoRecorder, String> {
+        let error = |e: io::Error| format!("Failed to create video {}: {}", path.display(), e);
+
+        if path == Path::new("-") {
+            let out = Box::new(io::stdout());
+            return Ok(VideoRecorder::Raw(RawRecorder { out, palette, scale }));
+        }
+
+        let file = io::BufWriter::new(fs::File::create(path).map_err(error)?);
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("gif") => {
+                Ok(VideoRecorder::Gif(GifRecorder::new(file, &palette, scale)?))
+            }
+            _ => Ok(VideoRecorder::Raw(RawRecorder { out: Box::new(file), palette, scale })),
+        }
+    }
+
+    /// Add the current display as the next frame, must be called exactly once per 60hz frame
+    pub fn frame(&mut self, display: &[u8]) {
+        let result = match self {
+            VideoRecorder::Gif(gif) => gif.frame(display),
+            VideoRecorder::Raw(raw) => raw.frame(display),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to write video frame: {}", e);
+        }
+    }
+
+    pub fn finish(self) {
+        let result = match self {
+            VideoRecorder::Gif(gif) => gif.finish(),
+            VideoRecorder::Raw(mut raw) => raw.out.flush().map_err(|e| e.to_string()),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to finish video: {}", e);
+        }
+    }
+}
+
+pub struct RawRecorder {
+    out: Box<dyn Write>,
+    palette: Palette,
+    scale: u32,
+}
+
+impl RawRecorder {
+    fn frame(&mut self, display: &[u8]) -> Result<(), String> {
+        let frame = display::to_rgba(display, &self.palette, self.scale);
+        self.out.write_all(&frame).map_err(|e| e.to_string())
+    }
+}
+
+/// Writes an animated GIF. GIF delays are measured in hundredths of a second, and most viewers
+/// treat delays shorter than 2/100 s as much longer ones, so frames are merged until they have been
+/// displayed for at least that long. The total duration stays exact by rounding each frame's end
+/// time rather than its length.
+pub struct GifRecorder {
+    encoder: gif::Encoder<io::BufWriter<fs::File>>,
+    scale: u32,
+
+    /// The image waiting to be written, and the frame it was first displayed on
+    pending: Vec<u8>,
+    pending_start: u64,
+
+    /// The number of frames recorded so far
+    frames: u64,
+}
+
+impl GifRecorder {
+    const MIN_DELAY: u64 = 2;
+
+    fn new(
+        out: io::BufWriter<fs::File>,
+        palette: &Palette,
+        scale: u32,
+    ) -> Result<GifRecorder, String> {
+        let (width, height) = display::image_size(scale);
+        let colors: Vec<u8> = palette.colors.iter().flat_map(|c| &c[..3]).copied().collect();
+
+        let mut encoder = gif::Encoder::new(out, width as u16, height as u16, &colors)
+            .map_err(|e| e.to_string())?;
+        encoder.set_repeat(gif::Repeat::Infinite).map_err(|e| e.to_string())?;
+
+        Ok(GifRecorder { encoder, scale, pending: vec![], pending_start: 0, frames: 0 })
+    }
+
+    fn frame(&mut self, display: &[u8]) -> Result<(), String> {
+        let image = display::to_indexed(display, self.scale);
+
+        if self.frames == 0 {
+            self.pending = image;
+        }
+        else if image != self.pending {
+            if centis(self.frames) - centis(self.pending_start) >= GifRecorder::MIN_DELAY {
+                self.write_pending()?;
+                self.pending_start = self.frames;
+            }
+            self.pending = image;
+        }
+
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> Result<(), String> {
+        let (width, height) = display::image_size(self.scale);
+        let frame = gif::Frame {
+            width: width as u16,
+            height: height as u16,
+            // A display unchanged for longer than the largest delay shows for that long instead
+            delay: (centis(self.frames) - centis(self.pending_start)).min(u16::MAX as u64) as u16,
+            buffer: Cow::Borrowed(&self.pending),
+            ..Default::default()
+        };
+        self.encoder.write_frame(&frame).map_err(|e| e.to_string())
+    }
+
+    fn finish(mut self) -> Result<(), String> {
+        if self.frames > 0 {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+}
+
+/// The time at the start of a 60hz frame, rounded to hundredths of a second
+fn centis(frame: u64) -> u64 {
+    (frame * 100 + 30) / 60
+}
+
+#[test]
+fn test_centis() {
+    assert_eq!(centis(0), 0);
+    assert_eq!(centis(1), 2);
+    assert_eq!(centis(2), 3);
+    assert_eq!(centis(3), 5);
+    assert_eq!(centis(60), 100);
+}
+
+#[test]
+fn test_gif_recording() {
+    let path = std::env::temp_dir().join(format!("chip8_emu_test_{}.gif", std::process::id()));
+    let mut recorder = VideoRecorder::create(&path, Palette::default(), 1).unwrap();
+
+    let mut display = [0; 256];
+    for i in 0..10 {
+        display[0] = i / 3;
+        recorder.frame(&display);
+    }
+    recorder.finish();
+
+    let mut decoder = gif::DecodeOptions::new().read_info(fs::File::open(&path).unwrap()).unwrap();
+    let mut delays = vec![];
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        delays.push(frame.delay);
+    }
+    fs::remove_file(&path).unwrap();
+
+    // Every 3 frames (5/100 s) the display changes
+    assert_eq!(delays, vec![5, 5, 5, 2]);
+}
@@ -0,0 +1,214 @@
+//! The control-flow graph of a ROM, recovered by `analysis`, split into basic blocks and exported
+//! as Graphviz DOT or JSON.
+//!
+//! A block ends at a jump, call, return or skip instruction, or before an instruction that control
+//! can reach in some other way than falling through to it. Each edge is labelled with how control
+//! passes along it (see `Edge`).
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+};
+
+use crate::analysis::{successors, Analysis, Edge, Instruction};
+
+pub struct Block {
+    /// The addresses of the instructions, in order
+    pub instructions: Vec<u16>,
+    /// Where control can go after the last instruction, each the start of a block
+    pub edges: Vec<(u16, Edge)>,
+}
+
+pub struct Graph {
+    pub analysis: Analysis,
+    /// The blocks by start address
+    pub blocks: BTreeMap<u16, Block>,
+}
+
+impl Graph {
+    pub fn new(rom: &[u8]) -> Graph {
+        let analysis = Analysis::new(rom);
+
+        let mut leaders: BTreeSet<u16> = analysis.subroutines.keys().copied().collect();
+        for instruction in analysis.instructions.values() {
+            let edges = successors(instruction);
+            let falls_through = matches!(edges[..], [(_, Edge::Next)]);
+            for (to, edge) in edges {
+                if !falls_through || edge != Edge::Next {
+                    leaders.insert(to);
+                }
+            }
+        }
+
+        let mut blocks = BTreeMap::new();
+        for &start in &leaders {
+            let mut instructions = vec![start];
+            let mut addr = start;
+            loop {
+                let edges = successors(&analysis.instructions[&addr]);
+                match edges[..] {
+                    [(next, Edge::Next)] if !leaders.contains(&next) => {
+                        instructions.push(next);
+                        addr = next;
+                    }
+                    _ => {
+                        blocks.insert(start, Block { instructions, edges });
+                        break;
+                    }
+                }
+            }
+        }
+        Graph { analysis, blocks }
+    }
+
+    /// A Graphviz digraph with a node per block listing its instructions. Subroutine entries are
+    /// drawn with a double border, skips dashed and calls dotted.
+    pub fn dot(&self) -> String {
+        let mut out =
+            String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for (start, block) in &self.blocks {
+            let label: String = block
+                .instructions
+                .iter()
+                .map(|addr| format!("{}\\l", escape(&text(&self.analysis.instructions[addr]))))
+                .collect();
+            let entry = match self.analysis.subroutines.contains_key(start) {
+                true => ", peripheries=2",
+                false => "",
+            };
+            let _ = writeln!(out, "    b{:03x} [label=\"{}\"{}];", start, label, entry);
+        }
+        for (start, block) in &self.blocks {
+            for (to, edge) in &block.edges {
+                let style = match edge {
+                    Edge::Next | Edge::Jump => "",
+                    Edge::Skip => " [label=\"skip\", style=dashed]",
+                    Edge::Call => " [label=\"call\", style=dotted]",
+                };
+                let _ = writeln!(out, "    b{:03x} -> b{:03x}{};", start, to, style);
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// The blocks as JSON, with one instruction per line so that graphs can be diffed. Addresses
+    /// and opcodes are hex strings.
+    pub fn json(&self) -> String {
+        let mut out = String::from("{\"blocks\": [\n");
+        for (i, (start, block)) in self.blocks.iter().enumerate() {
+            let subroutine = self.analysis.subroutines.contains_key(start);
+            let _ = writeln!(
+                out,
+                "  {{\"start\": \"{:03x}\", \"subroutine\": {}, \"instructions\": [",
+                start, subroutine
+            );
+            for (j, addr) in block.instructions.iter().enumerate() {
+                let instruction = &self.analysis.instructions[addr];
+                let opcode = match instruction.opcode {
+                    Some(opcode) => format!("\"{:04x}\"", opcode),
+                    None => String::from("null"),
+                };
+                let op = match &instruction.op {
+                    Ok(op) => op.to_string(),
+                    Err(e) => e.to_string(),
+                };
+                let separator = match j + 1 < block.instructions.len() {
+                    true => ",",
+                    false => "",
+                };
+                let _ = writeln!(
+                    out,
+                    "    {{\"addr\": \"{:03x}\", \"opcode\": {}, \"op\": \"{}\"}}{}",
+                    addr,
+                    opcode,
+                    escape(&op),
+                    separator
+                );
+            }
+            let edges: Vec<_> = block
+                .edges
+                .iter()
+                .map(|(to, edge)| {
+                    format!("{{\"to\": \"{:03x}\", \"kind\": \"{}\"}}", to, edge_name(*edge))
+                })
+                .collect();
+            let separator = match i + 1 < self.blocks.len() {
+                true => ",",
+                false => "",
+            };
+            let _ = writeln!(out, "  ], \"edges\": [{}]}}{}", edges.join(", "), separator);
+        }
+        out.push_str("]}\n");
+        out
+    }
+}
+
+fn text(instruction: &Instruction) -> String {
+    match &instruction.op {
+        Ok(op) => format!("{:03x}: {}", instruction.addr, op),
+        Err(e) => format!("{:03x}: {}", instruction.addr, e),
+    }
+}
+
+fn edge_name(edge: Edge) -> &'static str {
+    match edge {
+        Edge::Next => "next",
+        Edge::Skip => "skip",
+        Edge::Jump => "jump",
+        Edge::Call => "call",
+    }
+}
+
+/// Escape a string for a double quoted DOT or JSON string
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[test]
+fn test_cfg() {
+    // Count V0 up in a loop whose jump back lands in the middle of the straight-line code before
+    // the skip, then call a subroutine that draws the sprite at 0x214, and halt
+    let rom = [
+        0x60, 0x00, 0x70, 0x01, 0x61, 0x02, 0x30, 0x05, 0x12, 0x02, 0x22, 0x0E, 0x12, 0x0C, 0xA2,
+        0x14, 0xD0, 0x11, 0x00, 0xEE, 0x80,
+    ];
+    let graph = Graph::new(&rom);
+    let blocks: Vec<_> =
+        graph.blocks.iter().map(|(start, block)| (*start, block.instructions.clone())).collect();
+    assert_eq!(blocks, [
+        (0x200, vec![0x200]),
+        (0x202, vec![0x202, 0x204, 0x206]),
+        (0x208, vec![0x208]),
+        (0x20a, vec![0x20a]),
+        (0x20c, vec![0x20c]),
+        (0x20e, vec![0x20e, 0x210, 0x212]),
+    ]);
+    assert_eq!(graph.blocks[&0x200].edges, [(0x202, Edge::Next)]);
+    assert_eq!(graph.blocks[&0x202].edges, [(0x208, Edge::Next), (0x20a, Edge::Skip)]);
+    assert_eq!(graph.blocks[&0x208].edges, [(0x202, Edge::Jump)]);
+    assert_eq!(graph.blocks[&0x20a].edges, [(0x20e, Edge::Call), (0x20c, Edge::Next)]);
+    assert_eq!(graph.blocks[&0x20c].edges, [(0x20c, Edge::Jump)]);
+    assert!(graph.blocks[&0x20e].edges.is_empty());
+
+    let dot = graph.dot();
+    assert!(dot.contains(
+        "    b202 [label=\"202: Add V0, 0x01\\l204: Set V1, 0x02\\l206: SkipIfEq V0, 0x05\\l\"];\n"
+    ));
+    assert!(dot.contains("    b200 [label=\"200: Set V0, 0x00\\l\", peripheries=2];\n"));
+    assert!(dot.contains("    b202 -> b20a [label=\"skip\", style=dashed];\n"));
+    assert!(dot.contains("    b208 -> b202;\n"));
+    assert!(dot.contains("    b20a -> b20e [label=\"call\", style=dotted];\n"));
+    assert!(dot.contains(
+        "    b20e [label=\"20e: SetAddr 0x214\\l210: Draw V0, V1, 1\\l212: Return\\l\", \
+         peripheries=2];\n"
+    ));
+
+    let json = graph.json();
+    assert!(json.starts_with(
+        "{\"blocks\": [\n  {\"start\": \"200\", \"subroutine\": true, \"instructions\": [\n    \
+         {\"addr\": \"200\", \"opcode\": \"6000\", \"op\": \"Set V0, 0x00\"}\n  ], \"edges\": \
+         [{\"to\": \"202\", \"kind\": \"next\"}]},\n"
+    ));
+    assert!(json.ends_with("\"edges\": []}\n]}\n"));
+}
@@ -0,0 +1,229 @@
+//! Cheats that write values into memory, and a search for the addresses worth writing to, such as
+//! the number of lives.
+//!
+//! The cheats for a ROM are kept in `cheats/<crc32>.txt` in the configuration directory, one per
+//! line:
+//!
+//! ```text
+//! # address value kind state name
+//! 2f0 03 freeze on Infinite lives
+//! 2f4 09 poke off Start on level 10
+//! ```
+//!
+//! A `freeze` is written at the start of every frame, a `poke` once when it is turned on and
+//! whenever the program is started from scratch.
+
+use std::{fs, path::PathBuf};
+
+use crate::{
+    chip8::mem::{self, Memory, Region},
+    config,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Freeze,
+    Poke,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cheat {
+    pub addr: u16,
+    pub value: u8,
+    pub kind: Kind,
+    pub enabled: bool,
+    pub name: String,
+}
+
+impl Cheat {
+    pub fn write(&self, memory: &mut Memory) {
+        // Only writable addresses are accepted when parsing
+        let _ = memory.write_byte(self.addr, self.value);
+    }
+}
+
+/// Parse cheats in the format described in the module documentation
+pub fn parse(text: &str) -> Result<Vec<Cheat>, String> {
+    let mut cheats = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        cheats.push(parse_line(line).map_err(|e| format!("line {}: {}", i + 1, e))?);
+    }
+    Ok(cheats)
+}
+
+/// Parse a single cheat, `address value kind state name`
+pub fn parse_line(line: &str) -> Result<Cheat, String> {
+    let mut rest = line.trim();
+    let mut field = || {
+        let (field, after) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        rest = after.trim_start();
+        field
+    };
+    let addr = u16::from_str_radix(field(), 16).map_err(|_| "invalid address")?;
+    if !matches!(mem::region(addr), Some(Region::Ram | Region::Display)) {
+        return Err("address is not writable".into());
+    }
+    let value = u8::from_str_radix(field(), 16).map_err(|_| "invalid value")?;
+    let kind = match field() {
+        "freeze" => Kind::Freeze,
+        "poke" => Kind::Poke,
+        _ => return Err("expected `freeze` or `poke`".into()),
+    };
+    let enabled = match field() {
+        "on" => true,
+        "off" => false,
+        _ => return Err("expected `on` or `off`".into()),
+    };
+    Ok(Cheat { addr, value, kind, enabled, name: rest.into() })
+}
+
+pub fn format(cheats: &[Cheat]) -> String {
+    let mut text = String::from("# address value kind state name\n");
+    for cheat in cheats {
+        text.push_str(&format_line(cheat));
+        text.push('\n');
+    }
+    text
+}
+
+/// Format a single cheat the way `parse_line` reads it
+pub fn format_line(cheat: &Cheat) -> String {
+    let kind = match cheat.kind {
+        Kind::Freeze => "freeze",
+        Kind::Poke => "poke",
+    };
+    let state = match cheat.enabled {
+        true => "on",
+        false => "off",
+    };
+    let line = format!("{:03x} {:02x} {} {} {}", cheat.addr, cheat.value, kind, state, cheat.name);
+    line.trim_end().into()
+}
+
+fn path(hash: u32) -> Option<PathBuf> {
+    config::dir().map(|dir| dir.join("cheats").join(format!("{:08x}.txt", hash)))
+}
+
+/// The cheats saved for the ROM with CRC-32 `hash`, if any
+pub fn load(hash: u32) -> Vec<Cheat> {
+    let Some(path) = path(hash)
+    else {
+        return vec![];
+    };
+    let Ok(text) = fs::read_to_string(&path)
+    else {
+        return vec![];
+    };
+    parse(&text).unwrap_or_else(|e| {
+        eprintln!("Ignoring cheats in {}: {}", path.display(), e);
+        vec![]
+    })
+}
+
+pub fn save(hash: u32, cheats: &[Cheat]) -> Result<(), String> {
+    let path = path(hash).ok_or("No configuration directory")?;
+    let dir = path.parent().unwrap_or(&path);
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&path, format(cheats)))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// How a value must have changed since the last snapshot for its address to stay a candidate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+/// Narrows down the RAM addresses that could hold a variable by comparing snapshots: take one,
+/// play until the variable changes in a known way, and keep the addresses whose values changed the
+/// same way
+pub struct Search {
+    snapshot: Vec<u8>,
+    /// The addresses still matching every comparison so far
+    pub candidates: Vec<u16>,
+}
+
+impl Search {
+    /// Start a search with every RAM address as a candidate
+    pub fn new(memory: &Memory) -> Search {
+        let candidates = (mem::RAM_START..mem::RESERVED_START).collect();
+        Search { snapshot: memory.ram.to_vec(), candidates }
+    }
+
+    /// Keep the candidates whose values compare to the snapshot as given, then take a new snapshot
+    pub fn filter(&mut self, memory: &Memory, comparison: Comparison) {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&addr| {
+            let index = (addr - mem::RAM_START) as usize;
+            let (old, new) = (snapshot[index], memory.ram[index]);
+            match comparison {
+                Comparison::Changed => new != old,
+                Comparison::Unchanged => new == old,
+                Comparison::Increased => new > old,
+                Comparison::Decreased => new < old,
+            }
+        });
+        self.snapshot = memory.ram.to_vec();
+    }
+
+    /// The value of a candidate in the last snapshot
+    pub fn value(&self, addr: u16) -> u8 {
+        self.snapshot[(addr - mem::RAM_START) as usize]
+    }
+}
+
+#[test]
+fn test_cheats() {
+    let text = "# Lives\n2f0 03 freeze on Infinite lives\n\n2F4 9 poke off\n";
+    let cheats = parse(text).unwrap();
+    assert_eq!(cheats, [
+        Cheat {
+            addr: 0x2f0,
+            value: 3,
+            kind: Kind::Freeze,
+            enabled: true,
+            name: "Infinite lives".into()
+        },
+        Cheat { addr: 0x2f4, value: 9, kind: Kind::Poke, enabled: false, name: String::new() },
+    ]);
+    assert_eq!(
+        format(&cheats),
+        "# address value kind state name\n2f0 03 freeze on Infinite lives\n2f4 09 poke off\n"
+    );
+    assert_eq!(parse(&format(&cheats)).unwrap(), cheats);
+
+    assert!(parse("100 03 freeze on").unwrap_err().contains("not writable"));
+    assert!(parse("2f0 03 hold on").is_err());
+    assert!(parse("2f0 103 freeze on").is_err());
+}
+
+#[test]
+fn test_search() {
+    let mut memory = Memory::new();
+    memory.ram[0x10] = 3;
+    memory.ram[0x20] = 3;
+    let mut search = Search::new(&memory);
+    assert_eq!(search.candidates.len(), mem::RAM_SIZE as usize);
+
+    memory.ram[0x10] = 2;
+    memory.ram[0x30] = 1;
+    search.filter(&memory, Comparison::Changed);
+    assert_eq!(search.candidates, [0x210, 0x230]);
+
+    memory.ram[0x10] = 1;
+    search.filter(&memory, Comparison::Decreased);
+    assert_eq!(search.candidates, [0x210]);
+    assert_eq!(search.value(0x210), 1);
+
+    search.filter(&memory, Comparison::Unchanged);
+    assert_eq!(search.candidates, [0x210]);
+    search.filter(&memory, Comparison::Increased);
+    assert!(search.candidates.is_empty());
+}
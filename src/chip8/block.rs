@@ -0,0 +1,215 @@
+//! A cached interpreter, for running programs as fast as possible when nothing needs to see the
+//! individual instructions. Runs of instructions in RAM are decoded once into blocks, which are
+//! then executed without fetching and decoding each instruction.
+//!
+//! Blocks carry on through jumps and calls, and end after a return, skip or indirect jump, where
+//! the next instruction depends on the state, and after writes to memory, so that the next block
+//! sees any code the program modified. Drawing, keyboard and timer instructions are left to
+//! `Cpu::exec`, as is anything outside of RAM. Blocks are thrown away whenever the program writes
+//! into decoded instructions (see `Memory::code_writes`).
+
+use crate::chip8::{
+    cpu::{Cpu, Operation, OPCODE_SIZE},
+    mem::{Memory, RAM_SIZE, RAM_START},
+    Error,
+};
+
+/// The most instructions in a block, which would otherwise go on forever in loops without skips
+const MAX_BLOCK: usize = 64;
+
+pub struct Blocks {
+    /// The block starting at each address in RAM, once it has been executed
+    blocks: Vec<Option<Vec<Operation>>>,
+    /// `Memory::code_writes` when the blocks were decoded
+    code_writes: u64,
+}
+
+impl Blocks {
+    pub fn new() -> Blocks {
+        Blocks { blocks: vec![None; RAM_SIZE as usize], code_writes: 0 }
+    }
+
+    /// Execute `cycles` instructions, stopping at the first fault. Returns the number of
+    /// instructions executed, including any that faulted.
+    pub fn run(
+        &mut self,
+        cpu: &mut Cpu,
+        mem: &mut Memory,
+        cycles: u64,
+    ) -> (u64, Result<(), Error>) {
+        let mut executed = 0;
+        while executed < cycles {
+            if mem.code_writes != self.code_writes {
+                self.blocks.fill(None);
+                self.code_writes = mem.code_writes;
+            }
+
+            let pc = cpu.pc();
+            let block = match self.blocks.get_mut(pc.wrapping_sub(RAM_START) as usize) {
+                Some(Some(block)) => Some(block),
+                Some(entry) => decode(mem, pc).map(|block| entry.insert(block)),
+                None => None,
+            };
+            let Some(block) = block
+            else {
+                executed += 1;
+                match cpu.exec(mem) {
+                    Ok(()) => continue,
+                    Err(e) => return (executed, Err(e)),
+                }
+            };
+            for &op in block.iter().take((cycles - executed) as usize) {
+                executed += 1;
+                if let Err(e) = cpu.exec_decoded(op, mem) {
+                    return (executed, Err(e));
+                }
+            }
+        }
+        (executed, Ok(()))
+    }
+}
+
+/// Decode the block starting at `pc`, or return `None` if its first instruction is left to
+/// `Cpu::exec`
+fn decode(mem: &mut Memory, mut pc: u16) -> Option<Vec<Operation>> {
+    use Operation::*;
+
+    let mut block = vec![];
+    // Instructions that fail to decode fault when executed
+    while let Ok(op) = mem.fetch(pc) {
+        // Only instructions in RAM are invalidated when written to
+        if block.len() == MAX_BLOCK || pc.wrapping_sub(RAM_START) >= RAM_SIZE {
+            break;
+        }
+        match op {
+            Draw(..) | SkipIfKeyPressed(_) | SkipIfKeyNotPressed(_) | KeyWait(_) => break,
+            GetDelay(_) | SetDelay(_) | SetSound(_) | CallRCA(_) | Unimplemented(_) => break,
+            Return | SkipIfEq(..) | SkipIfNotEq(..) | JumpWithOffset(_) | StoreBcd(_)
+            | StoreBytes(_) => {
+                block.push(op);
+                break;
+            }
+            // Where these go is known, so the block carries on from there
+            Jump(addr) | Call(addr) => {
+                block.push(op);
+                pc = addr;
+                continue;
+            }
+            _ => block.push(op),
+        }
+        pc += OPCODE_SIZE;
+    }
+    (!block.is_empty()).then_some(block)
+}
+
+#[test]
+fn test_blocks() {
+    // Store V0 into the `Set` at 0x206 and count V0 up to 3, then draw the glyph for V1 in a
+    // subroutine and wait for a key
+    let program = [
+        0xA2, 0x07, 0xF0, 0x55, 0x70, 0x01, 0x61, 0x00, 0x30, 0x03, 0x12, 0x02, 0x22, 0x12, 0xF3,
+        0x0A, 0x00, 0x00, 0xF1, 0x29, 0xD2, 0x25, 0x00, 0xEE,
+    ];
+    let mut mem = Memory::new();
+    mem.ram[..program.len()].copy_from_slice(&program);
+    let mut cpu = Cpu::new(0);
+    let mut blocks = Blocks::new();
+
+    // The `Set` is decoded again after each store, so V1 ends up with the count before the last
+    assert_eq!(blocks.run(&mut cpu, &mut mem, 20), (20, Ok(())));
+    assert_eq!(cpu.registers().v[..2], [3, 2]);
+    assert_eq!(cpu.pc(), 0x20e);
+    assert!(mem.stack.is_empty());
+    assert_eq!(mem.last_draw.map(|sprite| sprite.addr), Some(10));
+
+    // The key wait doesn't complete without a key
+    assert_eq!(blocks.run(&mut cpu, &mut mem, 5), (5, Ok(())));
+    assert_eq!(cpu.pc(), 0x20e);
+
+    // Faults stop the run
+    mem.ram[0xE..0x10].copy_from_slice(&[0x00, 0xEE]);
+    mem.clear_decoded();
+    assert_eq!(blocks.run(&mut cpu, &mut mem, 5), (1, Err(Error::StackUnderflow)));
+    assert_eq!(cpu.pc(), 0x20e);
+}
+
+/// Runs random programs without the decode cache, through it one instruction at a time and through
+/// the blocks, with random input and timer ticks in between, checking that all three end up in the
+/// same state. Programs are restarted when they fault.
+#[test]
+fn test_blocks_match_exec() {
+    use rand::{Rng, SeedableRng};
+
+    use crate::chip8::{try_decode, Emulator};
+
+    // The first digits of the other instructions. Jumps anywhere else would mostly run into empty
+    // memory.
+    const OTHERS: [u16; 12] = [0x0, 0x3, 0x4, 0x5, 0x6, 0x7, 0x8, 0x9, 0xC, 0xD, 0xE, 0xF];
+
+    let mut rng = rand_pcg::Pcg32::seed_from_u64(1);
+    for seed in 0..200 {
+        let mut program = vec![];
+        while program.len() < 124 {
+            let target = 0x200 | rng.gen_range(0..128) & 0xFE;
+            let opcode: u16 = match rng.gen_range(0..32) {
+                // Keep jumps, calls and I within the program, so that it runs for a while and
+                // modifies itself
+                0..4 => 0x1000 | target,
+                4 => 0x2000 | target,
+                5 => 0x00EE,
+                6..8 => 0xA000 | target | rng.gen_range(0..2),
+                8..10 => [0xF033, 0xF055, 0xF065][rng.gen_range(0..3)] | rng.gen_range(0..4) << 8,
+                _ => OTHERS[rng.gen_range(0..OTHERS.len())] << 12 | rng.gen_range(0..0x1000),
+            };
+            if !matches!(try_decode(opcode), Ok(Operation::CallRCA(_)) | Err(_)) {
+                program.extend_from_slice(&opcode.to_be_bytes());
+            }
+        }
+        // Start over instead of running past the end, even after a skip
+        program.extend_from_slice(&[0x12, 0x00, 0x12, 0x00]);
+
+        let mut emulators = [(); 3].map(|_| {
+            let mut emulator = Emulator::new(seed);
+            emulator.load(&program);
+            emulator
+        });
+        let state = |emulator: &Emulator| {
+            let mem = &emulator.mem;
+            (emulator.cpu.registers(), mem.stack.clone(), mem.ram, emulator.display().to_vec())
+        };
+        // The reference, decoding every instruction instead of going through the decode cache
+        let step = |emulator: &mut Emulator| {
+            let op = try_decode(emulator.mem.read_word(emulator.cpu.pc())?)?;
+            emulator.cpu.exec_decoded(op, &mut emulator.mem)
+        };
+
+        for _ in 0..50 {
+            let cycles = rng.gen_range(0..40);
+            let key = rng.gen_range(0..16);
+            let [uncached, exec, blocks] = &mut emulators;
+            for emulator in [&mut *uncached, &mut *exec, &mut *blocks] {
+                emulator.keydown(key);
+                emulator.tick();
+            }
+
+            let (mut executed, mut result) = (0, Ok(()));
+            while executed < cycles && result.is_ok() {
+                executed += 1;
+                result = step(uncached);
+                assert_eq!(exec.frame(), result, "program {:02x?}", program);
+            }
+            assert!(state(uncached) == state(exec), "program {:02x?}", program);
+            assert_eq!(blocks.run(cycles), (executed, result), "program {:02x?}", program);
+            assert!(state(uncached) == state(blocks), "program {:02x?}", program);
+            for emulator in [&mut *uncached, &mut *exec, &mut *blocks] {
+                emulator.keyup(key);
+            }
+            // Keep going from the start, with whatever the program did to itself
+            if result.is_err() {
+                for emulator in [&mut *uncached, &mut *exec, &mut *blocks] {
+                    emulator.reset();
+                }
+            }
+        }
+    }
+}
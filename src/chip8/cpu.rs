@@ -1,4 +1,6 @@
-use rand::Rng;
+use std::fmt;
+
+use rand::{Rng, SeedableRng};
 
 use crate::chip8;
 
@@ -8,13 +10,13 @@ pub use self::Value::*;
 pub const OPCODE_SIZE: u16 = 2;
 
 pub type RegId = u8;
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Value {
     Reg(RegId),
     Const(u8),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Operation {
     // Special 1
     CallRCA(u16),
@@ -65,7 +67,123 @@ pub enum Operation {
     SetSound(RegId),
 }
 
+/// The names of the groups of operations, see `Operation::class`
+pub const CLASSES: [&str; 8] =
+    ["system", "control", "arithmetic", "address", "memory", "special", "keyboard", "timer"];
+
+impl Operation {
+    /// The group the operation belongs to, as listed in `CLASSES`
+    pub fn class(&self) -> &'static str {
+        match self {
+            CallRCA(_) | Unimplemented(_) => "system",
+            Jump(_) | Call(_) | Return | SkipIfEq(..) | SkipIfNotEq(..) => "control",
+            Set(..) | Add(..) | Sub(..) | SubRev(..) | Or(..) | And(..) | Xor(..) | Shr(..)
+            | Shl(..) => "arithmetic",
+            SetAddr(_) | AddAddr(_) | JumpWithOffset(_) => "address",
+            StoreBcd(_) | LoadBytes(_) | StoreBytes(_) => "memory",
+            GetRandom(..) | Draw(..) | LoadGlyph(_) | ClearScreen => "special",
+            SkipIfKeyPressed(_) | SkipIfKeyNotPressed(_) | KeyWait(_) => "keyboard",
+            GetDelay(_) | SetDelay(_) | SetSound(_) => "timer",
+        }
+    }
+
+    /// The name of the operation, without its operands
+    pub fn name(&self) -> &'static str {
+        match self {
+            CallRCA(_) => "CallRCA",
+            Unimplemented(_) => "Unimplemented",
+            Jump(_) => "Jump",
+            Call(_) => "Call",
+            Return => "Return",
+            SkipIfEq(..) => "SkipIfEq",
+            SkipIfNotEq(..) => "SkipIfNotEq",
+            Set(..) => "Set",
+            Add(..) => "Add",
+            Sub(..) => "Sub",
+            SubRev(..) => "SubRev",
+            Or(..) => "Or",
+            And(..) => "And",
+            Xor(..) => "Xor",
+            Shr(..) => "Shr",
+            Shl(..) => "Shl",
+            SetAddr(_) => "SetAddr",
+            AddAddr(_) => "AddAddr",
+            JumpWithOffset(_) => "JumpWithOffset",
+            StoreBcd(_) => "StoreBcd",
+            LoadBytes(_) => "LoadBytes",
+            StoreBytes(_) => "StoreBytes",
+            GetRandom(..) => "GetRandom",
+            Draw(..) => "Draw",
+            LoadGlyph(_) => "LoadGlyph",
+            ClearScreen => "ClearScreen",
+            SkipIfKeyPressed(_) => "SkipIfKeyPressed",
+            SkipIfKeyNotPressed(_) => "SkipIfKeyNotPressed",
+            KeyWait(_) => "KeyWait",
+            GetDelay(_) => "GetDelay",
+            SetDelay(_) => "SetDelay",
+            SetSound(_) => "SetSound",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reg(r) => write!(f, "V{:X}", r),
+            Const(val) => write!(f, "{:#04x}", val),
+        }
+    }
+}
+
+/// Formats the operation as its name followed by the operands, e.g. `SkipIfEq V3, 0x10`
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.name();
+        match *self {
+            ClearScreen | Return => write!(f, "{}", name),
+            CallRCA(addr) | Jump(addr) | Call(addr) | SetAddr(addr) | JumpWithOffset(addr) => {
+                write!(f, "{} {:#05x}", name, addr)
+            }
+            Unimplemented(op) => write!(f, "{} {:#06x}", name, op),
+            SkipIfEq(r, ref val) | SkipIfNotEq(r, ref val) | Set(r, ref val) | Add(r, ref val) => {
+                write!(f, "{} V{:X}, {}", name, r, val)
+            }
+            Sub(r1, r2)
+            | SubRev(r1, r2)
+            | Or(r1, r2)
+            | And(r1, r2)
+            | Xor(r1, r2)
+            | Shr(r1, r2)
+            | Shl(r1, r2) => write!(f, "{} V{:X}, V{:X}", name, r1, r2),
+            AddAddr(r)
+            | StoreBcd(r)
+            | LoadBytes(r)
+            | StoreBytes(r)
+            | LoadGlyph(r)
+            | SkipIfKeyPressed(r)
+            | SkipIfKeyNotPressed(r)
+            | KeyWait(r)
+            | GetDelay(r)
+            | SetDelay(r)
+            | SetSound(r) => write!(f, "{} V{:X}", name, r),
+            GetRandom(r, mask) => write!(f, "{} V{:X}, {:#04x}", name, r, mask),
+            Draw(x, y, n) => write!(f, "{} V{:X}, V{:X}, {}", name, x, y, n),
+        }
+    }
+}
+
+/// A copy of the CPU's registers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub delay: u8,
+    pub sound: u8,
+}
+
 #[allow(non_snake_case)]
+#[derive(Clone)]
 pub struct Cpu {
     // Delay timer register
     delay: u8,
@@ -82,22 +200,51 @@ pub struct Cpu {
     // Program counter
     pc: u16,
 
-    // Cpu random number generator
-    rng: rand::rngs::ThreadRng,
+    // Cpu random number generator. A seeded PCG generator is used so that a run can be reproduced
+    // exactly from its seed, independent of the version of `rand` the emulator was built with.
+    rng: rand_pcg::Pcg32,
 }
 
 impl Cpu {
-    pub fn new() -> Cpu {
+    pub fn new(seed: u64) -> Cpu {
         Cpu {
             delay: 0,
             sound: 0,
             V: [0; 16],
             I: 0,
             pc: chip8::mem::RAM_START,
-            rng: rand::thread_rng(),
+            rng: rand_pcg::Pcg32::seed_from_u64(seed),
         }
     }
 
+    /// Clear all registers and start again from the beginning of the program. The random number
+    /// generator keeps its state.
+    pub fn reset(&mut self) {
+        self.set_registers(&Registers {
+            v: [0; 16],
+            i: 0,
+            pc: chip8::mem::RAM_START,
+            delay: 0,
+            sound: 0,
+        });
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers { v: self.V, i: self.I, pc: self.pc, delay: self.delay, sound: self.sound }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.V = registers.v;
+        self.I = registers.i;
+        self.pc = registers.pc;
+        self.delay = registers.delay;
+        self.sound = registers.sound;
+    }
+
     pub fn tick(&mut self) {
         if self.delay > 0 {
             self.delay -= 1;
@@ -107,26 +254,46 @@ impl Cpu {
         }
     }
 
-    pub fn exec(&mut self, mem: &mut chip8::mem::Memory) {
-        let op = chip8::decode(mem.read_word(self.pc));
+    /// Execute the instruction at the program counter. On a fault the program counter is left
+    /// pointing at the faulting instruction, but any memory it wrote before faulting stays written.
+    pub fn exec(&mut self, mem: &mut chip8::mem::Memory) -> Result<(), chip8::Error> {
+        let op = mem.fetch(self.pc)?;
+        self.exec_decoded(op, mem)
+    }
+
+    /// Execute `op`, which was decoded from the instruction at the program counter. See `exec`.
+    pub fn exec_decoded(
+        &mut self,
+        op: Operation,
+        mem: &mut chip8::mem::Memory,
+    ) -> Result<(), chip8::Error> {
+        let pc = self.pc;
+        let result = self.exec_op(op, mem);
+        if result.is_err() {
+            self.pc = pc;
+        }
+        result
+    }
+
+    fn exec_op(&mut self, op: Operation, mem: &mut chip8::mem::Memory) -> Result<(), chip8::Error> {
         self.pc += OPCODE_SIZE;
 
         match op {
             //
             // Special 1
-            CallRCA(..) => unimplemented!(),
-            Unimplemented(code) => println!("Unimplemented opcode: {}", code),
+            CallRCA(addr) => return Err(chip8::Error::RcaCall(addr)),
+            Unimplemented(code) => mem.unimplemented = Some(code),
 
             //
             // Control flow
             Jump(addr) => self.pc = addr,
 
             Call(addr) => {
-                mem.stack_push(self.pc);
+                mem.stack_push(self.pc)?;
                 self.pc = addr;
             }
 
-            Return => self.pc = mem.stack_pop(),
+            Return => self.pc = mem.stack_pop()?,
 
             SkipIfEq(r, Const(val)) => {
                 if self.V[r as usize] == val {
@@ -155,7 +322,7 @@ impl Cpu {
             Set(reg, Const(val)) => self.V[reg as usize] = val,
             Set(r1, Reg(r2)) => self.V[r1 as usize] = self.V[r2 as usize],
 
-            Add(reg, Const(val)) => self.V[reg as usize] += val,
+            Add(reg, Const(val)) => self.V[reg as usize] = self.V[reg as usize].wrapping_add(val),
             Add(r1, Reg(r2)) => {
                 let result = self.V[r1 as usize] as u16 + self.V[r2 as usize] as u16;
                 self.V[0xF] = u8::from(overflow8(result));
@@ -163,12 +330,12 @@ impl Cpu {
             }
 
             Sub(r1, r2) => {
-                let result = self.V[r1 as usize] as u16 - self.V[r2 as usize] as u16;
+                let result = (self.V[r1 as usize] as u16).wrapping_sub(self.V[r2 as usize] as u16);
                 self.V[0xF] = u8::from(!overflow8(result));
                 self.V[r1 as usize] = result as u8;
             }
             SubRev(r1, r2) => {
-                let result = self.V[r2 as usize] as u16 - self.V[r1 as usize] as u16;
+                let result = (self.V[r2 as usize] as u16).wrapping_sub(self.V[r1 as usize] as u16);
                 self.V[0xF] = u8::from(!overflow8(result));
                 self.V[r1 as usize] = result as u8;
             }
@@ -200,19 +367,19 @@ impl Cpu {
             // Manipulation on multiple bytes
             StoreBcd(r) => {
                 let val = self.V[r as usize];
-                mem.write_byte(self.I, (val / 100) % 10);
-                mem.write_byte(self.I + 1, (val / 10) % 10);
-                mem.write_byte(self.I + 2, (val / 1) % 10);
+                mem.write_byte(self.I, (val / 100) % 10)?;
+                mem.write_byte(self.I + 1, (val / 10) % 10)?;
+                mem.write_byte(self.I + 2, val % 10)?;
             }
 
             LoadBytes(r) => {
                 for i in 0..=r as usize {
-                    self.V[i] = mem.read_byte(self.I + i as u16);
+                    self.V[i] = mem.read_byte(self.I + i as u16)?;
                 }
             }
             StoreBytes(r) => {
                 for i in 0..=r as usize {
-                    mem.write_byte(self.I + i as u16, self.V[i]);
+                    mem.write_byte(self.I + i as u16, self.V[i])?;
                 }
             }
 
@@ -220,9 +387,9 @@ impl Cpu {
             // Special 2
             GetRandom(r, val) => self.V[r as usize] = self.rng.gen::<u8>() & val,
             Draw(x, y, n) => {
-                self.V[0xF] = mem.draw(self.V[x as usize], self.V[y as usize], n, self.I);
+                self.V[0xF] = mem.draw(self.V[x as usize], self.V[y as usize], n, self.I)?;
             }
-            LoadGlyph(r) => self.I = mem.load_glyph(self.V[r as usize]),
+            LoadGlyph(r) => self.I = mem.load_glyph(self.V[r as usize])?,
             ClearScreen => mem.clear_disp(),
 
             //
@@ -248,6 +415,7 @@ impl Cpu {
             SetDelay(r) => self.delay = self.V[r as usize],
             SetSound(r) => self.sound = self.V[r as usize],
         }
+        Ok(())
     }
 }
 
@@ -259,3 +427,39 @@ fn overflow8(value: u16) -> bool {
 fn overflow12(value: u16) -> bool {
     value > 0xFFF
 }
+
+#[test]
+fn test_operation_display() {
+    let display = |op: u16| chip8::decode(op).to_string();
+    assert_eq!(display(0x00E0), "ClearScreen");
+    assert_eq!(display(0x1206), "Jump 0x206");
+    assert_eq!(display(0x3A10), "SkipIfEq VA, 0x10");
+    assert_eq!(display(0x5120), "SkipIfEq V1, V2");
+    assert_eq!(display(0x8236), "Shr V2, V3");
+    assert_eq!(display(0xC0FF), "GetRandom V0, 0xff");
+    assert_eq!(display(0xD015), "Draw V0, V1, 5");
+    assert_eq!(display(0xF355), "StoreBytes V3");
+    assert_eq!(chip8::decode(0xF355).class(), "memory");
+}
+
+#[test]
+fn test_arithmetic_wraps() {
+    // 0xFF + 1, 0 - 1 and 1 - 2, each of which overflows a register
+    let program =
+        [0x60, 0xFF, 0x70, 0x01, 0x61, 0x01, 0x80, 0x15, 0x62, 0x01, 0x63, 0x02, 0x83, 0x27];
+    let mut mem = chip8::mem::Memory::new();
+    mem.ram[..program.len()].copy_from_slice(&program);
+    let mut cpu = Cpu::new(0);
+
+    cpu.exec(&mut mem).unwrap();
+    cpu.exec(&mut mem).unwrap();
+    assert_eq!(cpu.registers().v[0], 0x00);
+    for _ in 0..5 {
+        cpu.exec(&mut mem).unwrap();
+    }
+    let registers = cpu.registers();
+    assert_eq!(registers.v[0], 0xFF);
+    assert_eq!(registers.v[3], 0xFF);
+    // Both subtractions borrowed
+    assert_eq!(registers.v[0xF], 0);
+}
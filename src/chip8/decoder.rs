@@ -1,7 +1,13 @@
-use crate::chip8::cpu;
+use crate::chip8::{cpu, Error};
 
+/// Decode an opcode that is known to be valid, for tests
+#[cfg(test)]
 pub fn decode(op: u16) -> cpu::Operation {
-    match mask01(op) {
+    try_decode(op).unwrap_or_else(|e| panic!("{}", e))
+}
+
+pub fn try_decode(op: u16) -> Result<cpu::Operation, Error> {
+    let operation = match mask01(op) {
         0x0 => match mask13(op) {
             0x0E0 => cpu::ClearScreen,
             0x0EE => cpu::Return,
@@ -27,7 +33,7 @@ pub fn decode(op: u16) -> cpu::Operation {
                 0x6 => cpu::Shr(r1, r2),
                 0x7 => cpu::SubRev(r1, r2),
                 0xE => cpu::Shl(r1, r2),
-                _ => panic!("Invalid opcode {:4x}", op),
+                _ => return Err(Error::InvalidOpcode(op)),
             }
         }
         0x9 => cpu::SkipIfNotEq(mask11(op), cpu::Reg(mask21(op))),
@@ -38,7 +44,7 @@ pub fn decode(op: u16) -> cpu::Operation {
         0xE => match mask22(op) {
             0x9E => cpu::SkipIfKeyPressed(mask11(op)),
             0xA1 => cpu::SkipIfKeyNotPressed(mask11(op)),
-            _ => panic!("Invalid opcode {:4x}", op),
+            _ => return Err(Error::InvalidOpcode(op)),
         },
         0xF => match mask22(op) {
             0x07 => cpu::GetDelay(mask11(op)),
@@ -52,10 +58,11 @@ pub fn decode(op: u16) -> cpu::Operation {
             0x65 => cpu::LoadBytes(mask11(op)),
             0x75 => cpu::Unimplemented(op),
             0x85 => cpu::Unimplemented(op),
-            _ => panic!("Invalid opcode {:4x}", op),
+            _ => return Err(Error::InvalidOpcode(op)),
         },
         _ => unreachable!(),
-    }
+    };
+    Ok(operation)
 }
 
 fn mask01(op: u16) -> u8 {
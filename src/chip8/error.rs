@@ -0,0 +1,39 @@
+use std::fmt;
+
+/// A fault raised by the running program. The emulator stops at the faulting instruction, with
+/// the program counter pointing at it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    StackOverflow,
+    StackUnderflow,
+    /// An address past the end of memory
+    AddressTooLarge(u16),
+    /// An address between the end of RAM and the display buffer
+    ReservedAddress(u16),
+    /// A write to the interpreter area holding the glyphs
+    ReadOnlyAddress(u16),
+    InvalidOpcode(u16),
+    /// A glyph other than `0`-`F`
+    InvalidGlyph(u8),
+    /// A call to a machine code routine of the COSMAC VIP's processor, which isn't emulated
+    RcaCall(u16),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::StackOverflow => write!(f, "Stack overflow"),
+            Error::StackUnderflow => write!(f, "Stack underflow"),
+            Error::AddressTooLarge(addr) => write!(f, "Address too large: {:#05x}", addr),
+            Error::ReservedAddress(addr) => {
+                write!(f, "Attempted to access reserved address: {:#05x}", addr)
+            }
+            Error::ReadOnlyAddress(addr) => {
+                write!(f, "Attempted to access read only memory: {:#05x}", addr)
+            }
+            Error::InvalidOpcode(op) => write!(f, "Invalid opcode: {:04x}", op),
+            Error::InvalidGlyph(val) => write!(f, "Invalid glyph: {:#04x}", val),
+            Error::RcaCall(addr) => write!(f, "RCA 1802 call to {:#05x}", addr),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! The most recently executed instructions, with the state needed to undo them. Used to show how
+//! the program arrived at a fault, and to step backwards in the debugger.
+
+use std::{collections::VecDeque, fmt};
+
+use crate::chip8::{
+    self,
+    cpu::{Operation, Registers},
+    mem::Sprite,
+    video::{BYTES_WIDTH, HEIGHT},
+    Cpu, Emulator, Input,
+};
+
+type Display = [u8; BYTES_WIDTH as usize * HEIGHT as usize];
+
+/// An executed instruction and the state of the emulator before it was executed
+pub struct Step {
+    pub opcode: u16,
+    pub frame: u64,
+    cpu: Cpu,
+    stack: Vec<u16>,
+    input: Input,
+    /// The memory the instruction overwrites, as `(address, old value)`
+    memory: Vec<(u16, u8)>,
+    /// The display before the instruction, if the instruction changes it
+    display: Option<Box<Display>>,
+    last_draw: Option<Sprite>,
+}
+
+impl Step {
+    /// The registers before the instruction was executed
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+}
+
+/// Formats the step as the frame, address, opcode and decoded operation, e.g.
+/// `12 206 d015 Draw V0, V1, 5`
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = chip8::try_decode(self.opcode).map(|op| op.to_string());
+        write!(f, "{} {:03x} {:04x} ", self.frame, self.registers().pc, self.opcode)?;
+        match op {
+            Ok(op) => write!(f, "{}", op),
+            Err(e) => write!(f, "({})", e),
+        }
+    }
+}
+
+pub struct History {
+    steps: VecDeque<Step>,
+    capacity: usize,
+}
+
+impl History {
+    /// Keep the last `capacity` instructions
+    pub fn new(capacity: usize) -> History {
+        History { steps: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    /// Remember the state of `emulator` before it executes its next instruction
+    pub fn record(&mut self, emulator: &chip8::Emulator) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+
+        let cpu = &emulator.cpu;
+        let mem = &emulator.mem;
+        let Registers { i, pc, .. } = cpu.registers();
+        let opcode = mem.read_word(pc).unwrap_or(0);
+        let op = chip8::try_decode(opcode);
+
+        // Only these instructions write to memory. Addresses that can't be read can't be written
+        // either, so they are skipped.
+        let written = match op {
+            Ok(Operation::StoreBcd(_)) => i..i + 3,
+            Ok(Operation::StoreBytes(r)) => i..i + r as u16 + 1,
+            _ => i..i,
+        };
+        let memory =
+            written.filter_map(|addr| mem.read_byte(addr).ok().map(|old| (addr, old))).collect();
+        let display = match op {
+            Ok(Operation::Draw(..) | Operation::ClearScreen) => Some(Box::new(mem.video.data)),
+            _ => None,
+        };
+
+        self.steps.push_back(Step {
+            opcode,
+            frame: emulator.ticks,
+            cpu: cpu.clone(),
+            stack: mem.stack.clone(),
+            input: mem.input.clone(),
+            memory,
+            display,
+            last_draw: mem.last_draw,
+        });
+    }
+
+    /// Restore `emulator` to the state before the most recently recorded instruction. Returns false
+    /// if there is nothing left to undo.
+    pub fn undo(&mut self, emulator: &mut Emulator) -> bool {
+        let Some(step) = self.steps.pop_back()
+        else {
+            return false;
+        };
+
+        emulator.cpu = step.cpu;
+        emulator.ticks = step.frame;
+        emulator.mem.stack = step.stack;
+        emulator.mem.input = step.input;
+        for (addr, old) in step.memory {
+            // The address was writable when the step was recorded
+            let _ = emulator.mem.write_byte(addr, old);
+        }
+        if let Some(display) = step.display {
+            emulator.mem.video.data = *display;
+            emulator.mem.video.screen_modified = true;
+        }
+        emulator.mem.last_draw = step.last_draw;
+        true
+    }
+
+    /// The recorded instructions, oldest first
+    pub fn steps(&self) -> impl DoubleEndedIterator<Item = &Step> + ExactSizeIterator {
+        self.steps.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.steps.clear();
+    }
+}
+
+#[test]
+fn test_undo() {
+    // Call a subroutine that draws a random sprite, stores its BCD over the sprite and returns
+    let program = [
+        0x22, 0x04, 0x12, 0x02, 0xC0, 0xFF, 0xA3, 0x00, 0xF0, 0x33, 0xD1, 0x13, 0xF2, 0x55, 0x00,
+        0xEE,
+    ];
+    let mut emulator = Emulator::new(5);
+    emulator.load(&program);
+    let state = |emulator: &Emulator| {
+        (
+            emulator.cpu.registers(),
+            emulator.mem.stack.clone(),
+            emulator.mem.ram,
+            emulator.display().to_vec(),
+        )
+    };
+    let start = state(&emulator);
+
+    let mut history = History::new(16);
+    for _ in 0..8 {
+        history.record(&emulator);
+        emulator.frame().unwrap();
+    }
+    assert_eq!(history.steps().len(), 8);
+    assert_eq!(history.steps().last().unwrap().to_string(), "0 202 1202 Jump 0x202");
+    let end = state(&emulator);
+
+    while history.undo(&mut emulator) {}
+    assert_eq!(state(&emulator), start);
+
+    // The random number generator is restored as well, so the program runs the same way again
+    for _ in 0..8 {
+        emulator.frame().unwrap();
+    }
+    assert_eq!(state(&emulator), end);
+
+    let mut history = History::new(2);
+    for _ in 0..3 {
+        history.record(&emulator);
+        emulator.frame().unwrap();
+    }
+    assert_eq!(history.steps().len(), 2);
+}
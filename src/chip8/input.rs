@@ -1,5 +1,4 @@
-use std::mem;
-
+#[derive(Clone)]
 pub struct Input {
     data: [bool; 0x10],
     pressed_key: Option<u8>,
@@ -31,6 +30,6 @@ impl Input {
     }
 
     pub fn get_key(&mut self) -> Option<u8> {
-        mem::replace(&mut self.pressed_key, None)
+        self.pressed_key.take()
     }
 }
@@ -1,4 +1,4 @@
-use crate::chip8;
+use crate::chip8::{self, cpu::Operation, Error};
 
 pub const GLYPHS_START: u16 = 0x000;
 pub const RAM_START: u16 = 0x200;
@@ -11,11 +11,55 @@ pub const STACK_SIZE: usize = 16;
 
 pub static ZERO: u8 = 0;
 
+/// The areas of the address space
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    /// The interpreter area holding the glyphs, read only
+    Glyphs,
+    /// The program and its data
+    Ram,
+    /// Used by the original interpreter, not accessible
+    Reserved,
+    Display,
+}
+
+/// The region containing `addr`, or `None` past the end of memory
+pub fn region(addr: u16) -> Option<Region> {
+    match addr {
+        TOTAL_MEMORY.. => None,
+        DISPLAY_START.. => Some(Region::Display),
+        RESERVED_START.. => Some(Region::Reserved),
+        RAM_START.. => Some(Region::Ram),
+        _ => Some(Region::Glyphs),
+    }
+}
+
+/// The arguments and result of a `Memory::draw` call
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sprite {
+    pub x: u8,
+    pub y: u8,
+    pub height: u8,
+    pub addr: u16,
+    /// Whether any pixels were turned off
+    pub collision: bool,
+}
+
 pub struct Memory {
-    pub ram: [u8; (RAM_SIZE as usize)],
-    stack: Vec<u16>,
+    /// Write through `write_byte`, or call `clear_decoded` after writing directly, so that no
+    /// stale instructions are executed
+    pub ram: [u8; RAM_SIZE as usize],
+    pub stack: Vec<u16>,
     pub input: chip8::Input,
     pub video: chip8::Video,
+    /// The most recently drawn sprite, for the debugger
+    pub last_draw: Option<Sprite>,
+    /// The last executed opcode that isn't implemented, until the frontend takes it to report it
+    pub unimplemented: Option<u16>,
+    /// The instructions decoded so far by address in RAM, see `fetch`
+    decoded: Vec<Option<Operation>>,
+    /// The number of times decoded instructions were written to, for anything caching them
+    pub code_writes: u64,
 }
 
 impl Memory {
@@ -25,35 +69,68 @@ impl Memory {
             stack: vec![],
             input: chip8::Input::new(),
             video: chip8::Video::new(),
+            last_draw: None,
+            unimplemented: None,
+            decoded: vec![None; RAM_SIZE as usize],
+            code_writes: 0,
         }
     }
 
-    pub fn stack_push(&mut self, addr: u16) {
+    pub fn stack_push(&mut self, addr: u16) -> Result<(), Error> {
         if self.stack.len() < STACK_SIZE {
             self.stack.push(addr);
+            Ok(())
         }
         else {
-            panic!("Stack overflow");
+            Err(Error::StackOverflow)
         }
     }
 
-    pub fn stack_pop(&mut self) -> u16 {
-        match self.stack.pop() {
-            Some(val) => val,
-            None => panic!("Stack underflow"),
-        }
+    pub fn stack_pop(&mut self) -> Result<u16, Error> {
+        self.stack.pop().ok_or(Error::StackUnderflow)
+    }
+
+    pub fn read_byte(&self, addr: u16) -> Result<u8, Error> {
+        self.map_addr(addr).copied()
+    }
+
+    pub fn read_word(&self, addr: u16) -> Result<u16, Error> {
+        Ok((self.read_byte(addr)? as u16) << 8 | (self.read_byte(addr.wrapping_add(1))? as u16))
     }
 
-    pub fn read_byte(&self, addr: u16) -> u8 {
-        *(self.map_addr(addr))
+    pub fn write_byte(&mut self, addr: u16, val: u8) -> Result<(), Error> {
+        *(self.map_addr_mut(addr)?) = val;
+        // The instructions starting at this byte and the one before it
+        let index = addr.wrapping_sub(RAM_START) as usize;
+        for index in [index, index.wrapping_sub(1)] {
+            if let Some(decoded) = self.decoded.get_mut(index) {
+                if decoded.take().is_some() {
+                    self.code_writes += 1;
+                }
+            }
+        }
+        Ok(())
     }
 
-    pub fn read_word(&self, addr: u16) -> u16 {
-        (self.read_byte(addr) as u16) << 8 | (self.read_byte(addr + 1) as u16)
+    /// Read and decode the instruction at `addr`. Instructions in RAM are decoded once and kept
+    /// until written to, since decoding every executed instruction is a large part of the time
+    /// spent emulating.
+    pub fn fetch(&mut self, addr: u16) -> Result<Operation, Error> {
+        let index = addr.wrapping_sub(RAM_START) as usize;
+        if let Some(&Some(op)) = self.decoded.get(index) {
+            return Ok(op);
+        }
+        let op = chip8::try_decode(self.read_word(addr)?)?;
+        if let Some(decoded) = self.decoded.get_mut(index) {
+            *decoded = Some(op);
+        }
+        Ok(op)
     }
 
-    pub fn write_byte(&mut self, addr: u16, val: u8) {
-        *(self.map_addr_mut(addr)) = val;
+    /// Forget the decoded instructions, after writing to `ram` directly
+    pub fn clear_decoded(&mut self) {
+        self.decoded.fill(None);
+        self.code_writes += 1;
     }
 
     pub fn is_keydown(&mut self, key: u8) -> bool {
@@ -71,66 +148,109 @@ impl Memory {
     /// Draws a sprite at addr on the screen at x, y
     /// Returns 1 if any screen pixels are flipped from set to unset when the sprite is drawn,
     /// or 0 if that doesn't happen.
-    pub fn draw(&mut self, x: u8, y: u8, h: u8, addr: u16) -> u8 {
+    pub fn draw(&mut self, x: u8, y: u8, h: u8, addr: u16) -> Result<u8, Error> {
         let mut flipped = 0x0;
         for dy in 0..h {
-            let draw_data = *(self.map_addr(addr + dy as u16));
-            flipped |= self.video.draw(x, y + dy, draw_data);
+            let draw_data = self.read_byte(addr + dy as u16)?;
+            flipped |= self.video.draw(x, y.wrapping_add(dy), draw_data);
         }
-        flipped
+        self.last_draw = Some(Sprite { x, y, height: h, addr, collision: flipped != 0 });
+        Ok(flipped)
     }
 
-    pub fn load_glyph(&self, val: u8) -> u16 {
-        assert!(val <= 0xF, "Invalid glyph");
-        GLYPHS_START + val as u16 * 5
+    pub fn load_glyph(&self, val: u8) -> Result<u16, Error> {
+        if val > 0xF {
+            return Err(Error::InvalidGlyph(val));
+        }
+        Ok(GLYPHS_START + val as u16 * 5)
     }
 
-    fn map_addr(&self, addr: u16) -> &u8 {
-        if addr >= TOTAL_MEMORY {
-            panic!("Address too large: {}", addr);
-        }
-        else if addr >= DISPLAY_START {
-            &self.video.data[(addr - DISPLAY_START) as usize]
-        }
-        else if addr >= RESERVED_START {
-            panic!("Attempted to access reserved address: {}", addr);
-        }
-        else if addr >= RAM_START {
-            &self.ram[(addr - RAM_START) as usize]
-        }
-        else if addr >= GLYPHS_START {
-            if addr < chip8::video::GLYPHS.len() as u16 {
-                &chip8::video::GLYPHS[(addr - GLYPHS_START) as usize]
-            }
-            else {
-                // The glyphs don't use up the entire reserved space, so return 0 if the address is
-                // larger than the number of glyphs
-                &ZERO
+    fn map_addr(&self, addr: u16) -> Result<&u8, Error> {
+        match region(addr) {
+            None => Err(Error::AddressTooLarge(addr)),
+            Some(Region::Display) => Ok(&self.video.data[(addr - DISPLAY_START) as usize]),
+            Some(Region::Reserved) => Err(Error::ReservedAddress(addr)),
+            Some(Region::Ram) => Ok(&self.ram[(addr - RAM_START) as usize]),
+            // The glyphs don't use up the entire interpreter area, so return 0 if the address is
+            // larger than the number of glyphs
+            Some(Region::Glyphs) => {
+                Ok(chip8::video::GLYPHS.get((addr - GLYPHS_START) as usize).unwrap_or(&ZERO))
             }
         }
-        else {
-            unreachable!()
-        }
     }
 
-    fn map_addr_mut(&mut self, addr: u16) -> &mut u8 {
-        if addr >= TOTAL_MEMORY {
-            panic!("Address too large: {}", addr);
-        }
-        else if addr >= DISPLAY_START {
-            &mut self.video.data[(addr - DISPLAY_START) as usize]
-        }
-        else if addr >= RESERVED_START {
-            panic!("Attempted to access reserved address: {}", addr);
-        }
-        else if addr >= RAM_START {
-            &mut self.ram[(addr - RAM_START) as usize]
-        }
-        else if addr >= GLYPHS_START {
-            panic!("Attempted to access read only memory: {}", addr);
-        }
-        else {
-            unreachable!()
+    fn map_addr_mut(&mut self, addr: u16) -> Result<&mut u8, Error> {
+        match region(addr) {
+            None => Err(Error::AddressTooLarge(addr)),
+            Some(Region::Display) => {
+                self.video.screen_modified = true;
+                Ok(&mut self.video.data[(addr - DISPLAY_START) as usize])
+            }
+            Some(Region::Reserved) => Err(Error::ReservedAddress(addr)),
+            Some(Region::Ram) => Ok(&mut self.ram[(addr - RAM_START) as usize]),
+            Some(Region::Glyphs) => Err(Error::ReadOnlyAddress(addr)),
         }
     }
 }
+
+#[test]
+fn test_regions() {
+    let mut memory = Memory::new();
+    assert_eq!(region(0x050), Some(Region::Glyphs));
+    assert_eq!(region(0x200), Some(Region::Ram));
+    assert_eq!(region(0xEA0), Some(Region::Reserved));
+    assert_eq!(region(0xFFF), Some(Region::Display));
+    assert_eq!(region(0x1000), None);
+
+    assert_eq!(memory.read_byte(0x000), Ok(0xF0));
+    assert_eq!(memory.read_byte(0x1FF), Ok(0));
+    assert_eq!(memory.write_byte(0x000, 1), Err(Error::ReadOnlyAddress(0x000)));
+    assert_eq!(memory.read_byte(0xEA0), Err(Error::ReservedAddress(0xEA0)));
+    assert_eq!(memory.read_byte(0x1000), Err(Error::AddressTooLarge(0x1000)));
+
+    memory.video.screen_modified = false;
+    memory.write_byte(0xF00, 0x80).unwrap();
+    assert!(memory.video.screen_modified);
+    assert_eq!(memory.video.data[0], 0x80);
+}
+
+#[test]
+fn test_last_draw() {
+    let mut memory = Memory::new();
+    assert_eq!(memory.draw(62, 3, 5, 0x000), Ok(0));
+    // The sprite wraps around to the left edge
+    assert_eq!(memory.video.data[3 * 8..3 * 8 + 8], [0xC0, 0, 0, 0, 0, 0, 0, 0x03]);
+    assert_eq!(memory.draw(62, 3, 5, 0x000), Ok(1));
+    let sprite = Sprite { x: 62, y: 3, height: 5, addr: 0x000, collision: true };
+    assert_eq!(memory.last_draw, Some(sprite));
+
+    assert!(memory.draw(0, 0, 2, 0xE9F).is_err());
+    assert_eq!(memory.last_draw, Some(sprite));
+}
+
+#[test]
+fn test_fetch() {
+    let mut memory = Memory::new();
+    memory.ram[..4].copy_from_slice(&[0x12, 0x02, 0x60, 0x01]);
+    assert!(matches!(memory.fetch(0x200), Ok(Operation::Jump(0x202))));
+    assert!(matches!(memory.fetch(0x202), Ok(Operation::Set(0, _))));
+
+    // Writing either byte of an instruction invalidates it
+    memory.write_byte(0x300, 0).unwrap();
+    assert_eq!(memory.code_writes, 0);
+    memory.write_byte(0x201, 0x04).unwrap();
+    assert_eq!(memory.code_writes, 1);
+    assert!(matches!(memory.fetch(0x200), Ok(Operation::Jump(0x204))));
+    // Including one starting at the byte before, as instructions can overlap
+    assert!(matches!(memory.fetch(0x201), Ok(Operation::CallRCA(0x460))));
+    memory.write_byte(0x202, 0x70).unwrap();
+    assert!(matches!(memory.fetch(0x201), Ok(Operation::CallRCA(0x470))));
+    assert!(matches!(memory.fetch(0x202), Ok(Operation::Add(0, _))));
+
+    memory.ram[0] = 0x13;
+    assert!(matches!(memory.fetch(0x200), Ok(Operation::Jump(0x204))));
+    memory.clear_decoded();
+    assert!(matches!(memory.fetch(0x200), Ok(Operation::Jump(0x304))));
+
+    assert_eq!(memory.fetch(0xE9F).err(), Some(Error::ReservedAddress(0xEA0)));
+}
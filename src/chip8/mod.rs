@@ -1,29 +1,88 @@
-pub use crate::chip8::{cpu::Cpu, decoder::decode, input::Input, mem::Memory, video::Video};
+use rand::RngCore;
 
-mod cpu;
+pub use crate::chip8::{
+    block::Blocks, cpu::Cpu, decoder::try_decode, error::Error, history::History, input::Input,
+    mem::Memory, video::Video,
+};
+
+#[cfg(test)]
+pub use crate::chip8::decoder::decode;
+
+mod block;
+pub mod cpu;
 mod decoder;
+mod error;
+mod history;
 mod input;
-mod mem;
+pub mod mem;
 pub mod video;
 
 /// The timer speed = 60hz
-pub const TICK_RATE: f64 = 1.0 / 60.0;
-/// Clock rate of CPU = 1Mhz
-pub const CLOCK_RATE: f64 = 1.0 / 1000.0;
+pub const TICK_HZ: u64 = 60;
+/// Clock rate of CPU = 1khz
+pub const CLOCK_HZ: u64 = 1000;
 
 pub struct Emulator {
     pub cpu: Cpu,
     pub mem: Memory,
+
+    /// The number of timer ticks since the emulator was started, used as the frame number
+    pub ticks: u64,
+
+    seed: u64,
+    blocks: Blocks,
 }
 
 impl Emulator {
-    pub fn new() -> Emulator {
-        Emulator { cpu: Cpu::new(), mem: Memory::new() }
+    /// Create an emulator with a random number generator seeded from `seed`. Two emulators created
+    /// with the same seed behave identically given the same sequence of inputs.
+    pub fn new(seed: u64) -> Emulator {
+        Emulator { cpu: Cpu::new(seed), mem: Memory::new(), ticks: 0, seed, blocks: Blocks::new() }
+    }
+
+    /// Soft reset: the registers, stack, timers, display and anything left to report about the
+    /// last run are cleared and the program starts again, but RAM keeps its contents
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.mem.stack.clear();
+        self.mem.clear_disp();
+        self.mem.last_draw = None;
+        self.mem.unimplemented = None;
+    }
+
+    /// Turn the emulator off and on again, reloading `program`. With `random_ram`, the RAM not
+    /// used by the program is filled with random bytes instead of zeros, which catches programs
+    /// that rely on memory being zeroed. The bytes are derived from the emulator's seed.
+    pub fn power_cycle(&mut self, program: &[u8], random_ram: bool) -> usize {
+        let input = std::mem::replace(&mut self.mem.input, Input::new());
+        *self = Emulator::new(self.seed);
+        self.mem.input = input;
+
+        if random_ram {
+            // A different stream than the one used by the CPU, so the two sequences don't overlap
+            let mut rng = rand_pcg::Pcg32::new(self.seed, 0xda3e_39cb_94b9_5bdb);
+            rng.fill_bytes(&mut self.mem.ram);
+        }
+        self.load(program)
+    }
+
+    /// Copy a program into RAM, returning the number of bytes that fit
+    pub fn load(&mut self, program: &[u8]) -> usize {
+        let n = program.len().min(self.mem.ram.len());
+        self.mem.ram[..n].copy_from_slice(&program[..n]);
+        self.mem.clear_decoded();
+        n
     }
 
     /// Execute the next frame
-    pub fn frame(&mut self) {
-        self.cpu.exec(&mut self.mem);
+    pub fn frame(&mut self) -> Result<(), Error> {
+        self.cpu.exec(&mut self.mem)
+    }
+
+    /// Execute `cycles` instructions through the cached interpreter (see `Blocks`), stopping at the
+    /// first fault. Returns the number of instructions executed, including any that faulted.
+    pub fn run(&mut self, cycles: u64) -> (u64, Result<(), Error>) {
+        self.blocks.run(&mut self.cpu, &mut self.mem, cycles)
     }
 
     /// Return the internal video data
@@ -33,6 +92,7 @@ impl Emulator {
 
     /// Signal a clock tick to the emulator
     pub fn tick(&mut self) {
+        self.ticks += 1;
         self.cpu.tick();
     }
 
@@ -50,3 +110,88 @@ impl Emulator {
         std::mem::replace(&mut self.mem.video.screen_modified, false)
     }
 }
+
+#[test]
+fn test_reset_and_power_cycle() {
+    // Store V0 to 0x300, then loop forever
+    let program = [0x60, 0x42, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x06];
+    let mut emulator = Emulator::new(1);
+    emulator.load(&program);
+    for _ in 0..4 {
+        emulator.frame().unwrap();
+    }
+    emulator.keydown(0x3);
+    emulator.mem.unimplemented = Some(0x0123);
+    assert_eq!(emulator.mem.ram[0x100], 0x42);
+
+    emulator.reset();
+    assert_eq!(emulator.cpu.registers(), Cpu::new(1).registers());
+    assert_eq!(emulator.mem.ram[0x100], 0x42);
+    assert_eq!(emulator.mem.unimplemented, None);
+
+    emulator.power_cycle(&program, false);
+    assert_eq!(emulator.mem.ram[0x100], 0x00);
+    assert_eq!(emulator.mem.ram[..program.len()], program);
+    assert!(emulator.mem.input.is_keydown(0x3));
+
+    emulator.power_cycle(&program, true);
+    assert_eq!(emulator.mem.ram[..program.len()], program);
+    assert!(emulator.mem.ram[program.len()..].iter().any(|&byte| byte != 0));
+    let ram = emulator.mem.ram;
+    emulator.power_cycle(&program, true);
+    assert_eq!(emulator.mem.ram, ram);
+}
+
+/// Compares fetching instructions through the decoded instruction cache with decoding each one,
+/// and executing instructions without the cache, through the cache one by one (`frame`) and with
+/// the cached interpreter. Run with
+/// `cargo test --release bench_exec -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_exec() {
+    use std::{hint::black_box, time::Instant};
+
+    // Count V1 down to 0 in an inner loop and V2 up in an outer one, like the main loop of a game
+    let program = [0x61, 0xFF, 0x71, 0xFF, 0x31, 0x00, 0x12, 0x02, 0x72, 0x01, 0x12, 0x00];
+    const INSTRUCTIONS: u32 = 20_000_000;
+    let mut emulator = Emulator::new(0);
+    emulator.load(&program);
+    let addrs: Vec<u16> =
+        (0..program.len() as u16).step_by(2).map(|i| mem::RAM_START + i).collect();
+
+    let rate = |run: &mut dyn FnMut()| {
+        let start = Instant::now();
+        run();
+        INSTRUCTIONS as f64 / start.elapsed().as_secs_f64() / 1e6
+    };
+    let decode = rate(&mut || {
+        for i in 0..INSTRUCTIONS as usize {
+            let opcode = emulator.mem.read_word(addrs[i % addrs.len()]);
+            let _ = black_box(opcode.and_then(try_decode));
+        }
+    });
+    let fetch = rate(&mut || {
+        for i in 0..INSTRUCTIONS as usize {
+            let _ = black_box(emulator.mem.fetch(addrs[i % addrs.len()]));
+        }
+    });
+    let uncached = rate(&mut || {
+        for _ in 0..INSTRUCTIONS {
+            let op = try_decode(emulator.mem.read_word(emulator.cpu.pc()).unwrap()).unwrap();
+            emulator.cpu.exec_decoded(op, &mut emulator.mem).unwrap();
+        }
+    });
+    let exec = rate(&mut || {
+        for _ in 0..INSTRUCTIONS {
+            emulator.frame().unwrap();
+        }
+    });
+    eprintln!("Decoding: {:.0}M instructions/s", decode);
+    eprintln!("Fetching from the cache: {:.0}M instructions/s ({:.1}x)", fetch, fetch / decode);
+    let blocks = rate(&mut || {
+        emulator.run(INSTRUCTIONS as u64).1.unwrap();
+    });
+    eprintln!("Executing without the cache: {:.0}M instructions/s", uncached);
+    eprintln!("Executing: {:.0}M instructions/s ({:.1}x)", exec, exec / uncached);
+    eprintln!("Executing blocks: {:.0}M instructions/s ({:.1}x)", blocks, blocks / uncached);
+}
@@ -80,11 +80,11 @@ fn flipped(v1: u8, v2: u8) -> bool {
 
 #[test]
 fn test_flipped() {
-    assert!(flipped(0b_0000_0000, 0b_0001_0000) == false);
-    assert!(flipped(0b_0001_0000, 0b_0000_0000) == true);
-    assert!(flipped(0b_1010_0101, 0b_1111_1111) == false);
-    assert!(flipped(0b_1010_0101, 0b_0000_0000) == true);
-    assert!(flipped(0b_1010_0101, 0b_1010_0100) == true);
-    assert!(flipped(0b_1111_0000, 0b_1111_1111) == false);
-    assert!(flipped(0b_1111_0000, 0b_0000_1111) == true);
+    assert!(!flipped(0b_0000_0000, 0b_0001_0000));
+    assert!(flipped(0b_0001_0000, 0b_0000_0000));
+    assert!(!flipped(0b_1010_0101, 0b_1111_1111));
+    assert!(flipped(0b_1010_0101, 0b_0000_0000));
+    assert!(flipped(0b_1010_0101, 0b_1010_0100));
+    assert!(!flipped(0b_1111_0000, 0b_1111_1111));
+    assert!(flipped(0b_1111_0000, 0b_0000_1111));
 }
@@ -0,0 +1,301 @@
+//! A panel for pausing the program, stepping through it forwards and backwards, and seeing how it
+//! arrived at a fault.
+
+use macroquad::prelude::*;
+
+use crate::{
+    chip8::{
+        self,
+        video::{BYTES_WIDTH, HEIGHT, WIDTH},
+    },
+    session::Session,
+};
+
+const FONT_SIZE: f32 = 16.0;
+const LINE_HEIGHT: f32 = 18.0;
+/// Lines used by the header above the registers and history
+const HEADER_LINES: usize = 3;
+/// Where the history column starts
+const HISTORY_X: f32 = 230.0;
+/// Size of a pixel of the framebuffer, the sprite at I and the sprites in memory
+const DISPLAY_PIXEL: f32 = 2.0;
+const SPRITE_PIXEL: f32 = 4.0;
+const BROWSER_PIXEL: f32 = 3.0;
+/// Width of the addresses in front of each row of sprites, and of each sprite with its margin
+const BROWSER_LABEL_WIDTH: f32 = 40.0;
+const BROWSER_COLUMN_WIDTH: f32 = 9.0 * BROWSER_PIXEL;
+/// The most recent writes into code shown
+const CODE_WRITES: usize = 4;
+/// Rows of the sprite at I, the tallest a sprite can be
+const SPRITE_ROWS: u8 = 15;
+
+const BACKGROUND_COLOR: Color = Color::new(0.0, 0.0, 0.0, 0.75);
+const TEXT_COLOR: Color = Color::new(0.8, 0.8, 0.8, 1.0);
+const CURRENT_COLOR: Color = Color::new(0.9, 0.6, 0.1, 1.0);
+const FAULT_COLOR: Color = Color::new(0.9, 0.2, 0.2, 1.0);
+const PIXEL_COLOR: Color = Color::new(0.9, 0.9, 0.9, 1.0);
+const UNSET_COLOR: Color = Color::new(0.2, 0.2, 0.2, 1.0);
+
+pub struct Debugger {
+    /// The program is paused while the debugger is open
+    pub open: bool,
+    /// Whether the program was stopped by a fault when last updated
+    faulted: bool,
+    /// Show sprites instead of the history
+    sprites: bool,
+    /// The first address shown as sprites, and the height of each sprite
+    browse_addr: u16,
+    browse_height: u8,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger { open: false, faulted: false, sprites: false, browse_addr: 0, browse_height: 8 }
+    }
+
+    /// Handle the debugger's keys, and open it when the program faults. The arrow keys are left
+    /// to the memory view while `memory_open`.
+    pub fn update(&mut self, session: &mut Session, memory_open: bool) {
+        let faulted = session.fault().is_some();
+        if faulted && !self.faulted {
+            if let Some(report) = session.fault_report() {
+                eprintln!("{}", report);
+            }
+            self.open = true;
+        }
+        self.faulted = faulted;
+
+        if is_key_pressed(KeyCode::F1) {
+            self.open = !self.open;
+        }
+        if !self.open {
+            return;
+        }
+        if is_key_pressed(KeyCode::F2) {
+            session.step();
+        }
+        if is_key_pressed(KeyCode::F3) && !session.step_back() {
+            eprintln!("No history left to step back through");
+        }
+        if is_key_pressed(KeyCode::G) {
+            self.sprites = !self.sprites;
+            self.browse_addr = session.emulator.cpu.registers().i;
+        }
+        if self.sprites && !memory_open {
+            self.browse();
+        }
+    }
+
+    /// Scroll through memory and change the height of the sprites it is shown as
+    fn browse(&mut self) {
+        let (columns, rows) = browser_grid(self.browse_height);
+        // Large windows fit more than the whole memory in a page
+        let total = chip8::mem::TOTAL_MEMORY as usize;
+        let row = (columns * self.browse_height as usize).min(total) as u16;
+        let page = (row as usize * rows).min(total) as u16;
+        let last = chip8::mem::TOTAL_MEMORY - 1;
+        let addr = self.browse_addr;
+
+        let (_, wheel) = mouse_wheel();
+        self.browse_addr = match () {
+            _ if is_key_pressed(KeyCode::Left) => addr.saturating_sub(1),
+            _ if is_key_pressed(KeyCode::Right) => (addr + 1).min(last),
+            _ if is_key_pressed(KeyCode::Up) || wheel > 0.0 => addr.saturating_sub(row),
+            _ if is_key_pressed(KeyCode::Down) || wheel < 0.0 => (addr + row).min(last),
+            _ if is_key_pressed(KeyCode::PageUp) => addr.saturating_sub(page),
+            _ if is_key_pressed(KeyCode::PageDown) => (addr + page).min(last),
+            _ => addr,
+        };
+        if is_key_pressed(KeyCode::Minus) || is_key_pressed(KeyCode::KpSubtract) {
+            self.browse_height = (self.browse_height - 1).max(1);
+        }
+        if is_key_pressed(KeyCode::Equal) || is_key_pressed(KeyCode::KpAdd) {
+            self.browse_height = (self.browse_height + 1).min(SPRITE_ROWS);
+        }
+    }
+
+    pub fn draw(&self, session: &Session) {
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), BACKGROUND_COLOR);
+
+        let line = |x: f32, row: usize, text: &str, color: Color| {
+            draw_text(text, 8.0 + x, (row + 1) as f32 * LINE_HEIGHT - 4.0, FONT_SIZE, color);
+        };
+
+        let emulator = &session.emulator;
+        let registers = emulator.cpu.registers();
+        match session.fault() {
+            Some(fault) => {
+                line(0.0, 0, &format!("{} at {:#05x}", fault, registers.pc), FAULT_COLOR)
+            }
+            None => line(0.0, 0, "Paused", TEXT_COLOR),
+        }
+        let help = match self.sprites {
+            true => "F1: resume  F2: step  F3: step back  G: history  Arrows: scroll  +/-: height",
+            false => "F1: resume  F2: step  F3: step back  F5: reset  G: sprites  Tab: memory",
+        };
+        line(0.0, 1, help, TEXT_COLOR);
+
+        let mut rows = vec![
+            format!("PC {:03x}  I {:03x}", registers.pc, registers.i),
+            format!("DT {:02x}  ST {:02x}", registers.delay, registers.sound),
+            format!("Frame {}", emulator.ticks),
+        ];
+        for (i, values) in registers.v.chunks(4).enumerate() {
+            let names =
+                values.iter().enumerate().map(|(j, v)| format!("V{:X} {:02x}", i * 4 + j, v));
+            rows.push(names.collect::<Vec<_>>().join(" "));
+        }
+        rows.push(format!("Stack ({})", emulator.mem.stack.len()));
+        for addresses in emulator.mem.stack.chunks(4) {
+            let addresses: Vec<_> = addresses.iter().map(|addr| format!("{:03x}", addr)).collect();
+            rows.push(format!("  {}", addresses.join(" ")));
+        }
+        if let Some(code_writes) = session.code_writes().filter(|writes| !writes.is_empty()) {
+            rows.push(format!("Code writes ({})", code_writes.len()));
+            let mut latest: Vec<_> = code_writes.values().collect();
+            latest.sort_by_key(|write| std::cmp::Reverse(write.frame));
+            for write in latest.iter().take(CODE_WRITES) {
+                let end = write.addr + write.len - 1;
+                let code = match write.executed {
+                    true => "executed",
+                    false => "reachable",
+                };
+                rows.push(format!("  {:03x}: {:03x}-{:03x} {}", write.pc, write.addr, end, code));
+            }
+        }
+        for (i, row) in rows.iter().enumerate() {
+            line(0.0, HEADER_LINES + i, row, TEXT_COLOR);
+        }
+
+        if self.sprites {
+            self.draw_sprites(emulator);
+            return;
+        }
+
+        // The most recently executed instructions that fit, followed by the next one
+        let visible =
+            ((screen_height() / LINE_HEIGHT) as usize).saturating_sub(HEADER_LINES).max(1);
+        let steps: Vec<String> = match session.history() {
+            Some(history) => {
+                history.steps().rev().take(visible - 1).rev().map(|s| s.to_string()).collect()
+            }
+            None => vec![],
+        };
+        for (i, step) in steps.iter().enumerate() {
+            line(HISTORY_X, HEADER_LINES + i, step, TEXT_COLOR);
+        }
+        line(HISTORY_X, HEADER_LINES + steps.len(), &next_instruction(emulator), CURRENT_COLOR);
+    }
+
+    /// The framebuffer with the last sprite drawn outlined, the sprite at I, and memory shown as
+    /// sprites
+    fn draw_sprites(&self, emulator: &chip8::Emulator) {
+        let top = HEADER_LINES as f32 * LINE_HEIGHT;
+        let text = |x: f32, y: f32, text: &str| {
+            draw_text(text, HISTORY_X + 8.0 + x, y + LINE_HEIGHT - 4.0, FONT_SIZE, TEXT_COLOR);
+        };
+
+        let x = HISTORY_X + 8.0;
+        for (i, &byte) in emulator.display().iter().enumerate() {
+            let (column, row) = ((i % BYTES_WIDTH as usize) * 8, i / BYTES_WIDTH as usize);
+            let y = top + row as f32 * DISPLAY_PIXEL;
+            draw_byte(x + column as f32 * DISPLAY_PIXEL, y, byte, DISPLAY_PIXEL);
+        }
+        let display_width = WIDTH as f32 * DISPLAY_PIXEL;
+        let display_height = HEIGHT as f32 * DISPLAY_PIXEL;
+        draw_rectangle_lines(x, top, display_width, display_height, 1.0, TEXT_COLOR);
+
+        let last_draw = match emulator.mem.last_draw {
+            Some(sprite) => {
+                let (sx, sy) = (sprite.x % WIDTH, sprite.y % HEIGHT);
+                let (width, height) = (8.0 * DISPLAY_PIXEL, sprite.height as f32 * DISPLAY_PIXEL);
+                let (sx, sy) = (x + sx as f32 * DISPLAY_PIXEL, top + sy as f32 * DISPLAY_PIXEL);
+                draw_rectangle_lines(sx, sy, width, height, 2.0, CURRENT_COLOR);
+                let collision = match sprite.collision {
+                    true => "collision",
+                    false => "no collision",
+                };
+                format!(
+                    "Last draw: {:03x} at {}, {}, {} rows, {}",
+                    sprite.addr, sprite.x, sprite.y, sprite.height, collision
+                )
+            }
+            None => "Last draw: none".into(),
+        };
+        text(0.0, top + display_height, &last_draw);
+
+        // The sprite at I, next to the framebuffer
+        let i = emulator.cpu.registers().i;
+        let sprite_x = x + display_width + 16.0;
+        text(display_width + 16.0, top - LINE_HEIGHT, &format!("I {:03x}", i));
+        for row in 0..SPRITE_ROWS as u16 {
+            let y = top + row as f32 * SPRITE_PIXEL;
+            if let Ok(byte) = emulator.mem.read_byte(i.wrapping_add(row)) {
+                draw_byte(sprite_x, y, byte, SPRITE_PIXEL);
+            }
+        }
+
+        // Memory from the browsed address, a row of sprites per line with its first address
+        let height = self.browse_height;
+        let (columns, rows) = browser_grid(height);
+        let total = chip8::mem::TOTAL_MEMORY as usize;
+        let browser_top = top + display_height + LINE_HEIGHT;
+        text(0.0, browser_top, &format!("Memory as 8x{} sprites", height));
+        for row in 0..rows {
+            let start = self.browse_addr as usize + row * columns * height as usize;
+            if start >= total {
+                break;
+            }
+            let y = browser_top + LINE_HEIGHT + row as f32 * browser_row_height(height);
+            text(0.0, y, &format!("{:03x}", start));
+            for column in 0..columns {
+                let sprite = start + column * height as usize;
+                let sx = x + BROWSER_LABEL_WIDTH + column as f32 * BROWSER_COLUMN_WIDTH;
+                for dy in 0..height as usize {
+                    // Addresses past the end of memory fail to read and are drawn as such
+                    let byte = emulator.mem.read_byte((sprite + dy).min(total) as u16);
+                    let py = y + dy as f32 * BROWSER_PIXEL;
+                    match byte {
+                        Ok(byte) => draw_byte(sx, py, byte, BROWSER_PIXEL),
+                        Err(_) => draw_rectangle(sx, py, 8.0 * BROWSER_PIXEL, BROWSER_PIXEL, BLACK),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn browser_row_height(height: u8) -> f32 {
+    (height as f32 + 1.0) * BROWSER_PIXEL
+}
+
+/// How many sprites fit in each row of the sprite browser, and how many rows fit
+fn browser_grid(height: u8) -> (usize, usize) {
+    let top = (HEADER_LINES + 2) as f32 * LINE_HEIGHT + HEIGHT as f32 * DISPLAY_PIXEL;
+    let width = screen_width() - HISTORY_X - 8.0 - BROWSER_LABEL_WIDTH;
+    let columns = (width / BROWSER_COLUMN_WIDTH).max(1.0) as usize;
+    let rows = ((screen_height() - top) / browser_row_height(height)).max(1.0) as usize;
+    (columns, rows)
+}
+
+/// Draw the bits of `byte` as a row of pixels, most significant bit first
+fn draw_byte(x: f32, y: f32, byte: u8, size: f32) {
+    for bit in 0..8 {
+        let color = match byte & (0x80 >> bit) != 0 {
+            true => PIXEL_COLOR,
+            false => UNSET_COLOR,
+        };
+        draw_rectangle(x + bit as f32 * size, y, size, size, color);
+    }
+}
+
+/// The instruction at the program counter, in the same format as the history
+fn next_instruction(emulator: &chip8::Emulator) -> String {
+    let pc = emulator.cpu.registers().pc;
+    let op = emulator.mem.read_word(pc).map(|opcode| (opcode, chip8::try_decode(opcode)));
+    match op {
+        Ok((opcode, Ok(op))) => format!("{} {:03x} {:04x} {}", emulator.ticks, pc, opcode, op),
+        Ok((opcode, Err(e))) => format!("{} {:03x} {:04x} ({})", emulator.ticks, pc, opcode, e),
+        Err(e) => format!("{} {:03x} ({})", emulator.ticks, pc, e),
+    }
+}
@@ -0,0 +1,302 @@
+//! Gamepad input using the Linux joystick API (`/dev/input/js*`). On other platforms no gamepads
+//! are detected and the emulator is keyboard only.
+
+use std::{collections::HashMap, sync::mpsc};
+
+use crate::config::{self, Section};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Button {
+    Up,
+    Down,
+    Left,
+    Right,
+    A,
+    B,
+    X,
+    Y,
+    L,
+    R,
+    Select,
+    Start,
+}
+
+impl Button {
+    fn from_name(name: &str) -> Option<Button> {
+        Some(match name {
+            "up" => Button::Up,
+            "down" => Button::Down,
+            "left" => Button::Left,
+            "right" => Button::Right,
+            "a" => Button::A,
+            "b" => Button::B,
+            "x" => Button::X,
+            "y" => Button::Y,
+            "l" => Button::L,
+            "r" => Button::R,
+            "select" => Button::Select,
+            "start" => Button::Start,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct GamepadEvent {
+    pub pad: usize,
+    pub button: Button,
+    pub pressed: bool,
+}
+
+/// Maps gamepad buttons to CHIP-8 keys, with a separate mapping for each gamepad
+pub struct Profile {
+    pads: Vec<HashMap<Button, u8>>,
+}
+
+impl Profile {
+    /// The mapping used when a ROM doesn't specify one. The directions follow the 2/4/6/8 layout
+    /// used by most games written for the COSMAC VIP keypad.
+    fn default_mapping() -> HashMap<Button, u8> {
+        HashMap::from([
+            (Button::Up, 0x2),
+            (Button::Down, 0x8),
+            (Button::Left, 0x4),
+            (Button::Right, 0x6),
+            (Button::A, 0x5),
+            (Button::B, 0x0),
+            (Button::X, 0x1),
+            (Button::Y, 0x3),
+            (Button::L, 0xA),
+            (Button::R, 0xB),
+            (Button::Select, 0xE),
+            (Button::Start, 0xF),
+        ])
+    }
+
+    /// Read the `padN.<button> = <key>` entries of a ROM database entry. A gamepad that has any
+    /// entries uses only those entries, otherwise it falls back to the default mapping.
+    pub fn from_section(section: &Section) -> Profile {
+        let mut pads: Vec<HashMap<Button, u8>> = vec![];
+
+        for (name, value) in section.entries() {
+            let Some((pad, button)) = name.strip_prefix("pad").and_then(|s| s.split_once('.'))
+            else {
+                continue;
+            };
+
+            let (Ok(pad), Some(button), Some(key)) =
+                (pad.parse::<usize>(), Button::from_name(button), config::parse_key(value))
+            else {
+                eprintln!("Ignoring invalid gamepad mapping: {} = {}", name, value);
+                continue;
+            };
+
+            if pads.len() <= pad {
+                pads.resize_with(pad + 1, HashMap::new);
+            }
+            pads[pad].insert(button, key);
+        }
+
+        for pad in pads.iter_mut().filter(|pad| pad.is_empty()) {
+            *pad = Profile::default_mapping();
+        }
+        if pads.is_empty() {
+            pads.push(Profile::default_mapping());
+        }
+
+        Profile { pads }
+    }
+
+    /// Get the key pressed by `button` on gamepad `pad`. Gamepads without their own mapping share
+    /// the mapping of the first gamepad.
+    pub fn key(&self, pad: usize, button: Button) -> Option<u8> {
+        self.pads.get(pad).unwrap_or(&self.pads[0]).get(&button).copied()
+    }
+}
+
+pub struct Gamepads {
+    events: mpsc::Receiver<GamepadEvent>,
+}
+
+impl Gamepads {
+    /// Open all connected gamepads. Each gamepad is read on its own thread.
+    pub fn connect() -> Gamepads {
+        let (sender, events) = mpsc::channel();
+
+        #[cfg(target_os = "linux")]
+        linux::spawn_readers(sender);
+        #[cfg(not(target_os = "linux"))]
+        drop(sender);
+
+        Gamepads { events }
+    }
+
+    /// Returns the button events received since the last poll
+    pub fn poll(&self) -> impl Iterator<Item = GamepadEvent> + '_ {
+        self.events.try_iter()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{fs::File, io::Read, sync::mpsc, thread};
+
+    use super::{Button, GamepadEvent};
+
+    const MAX_GAMEPADS: usize = 4;
+
+    const JS_EVENT_BUTTON: u8 = 0x01;
+    const JS_EVENT_AXIS: u8 = 0x02;
+    const JS_EVENT_INIT: u8 = 0x80;
+
+    /// How far an axis needs to be pushed before it counts as a direction being pressed
+    const AXIS_THRESHOLD: i16 = i16::MAX / 2;
+
+    pub fn spawn_readers(sender: mpsc::Sender<GamepadEvent>) {
+        let mut pad = 0;
+        for i in 0..MAX_GAMEPADS {
+            let Ok(file) = File::open(format!("/dev/input/js{}", i))
+            else {
+                continue;
+            };
+
+            let sender = sender.clone();
+            thread::spawn(move || read_events(pad, file, sender));
+            pad += 1;
+        }
+    }
+
+    fn read_events(pad: usize, mut file: File, sender: mpsc::Sender<GamepadEvent>) {
+        let mut joystick = Joystick::default();
+
+        // struct js_event { u32 time; i16 value; u8 type; u8 number; }
+        let mut event = [0; 8];
+        while file.read_exact(&mut event).is_ok() {
+            let value = i16::from_ne_bytes([event[4], event[5]]);
+            for (button, pressed) in joystick.translate(event[6] & !JS_EVENT_INIT, event[7], value)
+            {
+                if sender.send(GamepadEvent { pad, button, pressed }).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Converts raw joystick events into button presses, assuming the layout used by the `xpad`
+    /// driver (Xbox style controllers), which most other drivers also follow.
+    #[derive(Default)]
+    pub(super) struct Joystick {
+        /// The direction (-1, 0 or 1) each axis is currently pushed in
+        axes: [i8; 8],
+    }
+
+    impl Joystick {
+        pub(super) fn translate(
+            &mut self,
+            kind: u8,
+            number: u8,
+            value: i16,
+        ) -> Vec<(Button, bool)> {
+            match kind {
+                JS_EVENT_BUTTON => match button(number) {
+                    Some(button) => vec![(button, value != 0)],
+                    None => vec![],
+                },
+                JS_EVENT_AXIS => {
+                    let Some((negative, positive)) = axis(number)
+                    else {
+                        return vec![];
+                    };
+
+                    let direction = match value {
+                        v if v <= -AXIS_THRESHOLD => -1,
+                        v if v >= AXIS_THRESHOLD => 1,
+                        _ => 0,
+                    };
+                    let old = std::mem::replace(&mut self.axes[number as usize], direction);
+
+                    let mut events = vec![];
+                    if old != direction {
+                        match old {
+                            -1 => events.push((negative, false)),
+                            1 => events.push((positive, false)),
+                            _ => {}
+                        }
+                        match direction {
+                            -1 => events.push((negative, true)),
+                            1 => events.push((positive, true)),
+                            _ => {}
+                        }
+                    }
+                    events
+                }
+                _ => vec![],
+            }
+        }
+    }
+
+    fn button(number: u8) -> Option<Button> {
+        Some(match number {
+            0 => Button::A,
+            1 => Button::B,
+            2 => Button::X,
+            3 => Button::Y,
+            4 => Button::L,
+            5 => Button::R,
+            6 => Button::Select,
+            7 => Button::Start,
+            // Reported when the D-pad is mapped to buttons instead of a hat
+            11 => Button::Left,
+            12 => Button::Right,
+            13 => Button::Up,
+            14 => Button::Down,
+            _ => return None,
+        })
+    }
+
+    /// The buttons for the negative and positive direction of an axis. Both the left stick (axes 0
+    /// and 1) and the D-pad hat (axes 6 and 7) act as the D-pad.
+    fn axis(number: u8) -> Option<(Button, Button)> {
+        match number {
+            0 | 6 => Some((Button::Left, Button::Right)),
+            1 | 7 => Some((Button::Up, Button::Down)),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_profile() {
+    let mut section = Section::new("pong");
+    section.set("pad0.up", "1");
+    section.set("pad0.down", "4");
+    section.set("pad1.up", "C");
+    section.set("pad1.down", "d");
+    section.set("pad1.bogus", "5");
+    section.set("title", "Pong");
+
+    let profile = Profile::from_section(&section);
+    assert_eq!(profile.key(0, Button::Up), Some(0x1));
+    assert_eq!(profile.key(0, Button::Down), Some(0x4));
+    assert_eq!(profile.key(0, Button::Left), None);
+    assert_eq!(profile.key(1, Button::Up), Some(0xC));
+    assert_eq!(profile.key(1, Button::Down), Some(0xD));
+    assert_eq!(profile.key(2, Button::Up), Some(0x1));
+
+    let profile = Profile::from_section(&Section::new("unknown"));
+    assert_eq!(profile.key(0, Button::Up), Some(0x2));
+    assert_eq!(profile.key(3, Button::Right), Some(0x6));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_joystick_axes() {
+    let mut joystick = linux::Joystick::default();
+
+    assert_eq!(joystick.translate(0x01, 0, 1), vec![(Button::A, true)]);
+    assert_eq!(joystick.translate(0x02, 7, -32767), vec![(Button::Up, true)]);
+    assert_eq!(joystick.translate(0x02, 7, -32000), vec![]);
+    assert_eq!(joystick.translate(0x02, 7, 32767), vec![(Button::Up, false), (Button::Down, true)]);
+    assert_eq!(joystick.translate(0x02, 7, 0), vec![(Button::Down, false)]);
+    assert_eq!(joystick.translate(0x02, 3, 32767), vec![]);
+}
@@ -0,0 +1,52 @@
+//! An overlay of the memory map showing which addresses the program executed (green), read as data
+//! (blue) and wrote (red), drawn over the display.
+
+use macroquad::prelude::*;
+
+use super::view::Layout;
+use crate::{
+    chip8::mem::{DISPLAY_START, RAM_START, RESERVED_START, TOTAL_MEMORY},
+    profile::Profiler,
+};
+
+/// Addresses per row of the map, which gives 64x64 cells filling the display
+const COLUMNS: usize = 64;
+
+const BACKGROUND_COLOR: Color = Color::new(0.0, 0.0, 0.0, 0.85);
+const BOUNDARY_COLOR: Color = Color::new(0.5, 0.5, 0.5, 0.8);
+
+pub fn draw(profiler: &Profiler, layout: &Layout) {
+    let size = layout.display_size();
+    let rows = TOTAL_MEMORY as usize / COLUMNS;
+    let (width, height) = (size.x / COLUMNS as f32, size.y / rows as f32);
+    draw_rectangle(layout.x, layout.y, size.x, size.y, BACKGROUND_COLOR);
+
+    let executed = intensity(&profiler.executed);
+    let read = intensity(&profiler.read);
+    let written = intensity(&profiler.written);
+    for addr in 0..TOTAL_MEMORY as usize {
+        let color = Color::new(written(addr), executed(addr), read(addr), 1.0);
+        if color.r + color.g + color.b == 0.0 {
+            continue;
+        }
+        let x = layout.x + (addr % COLUMNS) as f32 * width;
+        let y = layout.y + (addr / COLUMNS) as f32 * height;
+        draw_rectangle(x, y, width, height, color);
+    }
+
+    // Mark where the program RAM, the reserved area and the display buffer start
+    for boundary in [RAM_START, RESERVED_START, DISPLAY_START] {
+        let y = layout.y + boundary as f32 / COLUMNS as f32 * height;
+        draw_line(layout.x, y, layout.x + size.x, y, 1.0, BOUNDARY_COLOR);
+    }
+}
+
+/// The brightness of each address, on a log scale so that rarely used addresses still show up
+/// next to hot loops
+fn intensity(counts: &[u64]) -> impl Fn(usize) -> f32 + '_ {
+    let max = (counts.iter().copied().max().unwrap_or(0).max(1) as f32).ln_1p();
+    move |addr| match counts[addr] {
+        0 => 0.0,
+        n => 0.25 + 0.75 * (n as f32).ln_1p() / max,
+    }
+}
@@ -0,0 +1,113 @@
+//! An on-screen hex keypad that shows which keys are pressed and can be pressed with the mouse or
+//! by touch.
+
+use macroquad::prelude::*;
+
+use crate::{chip8, session::Session};
+
+/// The layout of the COSMAC VIP hex keypad
+pub const LAYOUT: [[u8; 4]; 4] =
+    [[0x1, 0x2, 0x3, 0xC], [0x4, 0x5, 0x6, 0xD], [0x7, 0x8, 0x9, 0xE], [0xA, 0x0, 0xB, 0xF]];
+
+const KEY_COLOR: Color = Color::new(0.2, 0.2, 0.2, 1.0);
+const PRESSED_COLOR: Color = Color::new(0.9, 0.6, 0.1, 1.0);
+const BORDER_COLOR: Color = Color::new(0.05, 0.05, 0.05, 1.0);
+
+pub struct Keypad {
+    x: f32,
+    y: f32,
+    cell: f32,
+
+    /// Bitmask of the keys currently held down by the mouse or a touch
+    held: u16,
+}
+
+impl Keypad {
+    pub fn new() -> Keypad {
+        // Touches are tracked individually so that multiple keys can be pressed at once
+        simulate_mouse_with_touch(false);
+        Keypad { x: 0.0, y: 0.0, cell: 0.0, held: 0 }
+    }
+
+    /// Move the keypad to `x`, `y`, with each key being a `cell` x `cell` square
+    pub fn place(&mut self, x: f32, y: f32, cell: f32) {
+        self.x = x;
+        self.y = y;
+        self.cell = cell;
+    }
+
+    /// Returns the key at a screen position
+    fn key_at(&self, pos: Vec2) -> Option<u8> {
+        let col = ((pos.x - self.x) / self.cell).floor();
+        let row = ((pos.y - self.y) / self.cell).floor();
+        if (0.0..4.0).contains(&col) && (0.0..4.0).contains(&row) {
+            Some(LAYOUT[row as usize][col as usize])
+        }
+        else {
+            None
+        }
+    }
+
+    /// Press and release keys to match the current mouse and touch state
+    pub fn update(&mut self, session: &mut Session) {
+        let mut held = 0_u16;
+
+        if is_mouse_button_down(MouseButton::Left) {
+            if let Some(key) = self.key_at(mouse_position().into()) {
+                held |= 1 << key;
+            }
+        }
+        for touch in touches() {
+            if matches!(touch.phase, TouchPhase::Ended | TouchPhase::Cancelled) {
+                continue;
+            }
+            if let Some(key) = self.key_at(touch.position) {
+                held |= 1 << key;
+            }
+        }
+
+        for key in 0..0x10 {
+            let mask = 1 << key;
+            if held & mask != 0 && self.held & mask == 0 {
+                session.keydown(key);
+            }
+            else if held & mask == 0 && self.held & mask != 0 {
+                session.keyup(key);
+            }
+        }
+        self.held = held;
+    }
+
+    pub fn draw(&self, input: &chip8::Input) {
+        for (row, keys) in LAYOUT.iter().enumerate() {
+            for (col, &key) in keys.iter().enumerate() {
+                let x = self.x + col as f32 * self.cell;
+                let y = self.y + row as f32 * self.cell;
+
+                let color = if input.is_keydown(key) { PRESSED_COLOR } else { KEY_COLOR };
+                draw_rectangle(x, y, self.cell, self.cell, color);
+                draw_rectangle_lines(x, y, self.cell, self.cell, 2.0, BORDER_COLOR);
+
+                let label = format!("{:X}", key);
+                let font_size = self.cell * 0.5;
+                let size = measure_text(&label, None, font_size as u16, 1.0);
+                draw_text(
+                    &label,
+                    x + (self.cell - size.width) / 2.0,
+                    y + (self.cell + size.height) / 2.0,
+                    font_size,
+                    WHITE,
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_layout_covers_all_keys() {
+    let mut seen = [false; 0x10];
+    for key in LAYOUT.iter().flatten() {
+        seen[*key as usize] = true;
+    }
+    assert!(seen.iter().all(|&x| x));
+}
@@ -0,0 +1,295 @@
+//! A hex view of the whole address space that follows the program while it runs, highlighting the
+//! bytes it writes. Memory and registers can be edited while the program is paused.
+
+use macroquad::prelude::*;
+
+use crate::{
+    chip8::{
+        self,
+        mem::{Region, TOTAL_MEMORY},
+    },
+    session::Session,
+};
+
+const FONT_SIZE: f32 = 16.0;
+const LINE_HEIGHT: f32 = 18.0;
+/// Lines used by the header and the registers above the memory
+const HEADER_LINES: usize = 5;
+/// Bytes per row
+const COLUMNS: u16 = 16;
+/// V0 to VF, I, PC, DT and ST
+const REGISTERS: usize = 20;
+const REGISTER_COLUMNS: usize = 10;
+/// How many frames written bytes stay highlighted
+const HIGHLIGHT_FRAMES: u64 = 60;
+
+const BACKGROUND_COLOR: Color = Color::new(0.0, 0.0, 0.0, 0.85);
+const TEXT_COLOR: Color = Color::new(0.8, 0.8, 0.8, 1.0);
+const GLYPHS_COLOR: Color = Color::new(0.7, 0.5, 0.9, 1.0);
+const RESERVED_COLOR: Color = Color::new(0.4, 0.4, 0.4, 1.0);
+const DISPLAY_COLOR: Color = Color::new(0.4, 0.8, 0.9, 1.0);
+const WRITTEN_COLOR: Color = Color::new(0.9, 0.2, 0.2, 1.0);
+const CURSOR_COLOR: Color = Color::new(0.9, 0.6, 0.1, 1.0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Cursor {
+    /// An index into V0 to VF, then I, PC, DT and ST
+    Register(usize),
+    Memory(u16),
+}
+
+pub struct MemoryView {
+    pub open: bool,
+    cursor: Cursor,
+    /// The first row shown
+    scroll: u16,
+    /// Each byte as of the last update, `None` where it can't be read
+    bytes: Vec<Option<u8>>,
+    /// The update in which each byte last changed while the view was open
+    written: Vec<Option<u64>>,
+    updates: u64,
+}
+
+impl MemoryView {
+    pub fn new() -> MemoryView {
+        MemoryView {
+            open: false,
+            cursor: Cursor::Memory(chip8::mem::RAM_START),
+            scroll: chip8::mem::RAM_START / COLUMNS,
+            bytes: vec![],
+            written: vec![],
+            updates: 0,
+        }
+    }
+
+    /// Whether typed keys are used for editing rather than passed to the emulator
+    pub fn editing(&self, paused: bool) -> bool {
+        self.open && paused
+    }
+
+    /// Handle the view's keys and follow the writes of the program. Typing hex digits edits the
+    /// byte or register under the cursor while `paused`.
+    pub fn update(&mut self, session: &mut Session, paused: bool) {
+        // Macroquad only drops typed characters when they are read
+        let typed: Vec<char> = std::iter::from_fn(get_char_pressed).collect();
+        if is_key_pressed(KeyCode::Tab) {
+            self.open = !self.open;
+            // Only writes made while the view is open are highlighted
+            self.bytes.clear();
+        }
+        if !self.open {
+            return;
+        }
+
+        self.updates += 1;
+        let bytes = (0..TOTAL_MEMORY).map(|addr| session.emulator.mem.read_byte(addr).ok());
+        if self.bytes.is_empty() {
+            self.bytes = bytes.collect();
+            self.written = vec![None; TOTAL_MEMORY as usize];
+        }
+        else {
+            for (addr, byte) in bytes.enumerate() {
+                if self.bytes[addr] != byte {
+                    self.bytes[addr] = byte;
+                    self.written[addr] = Some(self.updates);
+                }
+            }
+        }
+
+        self.navigate(&session.emulator);
+        if !paused {
+            return;
+        }
+        for digit in typed.iter().filter_map(|c| c.to_digit(16)) {
+            if let Err(e) = edit(&mut session.emulator, self.cursor, digit as u8) {
+                eprintln!("Can't edit memory: {}", e);
+            }
+        }
+    }
+
+    fn navigate(&mut self, emulator: &chip8::Emulator) {
+        let visible = visible_rows();
+        let last = TOTAL_MEMORY - 1;
+        let page = visible * COLUMNS;
+
+        self.cursor = match self.cursor {
+            Cursor::Register(i) if is_key_pressed(KeyCode::Left) => {
+                Cursor::Register(i.saturating_sub(1))
+            }
+            Cursor::Register(i) if is_key_pressed(KeyCode::Right) => {
+                Cursor::Register((i + 1).min(REGISTERS - 1))
+            }
+            Cursor::Register(i) if is_key_pressed(KeyCode::Up) => {
+                Cursor::Register(i.saturating_sub(REGISTER_COLUMNS))
+            }
+            Cursor::Register(i) if is_key_pressed(KeyCode::Down) => match i + REGISTER_COLUMNS {
+                below if below < REGISTERS => Cursor::Register(below),
+                _ => Cursor::Memory(self.scroll * COLUMNS + (i % REGISTER_COLUMNS) as u16),
+            },
+            Cursor::Memory(addr) if is_key_pressed(KeyCode::Left) => {
+                Cursor::Memory(addr.saturating_sub(1))
+            }
+            Cursor::Memory(addr) if is_key_pressed(KeyCode::Right) => {
+                Cursor::Memory((addr + 1).min(last))
+            }
+            Cursor::Memory(addr) if is_key_pressed(KeyCode::Up) && addr < COLUMNS => {
+                Cursor::Register(REGISTER_COLUMNS + (addr as usize).min(REGISTER_COLUMNS - 1))
+            }
+            Cursor::Memory(addr) if is_key_pressed(KeyCode::Up) => Cursor::Memory(addr - COLUMNS),
+            Cursor::Memory(addr) if is_key_pressed(KeyCode::Down) => {
+                Cursor::Memory((addr + COLUMNS).min(last))
+            }
+            Cursor::Memory(addr) if is_key_pressed(KeyCode::PageUp) => {
+                Cursor::Memory(addr.saturating_sub(page))
+            }
+            Cursor::Memory(addr) if is_key_pressed(KeyCode::PageDown) => {
+                Cursor::Memory((addr + page).min(last))
+            }
+            cursor => cursor,
+        };
+        // Jump to the program counter or the address register
+        if is_key_pressed(KeyCode::P) {
+            self.cursor = Cursor::Memory(emulator.cpu.registers().pc.min(last));
+        }
+        if is_key_pressed(KeyCode::I) {
+            self.cursor = Cursor::Memory(emulator.cpu.registers().i.min(last));
+        }
+
+        let (_, wheel) = mouse_wheel();
+        let max_scroll = TOTAL_MEMORY / COLUMNS - visible.min(TOTAL_MEMORY / COLUMNS);
+        if wheel != 0.0 {
+            self.scroll = match wheel > 0.0 {
+                true => self.scroll.saturating_sub(3),
+                false => (self.scroll + 3).min(max_scroll),
+            };
+        }
+        else if let Cursor::Memory(addr) = self.cursor {
+            // Keep the cursor on screen
+            let row = addr / COLUMNS;
+            if row < self.scroll {
+                self.scroll = row;
+            }
+            else if row >= self.scroll + visible {
+                self.scroll = row + 1 - visible;
+            }
+        }
+    }
+
+    pub fn draw(&self, session: &Session, paused: bool) {
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), BACKGROUND_COLOR);
+
+        let text = |x: f32, row: usize, text: &str, color: Color| {
+            draw_text(text, 8.0 + x, (row + 1) as f32 * LINE_HEIGHT - 4.0, FONT_SIZE, color);
+        };
+        let width = |text: &str| measure_text(text, None, FONT_SIZE as u16, 1.0).width;
+        let highlight = |x: f32, row: usize, text: &str, color: Color| {
+            draw_rectangle(8.0 + x, row as f32 * LINE_HEIGHT, width(text), LINE_HEIGHT, color);
+        };
+
+        let header = match paused {
+            true => "Tab: close  Arrows: move  0-F: edit  P: go to PC  I: go to I",
+            false => "Tab: close  Arrows: move  P: go to PC  I: go to I  F1: pause to edit",
+        };
+        text(0.0, 0, "Memory", TEXT_COLOR);
+        text(0.0, 1, header, TEXT_COLOR);
+
+        let registers = session.emulator.cpu.registers();
+        let register_width = width("PC 000 ");
+        for index in 0..REGISTERS {
+            let (name, value) = match index {
+                0..=15 => (format!("V{:X}", index), format!("{:02x}", registers.v[index])),
+                16 => ("I".into(), format!("{:03x}", registers.i)),
+                17 => ("PC".into(), format!("{:03x}", registers.pc)),
+                18 => ("DT".into(), format!("{:02x}", registers.delay)),
+                _ => ("ST".into(), format!("{:02x}", registers.sound)),
+            };
+            let x = (index % REGISTER_COLUMNS) as f32 * register_width;
+            let row = 2 + index / REGISTER_COLUMNS;
+            let name = format!("{} ", name);
+            if self.cursor == Cursor::Register(index) {
+                highlight(x + width(&name), row, &value, CURSOR_COLOR);
+            }
+            text(x, row, &format!("{}{}", name, value), TEXT_COLOR);
+        }
+
+        let address_width = width("000  ");
+        let cell_width = width("00 ");
+        for row in 0..visible_rows() {
+            let start = (self.scroll + row) * COLUMNS;
+            if start >= TOTAL_MEMORY {
+                break;
+            }
+            let line = HEADER_LINES + row as usize;
+            text(0.0, line, &format!("{:03x}", start), TEXT_COLOR);
+            for addr in start..start + COLUMNS {
+                let x = address_width + (addr - start) as f32 * cell_width;
+                let byte = self.bytes.get(addr as usize).copied().flatten();
+                let value = byte.map(|b| format!("{:02x}", b)).unwrap_or("--".into());
+
+                let age =
+                    self.written.get(addr as usize).copied().flatten().map(|w| self.updates - w);
+                if self.cursor == Cursor::Memory(addr) {
+                    highlight(x, line, &value, CURSOR_COLOR);
+                }
+                else if let Some(age) = age.filter(|&age| age < HIGHLIGHT_FRAMES) {
+                    let fade = 1.0 - age as f32 / HIGHLIGHT_FRAMES as f32;
+                    highlight(x, line, &value, Color { a: fade, ..WRITTEN_COLOR });
+                }
+                text(x, line, &value, region_color(addr));
+            }
+        }
+    }
+}
+
+fn region_color(addr: u16) -> Color {
+    match chip8::mem::region(addr) {
+        Some(Region::Glyphs) => GLYPHS_COLOR,
+        Some(Region::Ram) => TEXT_COLOR,
+        Some(Region::Display) => DISPLAY_COLOR,
+        Some(Region::Reserved) | None => RESERVED_COLOR,
+    }
+}
+
+fn visible_rows() -> u16 {
+    ((screen_height() / LINE_HEIGHT) as usize).saturating_sub(HEADER_LINES).max(1) as u16
+}
+
+/// Shift a hex digit into the byte or register at `cursor`
+fn edit(emulator: &mut chip8::Emulator, cursor: Cursor, digit: u8) -> Result<(), chip8::Error> {
+    match cursor {
+        Cursor::Memory(addr) => {
+            let byte = emulator.mem.read_byte(addr)?;
+            emulator.mem.write_byte(addr, byte << 4 | digit)
+        }
+        Cursor::Register(index) => {
+            let mut registers = emulator.cpu.registers();
+            let shift = |value: u16| (value << 4 | digit as u16) & 0xFFF;
+            match index {
+                0..=15 => registers.v[index] = registers.v[index] << 4 | digit,
+                16 => registers.i = shift(registers.i),
+                17 => registers.pc = shift(registers.pc),
+                18 => registers.delay = registers.delay << 4 | digit,
+                _ => registers.sound = registers.sound << 4 | digit,
+            }
+            emulator.cpu.set_registers(&registers);
+            Ok(())
+        }
+    }
+}
+
+#[test]
+fn test_edit() {
+    let mut emulator = chip8::Emulator::new(0);
+    for digit in [0xA, 0xB, 0xC] {
+        edit(&mut emulator, Cursor::Memory(0x300), digit).unwrap();
+        edit(&mut emulator, Cursor::Register(3), digit).unwrap();
+        edit(&mut emulator, Cursor::Register(17), digit).unwrap();
+    }
+    assert_eq!(emulator.mem.read_byte(0x300), Ok(0xBC));
+    assert_eq!(emulator.cpu.registers().v[3], 0xBC);
+    assert_eq!(emulator.cpu.registers().pc, 0xABC);
+
+    let error = edit(&mut emulator, Cursor::Memory(0x010), 1);
+    assert_eq!(error, Err(chip8::Error::ReadOnlyAddress(0x010)));
+    assert!(edit(&mut emulator, Cursor::Memory(0xEA0), 1).is_err());
+}
@@ -0,0 +1,345 @@
+//! A menu for loading ROMs and resetting the running program without restarting the emulator.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use macroquad::prelude::*;
+
+use crate::{
+    cheat::{Cheat, Comparison, Search},
+    rom::{self, Database, Recent, Rom},
+};
+
+/// Extensions of the files shown in the ROM browser. Files without an extension are shown as well
+/// (if they are small enough), since many ROM collections don't use one.
+const EXTENSIONS: [&str; 4] = ["ch8", "c8", "sc8", "xo8"];
+const MAX_UNKNOWN_SIZE: u64 = 0x1000;
+/// Cheat search candidates are offered as cheats once there are this few left
+const MAX_CANDIDATES: usize = 8;
+
+const FONT_SIZE: f32 = 20.0;
+const LINE_HEIGHT: f32 = 24.0;
+/// Lines used by the header above the items
+const HEADER_LINES: f32 = 2.0;
+
+const BACKGROUND_COLOR: Color = Color::new(0.0, 0.0, 0.0, 0.85);
+const TEXT_COLOR: Color = Color::new(0.8, 0.8, 0.8, 1.0);
+const SELECTED_COLOR: Color = Color::new(0.9, 0.6, 0.1, 1.0);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Restart the running program, see `Session::reset`
+    Reset,
+    PowerCycle,
+    Load(PathBuf),
+    /// Browse to a directory
+    Open(PathBuf),
+    /// Turn the cheat with the given index on or off
+    ToggleCheat(usize),
+    /// Freeze an address at a value
+    AddCheat(u16, u8),
+    /// Start a new cheat search, or narrow down the current one
+    Search(Option<Comparison>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Item {
+    pub label: String,
+    pub action: Action,
+}
+
+pub struct Menu {
+    pub open: bool,
+    dir: PathBuf,
+
+    /// The items shown above the directory listing
+    fixed: Vec<Item>,
+    items: Vec<Item>,
+    selected: usize,
+    /// The first item shown
+    scroll: usize,
+}
+
+impl Menu {
+    /// Create a closed menu, browsing `dir` when first opened
+    pub fn new(dir: &Path) -> Menu {
+        Menu { open: false, dir: dir.into(), fixed: vec![], items: vec![], selected: 0, scroll: 0 }
+    }
+
+    pub fn show(
+        &mut self,
+        recent: &Recent,
+        database: &Database,
+        cheats: &[Cheat],
+        search: Option<&Search>,
+    ) {
+        self.fixed = vec![Item { label: "Reset (F5)".into(), action: Action::Reset }, Item {
+            label: "Power cycle (F6)".into(),
+            action: Action::PowerCycle,
+        }];
+        self.fixed.extend(cheat_items(cheats, search));
+        for path in &recent.paths {
+            let label = format!("Recent: {}", label(path, database));
+            self.fixed.push(Item { label, action: Action::Load(path.clone()) });
+        }
+
+        let dir = self.dir.clone();
+        self.browse(&dir, database);
+        self.open = true;
+    }
+
+    fn browse(&mut self, dir: &Path, database: &Database) {
+        let listing = list_dir(dir, database).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            vec![]
+        });
+
+        self.dir = dir.into();
+        self.items = self.fixed.clone();
+        self.items.extend(listing);
+        self.selected = 0;
+        self.scroll = 0;
+    }
+
+    /// Handle input while the menu is open. Returns the action chosen by the user, after which
+    /// the menu is closed.
+    pub fn update(&mut self, database: &Database) -> Option<Action> {
+        let visible = visible_lines();
+        let last = self.items.len().saturating_sub(1);
+
+        if is_key_pressed(KeyCode::Escape) {
+            self.open = false;
+            return None;
+        }
+        if is_key_pressed(KeyCode::Up) {
+            self.selected = self.selected.saturating_sub(1);
+        }
+        if is_key_pressed(KeyCode::Down) {
+            self.selected = (self.selected + 1).min(last);
+        }
+        if is_key_pressed(KeyCode::PageUp) {
+            self.selected = self.selected.saturating_sub(visible);
+        }
+        if is_key_pressed(KeyCode::PageDown) {
+            self.selected = (self.selected + visible).min(last);
+        }
+        if is_key_pressed(KeyCode::Home) {
+            self.selected = 0;
+        }
+        if is_key_pressed(KeyCode::End) {
+            self.selected = last;
+        }
+
+        let (_, wheel) = mouse_wheel();
+        if wheel != 0.0 {
+            let max_scroll = self.items.len().saturating_sub(visible);
+            self.scroll = match wheel > 0.0 {
+                true => self.scroll.saturating_sub(3),
+                false => (self.scroll + 3).min(max_scroll),
+            };
+        }
+        else {
+            // Keep the selected item on screen
+            if self.selected < self.scroll {
+                self.scroll = self.selected;
+            }
+            else if self.selected >= self.scroll + visible {
+                self.scroll = self.selected + 1 - visible;
+            }
+        }
+
+        let mut activate = is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::KpEnter);
+        if is_mouse_button_pressed(MouseButton::Left) {
+            let line = (mouse_position().1 / LINE_HEIGHT - HEADER_LINES).floor();
+            let index = self.scroll as f32 + line;
+            if line >= 0.0 && index < self.items.len() as f32 {
+                self.selected = index as usize;
+                activate = true;
+            }
+        }
+
+        if is_key_pressed(KeyCode::Backspace) {
+            if let Some(parent) = self.dir.parent().map(Path::to_path_buf) {
+                self.browse(&parent, database);
+            }
+        }
+        else if activate && !self.items.is_empty() {
+            match self.items[self.selected].action.clone() {
+                Action::Open(dir) => self.browse(&dir, database),
+                action => {
+                    self.open = false;
+                    return Some(action);
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn draw(&self) {
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), BACKGROUND_COLOR);
+
+        let line = |row: f32, text: &str, color: Color| {
+            draw_text(text, 8.0, (row + 1.0) * LINE_HEIGHT - 6.0, FONT_SIZE, color);
+        };
+        line(0.0, &format!("{}", self.dir.display()), TEXT_COLOR);
+        line(1.0, "Enter: select  Backspace: parent directory  Esc: close", TEXT_COLOR);
+
+        let visible = self.items.iter().enumerate().skip(self.scroll).take(visible_lines());
+        for (row, (i, item)) in visible.enumerate() {
+            let color = if i == self.selected { SELECTED_COLOR } else { TEXT_COLOR };
+            line(HEADER_LINES + row as f32, &item.label, color);
+        }
+    }
+}
+
+/// Items for toggling the cheats and searching for new ones
+fn cheat_items(cheats: &[Cheat], search: Option<&Search>) -> Vec<Item> {
+    let mut items = vec![];
+    for (i, cheat) in cheats.iter().enumerate() {
+        let state = match cheat.enabled {
+            true => "on",
+            false => "off",
+        };
+        let name = match cheat.name.as_str() {
+            "" => format!("{:03x}", cheat.addr),
+            name => name.into(),
+        };
+        let label = format!(
+            "Cheat: {} ({:?} {:03x} = {:02x}) [{}]",
+            name, cheat.kind, cheat.addr, cheat.value, state
+        );
+        items.push(Item { label, action: Action::ToggleCheat(i) });
+    }
+
+    let Some(search) = search
+    else {
+        items.push(Item { label: "Cheat search: start".into(), action: Action::Search(None) });
+        return items;
+    };
+    let left = search.candidates.len();
+    let comparisons = [
+        (Comparison::Changed, "changed"),
+        (Comparison::Unchanged, "unchanged"),
+        (Comparison::Increased, "increased"),
+        (Comparison::Decreased, "decreased"),
+    ];
+    for (comparison, name) in comparisons {
+        let label = format!("Cheat search: keep {} values ({} left)", name, left);
+        items.push(Item { label, action: Action::Search(Some(comparison)) });
+    }
+    if left <= MAX_CANDIDATES {
+        for &addr in &search.candidates {
+            let value = search.value(addr);
+            let label = format!("Cheat search: freeze {:03x} = {:02x}", addr, value);
+            items.push(Item { label, action: Action::AddCheat(addr, value) });
+        }
+    }
+    items.push(Item { label: "Cheat search: start over".into(), action: Action::Search(None) });
+    items
+}
+
+fn visible_lines() -> usize {
+    ((screen_height() / LINE_HEIGHT - HEADER_LINES).floor() as usize).max(1)
+}
+
+/// The title of a ROM from the database followed by its file name, or just the file name
+fn label(path: &Path, database: &Database) -> String {
+    let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy();
+    // Only read the file if its name isn't in the database, and without looking for patches
+    let title = match database.by_name(&rom::name(path)) {
+        Some(entry) => entry.get("title").map(String::from),
+        None => Rom::load_patched(path, None)
+            .ok()
+            .and_then(|rom| database.by_hash(rom.hash)?.get("title").map(String::from)),
+    };
+    match title {
+        Some(title) => format!("{} ({})", title, name),
+        None => name.into(),
+    }
+}
+
+/// List the parent directory, subdirectories and ROMs in `dir`, with directories first and each
+/// group sorted by name
+pub fn list_dir(dir: &Path, database: &Database) -> Result<Vec<Item>, String> {
+    let entries =
+        fs::read_dir(dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+
+    let mut dirs = vec![];
+    let mut roms = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(metadata) = fs::metadata(&path)
+        else {
+            continue;
+        };
+
+        if metadata.is_dir() {
+            dirs.push(path);
+            continue;
+        }
+        let is_rom = match path.extension() {
+            Some(ext) => EXTENSIONS.iter().any(|rom| ext.eq_ignore_ascii_case(rom)),
+            None => metadata.len() <= MAX_UNKNOWN_SIZE,
+        };
+        if is_rom {
+            roms.push(path);
+        }
+    }
+    dirs.sort();
+    roms.sort();
+
+    let mut items = vec![];
+    if let Some(parent) = dir.parent() {
+        items.push(Item { label: "../".into(), action: Action::Open(parent.into()) });
+    }
+    for path in dirs {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        items.push(Item { label: format!("{}/", name), action: Action::Open(path.clone()) });
+    }
+    for path in roms {
+        items.push(Item { label: label(&path, database), action: Action::Load(path) });
+    }
+    Ok(items)
+}
+
+#[test]
+fn test_list_dir() {
+    let dir = std::env::temp_dir().join(format!("chip8_emu_test_roms_{}", std::process::id()));
+    fs::create_dir_all(dir.join("games")).unwrap();
+    fs::write(dir.join("PONG.ch8"), [0x12, 0x00]).unwrap();
+    fs::write(dir.join("maze"), [0x12, 0x00]).unwrap();
+    fs::write(dir.join("notes.txt"), "not a rom").unwrap();
+
+    let items = list_dir(&dir, &rom::builtin_database());
+    fs::remove_dir_all(&dir).unwrap();
+
+    let labels: Vec<_> = items.unwrap().into_iter().map(|item| item.label).collect();
+    assert_eq!(labels, ["../", "games/", "Pong [Paul Vervalin, 1990] (PONG.ch8)", "maze"]);
+}
+
+#[test]
+fn test_cheat_items() {
+    let cheat = Cheat {
+        addr: 0x2f0,
+        value: 3,
+        kind: crate::cheat::Kind::Freeze,
+        enabled: true,
+        name: "Infinite lives".into(),
+    };
+    let items = cheat_items(&[cheat], None);
+    let labels: Vec<_> = items.iter().map(|item| item.label.as_str()).collect();
+    assert_eq!(labels, ["Cheat: Infinite lives (Freeze 2f0 = 03) [on]", "Cheat search: start"]);
+
+    let mut memory = crate::chip8::Memory::new();
+    let mut search = Search::new(&memory);
+    memory.ram[0xf0] = 2;
+    search.filter(&memory, Comparison::Increased);
+    let items = cheat_items(&[], Some(&search));
+    assert_eq!(items.len(), 6);
+    assert_eq!(items[0].label, "Cheat search: keep changed values (1 left)");
+    assert_eq!(items[4].label, "Cheat search: freeze 2f0 = 02");
+    assert_eq!(items[4].action, Action::AddCheat(0x2f0, 2));
+}
@@ -0,0 +1,369 @@
+use macroquad::{miniquad::EventHandler, prelude::*, texture};
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    capture, cheat, chip8, config, display, options::Options, profile::Profiler, rom,
+    session::Session,
+};
+
+pub mod debugger;
+pub mod gamepad;
+pub mod heatmap;
+pub mod keypad;
+pub mod memory;
+pub mod menu;
+pub mod view;
+
+const SRC_WIDTH: u32 = chip8::video::WIDTH as u32;
+const SRC_HEIGHT: u32 = chip8::video::HEIGHT as u32;
+
+struct Chip8EventHandler<'a> {
+    session: &'a mut Session,
+    view: &'a mut view::View,
+    /// Keys aren't passed to the emulator while the menu is open or memory is being edited
+    capture_keys: bool,
+    /// The files dropped onto the window when last checked, see `dropped_file`
+    dropped: &'a mut Vec<PathBuf>,
+    /// A file dropped since the last frame
+    load: Option<PathBuf>,
+}
+
+impl<'a> EventHandler for Chip8EventHandler<'a> {
+    fn update(&mut self, _ctx: &mut macroquad::miniquad::Context) {}
+    fn draw(&mut self, _ctx: &mut macroquad::miniquad::Context) {}
+
+    fn mouse_motion_event(&mut self, ctx: &mut macroquad::miniquad::Context, _x: f32, _y: f32) {
+        // macroquad doesn't pass on file drop events, so dropped files are picked up once the
+        // mouse moves over the window
+        if let Some(path) = dropped_file(ctx, self.dropped) {
+            self.load = Some(path);
+        }
+    }
+
+    fn key_up_event(
+        &mut self,
+        _ctx: &mut macroquad::miniquad::Context,
+        keycode: KeyCode,
+        _keymods: macroquad::miniquad::KeyMods,
+    ) {
+        eprintln!("keyup: {keycode:?}");
+        if self.capture_keys {
+            return;
+        }
+        if let Some(key) = convert_keycode(keycode) {
+            self.session.keyup(key)
+        }
+    }
+
+    fn key_down_event(
+        &mut self,
+        ctx: &mut macroquad::miniquad::Context,
+        keycode: KeyCode,
+        _keymods: macroquad::miniquad::KeyMods,
+        repeat: bool,
+    ) {
+        eprintln!("keydown: {keycode:?}");
+        if keycode == KeyCode::F11 && !repeat {
+            self.view.fullscreen = !self.view.fullscreen;
+            ctx.set_fullscreen(self.view.fullscreen);
+        }
+        if self.capture_keys {
+            return;
+        }
+        if let Some(key) = convert_keycode(keycode) {
+            self.session.keydown(key)
+        }
+    }
+}
+
+pub async fn run(
+    mut session: Session,
+    database: rom::Database,
+    mut palette: display::Palette,
+    mut view: view::View,
+    options: Options,
+) -> Result<(), String> {
+    let mut screen = Image::gen_image_color(SRC_WIDTH as u16, SRC_HEIGHT as u16, WHITE);
+    let screen_texture = texture::render_target(SRC_WIDTH, SRC_HEIGHT).texture;
+    screen_texture.set_filter(FilterMode::Nearest);
+
+    // Closing the window is handled in the main loop so that recordings can be finished
+    prevent_quit();
+
+    let events_subscriber = utils::register_input_subscriber();
+    let gamepads = gamepad::Gamepads::connect();
+    let mut profile = gamepad::Profile::from_section(&database.lookup(&session.rom));
+    let mut keypad = keypad::Keypad::new();
+
+    let mut recent = rom::Recent::load();
+    add_recent(&mut recent, &session.rom.path);
+    let rom_path = std::fs::canonicalize(&session.rom.path).unwrap_or_default();
+    let mut menu = menu::Menu::new(rom_path.parent().unwrap_or(Path::new(".")));
+    let mut dropped = vec![];
+    let mut debugger = debugger::Debugger::new();
+    let mut memory = memory::MemoryView::new();
+    let mut heatmap = false;
+    let mut search: Option<cheat::Search> = None;
+    // The debugger lists the writes into code
+    session.detect_code_writes();
+
+    loop {
+        let layout = view.layout(screen_width(), screen_height());
+        view.resized(&layout);
+
+        if is_quit_requested() {
+            session.finish();
+            if let Err(e) = config::save_user(&view.settings()) {
+                eprintln!("Failed to save settings: {}", e);
+            }
+            return Ok(());
+        }
+
+        let capture_keys = menu.open || memory.editing(debugger.open);
+        let mut handler = Chip8EventHandler {
+            session: &mut session,
+            view: &mut view,
+            capture_keys,
+            dropped: &mut dropped,
+            load: None,
+        };
+        utils::repeat_all_miniquad_input(&mut handler, events_subscriber);
+        let mut load = handler.load;
+        for event in gamepads.poll() {
+            if let Some(key) = profile.key(event.pad, event.button) {
+                match event.pressed {
+                    _ if menu.open => {}
+                    true => session.keydown(key),
+                    false => session.keyup(key),
+                }
+            }
+        }
+
+        if menu.open {
+            match menu.update(&database) {
+                Some(menu::Action::Reset) => session.reset(),
+                Some(menu::Action::PowerCycle) => session.power_cycle(),
+                Some(menu::Action::Load(path)) => load = Some(path),
+                Some(menu::Action::ToggleCheat(index)) => {
+                    session.toggle_cheat(index);
+                    save_cheats(&session);
+                }
+                Some(menu::Action::AddCheat(addr, value)) => {
+                    let name = format!("Found at {:03x}", addr);
+                    let kind = cheat::Kind::Freeze;
+                    session.add_cheat(cheat::Cheat { addr, value, kind, enabled: true, name });
+                    save_cheats(&session);
+                    search = None;
+                }
+                Some(menu::Action::Search(None)) => {
+                    search = Some(cheat::Search::new(&session.emulator.mem));
+                }
+                Some(menu::Action::Search(Some(comparison))) => {
+                    if let Some(search) = &mut search {
+                        search.filter(&session.emulator.mem, comparison);
+                    }
+                }
+                _ => {}
+            }
+        }
+        else if is_key_pressed(KeyCode::Escape) {
+            // Release all keys, as their key up events would go to the menu
+            for key in 0..0x10 {
+                if session.emulator.mem.input.is_keydown(key) {
+                    session.keyup(key);
+                }
+            }
+            menu.show(&recent, &database, session.cheats(), search.as_ref());
+        }
+        if let Some(path) = load {
+            match rom::Rom::load(&path) {
+                Ok(rom) => {
+                    let entry = database.lookup(&rom);
+                    eprintln!("Loading: {}", entry.get("title").unwrap_or(&rom.name()));
+                    profile = gamepad::Profile::from_section(&entry);
+                    if let (None, Some(value)) = (&options.palette, entry.get("palette")) {
+                        match display::Palette::parse(value) {
+                            Ok(rom_palette) => palette = rom_palette,
+                            Err(e) => eprintln!("Ignoring palette setting: {}", e),
+                        }
+                    }
+                    add_recent(&mut recent, &path);
+                    let cheats = cheat::load(rom.hash);
+                    session.load(rom, cheats);
+                    search = None;
+                }
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+
+        if !menu.open {
+            debugger.update(&mut session, memory.open);
+            memory.update(&mut session, debugger.open);
+        }
+
+        // The emulator is paused while the menu or the debugger is open
+        if !menu.open && !debugger.open {
+            keypad.place(layout.keypad_x(), layout.y, layout.keypad_cell());
+            keypad.update(&mut session);
+            session.run(get_frame_time() as f64);
+        }
+        for message in session.take_messages() {
+            eprintln!("{}", message);
+        }
+
+        if is_key_pressed(KeyCode::F5) {
+            session.reset();
+        }
+        if is_key_pressed(KeyCode::F6) {
+            session.power_cycle();
+        }
+        if is_key_pressed(KeyCode::F12) {
+            screenshot(&session, &palette, &options);
+        }
+        if is_key_pressed(KeyCode::F10) {
+            toggle_video(&mut session, &palette, &options);
+        }
+
+        if is_key_pressed(KeyCode::F4) {
+            heatmap = !heatmap;
+            if heatmap && session.profiler().is_none() {
+                session.profile(Profiler::new(None));
+            }
+        }
+        if is_key_pressed(KeyCode::F8) {
+            view.next_overlay();
+        }
+        if is_key_pressed(KeyCode::F9) {
+            view.next_scaling();
+        }
+
+        let mut redraw = session.emulator.poll_screen();
+        if is_key_pressed(KeyCode::F7) {
+            palette = palette.next_theme();
+            eprintln!("Palette: {}", palette.name().unwrap_or("custom"));
+            redraw = true;
+        }
+
+        if redraw {
+            render_screen(&mut screen, session.emulator.display(), &palette);
+            screen_texture.update(&screen);
+        }
+
+        let [r, g, b, a] = palette.background();
+        clear_background(Color::from_rgba(r, g, b, a));
+        draw_texture_ex(screen_texture, layout.x, layout.y, WHITE, DrawTextureParams {
+            dest_size: Some(layout.display_size()),
+            ..Default::default()
+        });
+        view.draw_overlay(&layout);
+        if let (true, Some(profiler)) = (heatmap, session.profiler()) {
+            heatmap::draw(profiler, &layout);
+        }
+        keypad.draw(&session.emulator.mem.input);
+        if memory.open {
+            memory.draw(&session, debugger.open);
+        }
+        else if debugger.open {
+            debugger.draw(&session);
+        }
+        if menu.open {
+            menu.draw();
+        }
+
+        next_frame().await
+    }
+}
+
+fn convert_keycode(code: KeyCode) -> Option<u8> {
+    // ------------
+    // 1234    123C
+    // QWER => 456D
+    // ASDF    789E
+    // ZXCV    A0BF
+    // ------------
+    match code {
+        KeyCode::Key1 => Some(0x1),
+        KeyCode::Key2 => Some(0x2),
+        KeyCode::Key3 => Some(0x3),
+        KeyCode::Key4 => Some(0xC),
+        KeyCode::Q => Some(0x4),
+        KeyCode::W => Some(0x5),
+        KeyCode::E => Some(0x6),
+        KeyCode::R => Some(0xD),
+        KeyCode::A => Some(0x7),
+        KeyCode::S => Some(0x8),
+        KeyCode::D => Some(0x9),
+        KeyCode::F => Some(0xE),
+        KeyCode::Z => Some(0xA),
+        KeyCode::X => Some(0x0),
+        KeyCode::C => Some(0xB),
+        KeyCode::V => Some(0xF),
+        _ => None,
+    }
+}
+
+fn save_cheats(session: &Session) {
+    if let Err(e) = cheat::save(session.rom.hash, session.cheats()) {
+        eprintln!("Failed to save cheats: {}", e);
+    }
+}
+
+fn add_recent(recent: &mut rom::Recent, path: &Path) {
+    recent.add(path);
+    if let Err(e) = recent.save() {
+        eprintln!("Failed to save recent ROMs: {}", e);
+    }
+}
+
+/// Returns a file dropped onto the window since the last call, `last` holds the previous drop.
+/// Depending on the platform, miniquad may not report dropped files.
+fn dropped_file(
+    ctx: &mut macroquad::miniquad::Context,
+    last: &mut Vec<PathBuf>,
+) -> Option<PathBuf> {
+    let files: Vec<_> =
+        (0..ctx.dropped_file_count()).filter_map(|i| ctx.dropped_file_path(i)).collect();
+    if files == *last {
+        return None;
+    }
+    *last = files;
+    last.first().cloned()
+}
+
+fn render_screen(dst: &mut Image, chip8_image: &[u8], palette: &display::Palette) {
+    dst.bytes.copy_from_slice(&display::to_rgba(chip8_image, palette, 1));
+}
+
+fn screenshot(session: &Session, palette: &display::Palette, options: &Options) {
+    let path = match &options.screenshot {
+        Some(path) => path.clone(),
+        None => format!("{}-{}.png", session.rom.name(), session.emulator.ticks).into(),
+    };
+
+    let display = session.emulator.display();
+    match display::write_png(&path, display, palette, options.screenshot_scale) {
+        Ok(()) => eprintln!("Saved screenshot to {}", path.display()),
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+fn toggle_video(session: &mut Session, palette: &display::Palette, options: &Options) {
+    if session.is_recording_video() {
+        session.stop_video();
+        eprintln!("Stopped recording video");
+        return;
+    }
+
+    let path = match &options.video {
+        Some(path) => path.clone(),
+        None => format!("{}-{}.gif", session.rom.name(), session.emulator.ticks).into(),
+    };
+    match capture::VideoRecorder::create(&path, palette.clone(), options.video_scale) {
+        Ok(video) => {
+            eprintln!("Recording video to {}", path.display());
+            session.start_video(video);
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+}
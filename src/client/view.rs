@@ -0,0 +1,208 @@
+//! Placement of the display and keypad in a resizable window.
+
+use macroquad::prelude::*;
+
+use crate::{chip8, config::Config};
+
+/// The size of the window contents in CHIP-8 pixels: the display, with the keypad to its right
+const CONTENT_WIDTH: f32 = chip8::video::WIDTH as f32 + KEYPAD_WIDTH;
+const CONTENT_HEIGHT: f32 = chip8::video::HEIGHT as f32;
+const KEYPAD_WIDTH: f32 = chip8::video::HEIGHT as f32;
+
+const DEFAULT_SCALE: u32 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scaling {
+    /// Scale by the largest whole number that fits, so that every CHIP-8 pixel is the same size
+    Integer,
+    /// Scale to fill the window while keeping the aspect ratio
+    Fit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overlay {
+    None,
+    Grid,
+    Scanlines,
+}
+
+pub struct View {
+    /// Size of a CHIP-8 pixel when the window was opened
+    pub scale: u32,
+    pub scaling: Scaling,
+    pub overlay: Overlay,
+    pub fullscreen: bool,
+}
+
+/// Where the display and keypad are drawn in the window
+#[derive(Debug, PartialEq)]
+pub struct Layout {
+    pub x: f32,
+    pub y: f32,
+    /// Size of a CHIP-8 pixel
+    pub scale: f32,
+}
+
+impl Layout {
+    pub fn display_size(&self) -> Vec2 {
+        vec2(chip8::video::WIDTH as f32, chip8::video::HEIGHT as f32) * self.scale
+    }
+
+    pub fn keypad_x(&self) -> f32 {
+        self.x + self.display_size().x
+    }
+
+    pub fn keypad_cell(&self) -> f32 {
+        KEYPAD_WIDTH * self.scale / 4.0
+    }
+}
+
+impl View {
+    /// Read the `scale`, `scaling`, `overlay` and `fullscreen` settings
+    pub fn from_config(config: &Config) -> View {
+        let mut view = View {
+            scale: DEFAULT_SCALE,
+            scaling: Scaling::Integer,
+            overlay: Overlay::None,
+            fullscreen: false,
+        };
+        let Some(settings) = config.section("")
+        else {
+            return view;
+        };
+
+        let warn =
+            |key: &str, value: &str| eprintln!("Ignoring invalid setting: {} = {}", key, value);
+        for (key, value) in settings.entries() {
+            match (key, value) {
+                ("scale", value) => match value.parse() {
+                    Ok(scale) if scale > 0 => view.scale = scale,
+                    _ => warn(key, value),
+                },
+                ("scaling", "integer") => view.scaling = Scaling::Integer,
+                ("scaling", "fit") => view.scaling = Scaling::Fit,
+                ("overlay", "none") => view.overlay = Overlay::None,
+                ("overlay", "grid") => view.overlay = Overlay::Grid,
+                ("overlay", "scanlines") => view.overlay = Overlay::Scanlines,
+                ("fullscreen", value) => match value.parse() {
+                    Ok(fullscreen) => view.fullscreen = fullscreen,
+                    Err(_) => warn(key, value),
+                },
+                ("scaling" | "overlay", _) => warn(key, value),
+                _ => {}
+            }
+        }
+
+        view
+    }
+
+    /// The settings to write back to the user's configuration
+    pub fn settings(&self) -> [(&'static str, String); 4] {
+        [
+            ("scale", self.scale.to_string()),
+            ("scaling", format!("{:?}", self.scaling).to_lowercase()),
+            ("overlay", format!("{:?}", self.overlay).to_lowercase()),
+            ("fullscreen", self.fullscreen.to_string()),
+        ]
+    }
+
+    /// The size of the window when it is opened
+    pub fn window_size(&self) -> (i32, i32) {
+        let scale = self.scale as f32;
+        ((CONTENT_WIDTH * scale) as i32, (CONTENT_HEIGHT * scale) as i32)
+    }
+
+    pub fn layout(&self, width: f32, height: f32) -> Layout {
+        let fit = (width / CONTENT_WIDTH).min(height / CONTENT_HEIGHT);
+        let scale = match self.scaling {
+            Scaling::Integer => fit.floor().max(1.0),
+            Scaling::Fit => fit,
+        };
+
+        Layout {
+            x: ((width - CONTENT_WIDTH * scale) / 2.0).floor(),
+            y: ((height - CONTENT_HEIGHT * scale) / 2.0).floor(),
+            scale,
+        }
+    }
+
+    /// Remember the current window size, so that the next session starts with the same size
+    pub fn resized(&mut self, layout: &Layout) {
+        if !self.fullscreen {
+            self.scale = (layout.scale.round() as u32).max(1);
+        }
+    }
+
+    pub fn next_overlay(&mut self) {
+        self.overlay = match self.overlay {
+            Overlay::None => Overlay::Grid,
+            Overlay::Grid => Overlay::Scanlines,
+            Overlay::Scanlines => Overlay::None,
+        };
+    }
+
+    pub fn next_scaling(&mut self) {
+        self.scaling = match self.scaling {
+            Scaling::Integer => Scaling::Fit,
+            Scaling::Fit => Scaling::Integer,
+        };
+    }
+
+    pub fn draw_overlay(&self, layout: &Layout) {
+        // Lines thinner than a few pixels just darken the whole display
+        if layout.scale < 3.0 {
+            return;
+        }
+
+        let size = layout.display_size();
+        let shade = Color::new(0.0, 0.0, 0.0, 0.35);
+        match self.overlay {
+            Overlay::None => {}
+            Overlay::Grid => {
+                for col in 1..chip8::video::WIDTH {
+                    let x = layout.x + col as f32 * layout.scale;
+                    draw_line(x, layout.y, x, layout.y + size.y, 1.0, shade);
+                }
+                for row in 1..chip8::video::HEIGHT {
+                    let y = layout.y + row as f32 * layout.scale;
+                    draw_line(layout.x, y, layout.x + size.x, y, 1.0, shade);
+                }
+            }
+            Overlay::Scanlines => {
+                let height = (layout.scale / 3.0).floor();
+                for row in 1..=chip8::video::HEIGHT {
+                    let y = layout.y + row as f32 * layout.scale - height;
+                    draw_rectangle(layout.x, y, size.x, height, shade);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_layout() {
+    let mut view = View::from_config(&Config::default());
+    assert_eq!(view.window_size(), (768, 256));
+    assert_eq!(view.layout(768.0, 256.0), Layout { x: 0.0, y: 0.0, scale: 8.0 });
+
+    // Letterboxed with the largest integer scale that fits
+    assert_eq!(view.layout(1000.0, 300.0), Layout { x: 68.0, y: 6.0, scale: 9.0 });
+
+    view.next_scaling();
+    assert_eq!(view.layout(960.0, 600.0), Layout { x: 0.0, y: 140.0, scale: 10.0 });
+    assert_eq!(view.layout(480.0, 100.0).scale, 3.125);
+}
+
+#[test]
+fn test_view_settings() {
+    let config = Config::parse("scale = 4\nscaling = fit\noverlay = scanlines\n").unwrap();
+    let view = View::from_config(&config);
+    assert_eq!(view.scale, 4);
+    assert_eq!(view.scaling, Scaling::Fit);
+    assert_eq!(view.overlay, Overlay::Scanlines);
+    assert!(!view.fullscreen);
+
+    let text: String = view.settings().iter().map(|(k, v)| format!("{} = {}\n", k, v)).collect();
+    let reloaded = View::from_config(&Config::parse(&text).unwrap());
+    assert_eq!(reloaded.settings(), view.settings());
+}
@@ -0,0 +1,241 @@
+//! A minimal INI style configuration format:
+//!
+//! ```text
+//! # Comment
+//! key = value
+//!
+//! [section]
+//! key = value
+//! ```
+//!
+//! Entries that appear before the first section header belong to the unnamed section `""`.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, Default)]
+pub struct Section {
+    pub name: String,
+    entries: Vec<(String, String)>,
+}
+
+impl Section {
+    pub fn new(name: &str) -> Section {
+        Section { name: name.into(), entries: vec![] }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value.into(),
+            None => self.entries.push((key.into(), value.into())),
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Copy all entries from `other` into this section, overwriting existing keys
+    pub fn merge(&mut self, other: &Section) {
+        for (key, value) in other.entries() {
+            self.set(key, value);
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Config {
+    sections: Vec<Section>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut current = String::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[') {
+                match name.strip_suffix(']') {
+                    Some(name) => current = name.trim().into(),
+                    None => return Err(format!("line {}: unterminated section header", i + 1)),
+                }
+                config.section_mut(&current);
+                continue;
+            }
+
+            match line.split_once('=') {
+                Some((key, value)) => config.section_mut(&current).set(key.trim(), value.trim()),
+                None => return Err(format!("line {}: expected `key = value`", i + 1)),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Load a configuration file, a missing file is treated as an empty configuration
+    pub fn load(path: &Path) -> Result<Config, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    pub fn section_mut(&mut self, name: &str) -> &mut Section {
+        match self.sections.iter().position(|s| s.name == name) {
+            Some(i) => &mut self.sections[i],
+            None => {
+                self.sections.push(Section::new(name));
+                self.sections.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Copy all sections from `other` into this configuration, overwriting existing keys
+    pub fn merge(&mut self, other: &Config) {
+        for section in &other.sections {
+            self.section_mut(&section.name).merge(section);
+        }
+    }
+}
+
+/// Load the user's settings (`config.ini` in the configuration directory). Errors are reported and
+/// treated as an empty configuration.
+pub fn load_user() -> Config {
+    let Some(dir) = dir()
+    else {
+        return Config::default();
+    };
+
+    Config::load(&dir.join("config.ini")).unwrap_or_else(|e| {
+        eprintln!("Ignoring user configuration: {}", e);
+        Config::default()
+    })
+}
+
+/// Write settings to the unnamed section of the user's `config.ini`. The rest of the file,
+/// including comments, is left untouched.
+pub fn save_user(settings: &[(&str, String)]) -> Result<(), String> {
+    let dir = dir().ok_or("No configuration directory")?;
+    let path = dir.join("config.ini");
+
+    let mut text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    for (key, value) in settings {
+        text = set_top_level(&text, key, value);
+    }
+
+    fs::create_dir_all(&dir)
+        .and_then(|_| fs::write(&path, text))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Replace the value of `key` in the unnamed section of a configuration file, adding the entry to
+/// the end of the unnamed section if it doesn't exist yet
+fn set_top_level(text: &str, key: &str, value: &str) -> String {
+    let mut lines: Vec<String> = text.lines().map(String::from).collect();
+    let entry = format!("{} = {}", key, value);
+
+    let section_start =
+        lines.iter().position(|l| l.trim_start().starts_with('[')).unwrap_or(lines.len());
+    let existing = lines[..section_start]
+        .iter()
+        .position(|line| line.split_once('=').is_some_and(|(k, _)| k.trim() == key));
+
+    match existing {
+        Some(i) => lines[i] = entry,
+        None => {
+            // Keep the blank line that usually separates the first section from the entries above
+            let mut at = section_start;
+            while at > 0 && lines[at - 1].trim().is_empty() {
+                at -= 1;
+            }
+            lines.insert(at, entry);
+        }
+    }
+
+    let mut out = lines.join("\n");
+    out.push('\n');
+    out
+}
+
+/// The directory user configuration files are read from
+pub fn dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+        return Some(PathBuf::from(dir).join("chip8_emu"));
+    }
+    if let Some(dir) = std::env::var_os("APPDATA") {
+        return Some(PathBuf::from(dir).join("chip8_emu"));
+    }
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config").join("chip8_emu"))
+}
+
+/// Parse a single CHIP-8 key (`0`-`F`)
+pub fn parse_key(value: &str) -> Option<u8> {
+    u8::from_str_radix(value, 16).ok().filter(|&key| key < 0x10)
+}
+
+#[test]
+fn test_parse() {
+    let config = Config::parse(
+        "top = 1\n\
+         # comment\n\
+         [pong]\n\
+         title = Pong [1990]\n\
+         pad0.up=1\n\
+         [pong]\n\
+         pad0.up = 2\n",
+    )
+    .unwrap();
+
+    assert_eq!(config.section("").unwrap().get("top"), Some("1"));
+    let pong = config.section("pong").unwrap();
+    assert_eq!(pong.get("title"), Some("Pong [1990]"));
+    assert_eq!(pong.get("pad0.up"), Some("2"));
+    assert_eq!(pong.get("missing"), None);
+
+    assert!(Config::parse("[broken").is_err());
+    assert!(Config::parse("no value").is_err());
+}
+
+#[test]
+fn test_parse_key() {
+    assert_eq!(parse_key("0"), Some(0x0));
+    assert_eq!(parse_key("c"), Some(0xC));
+    assert_eq!(parse_key("F"), Some(0xF));
+    assert_eq!(parse_key("10"), None);
+    assert_eq!(parse_key("G"), None);
+}
+
+#[test]
+fn test_set_top_level() {
+    assert_eq!(set_top_level("", "scale", "4"), "scale = 4\n");
+
+    let text = "# Settings\nscale = 8\npalette = amber\n\n[pong]\nscale = 2\n";
+    assert_eq!(
+        set_top_level(text, "scale", "4"),
+        "# Settings\nscale = 4\npalette = amber\n\n[pong]\nscale = 2\n"
+    );
+    assert_eq!(
+        set_top_level(text, "overlay", "grid"),
+        "# Settings\nscale = 8\npalette = amber\noverlay = grid\n\n[pong]\nscale = 2\n"
+    );
+}
@@ -0,0 +1,236 @@
+//! Records which instructions were executed and which way each skip instruction went, and writes
+//! coverage reports against the disassembly of the ROM.
+//!
+//! The lcov report uses the address of each instruction as its line number, with the ROM as the
+//! source file. Each skip instruction has two branches: `0` when the next instruction was skipped,
+//! and `1` when it wasn't. The HTML report is the annotated disassembly.
+
+use std::{collections::BTreeMap, fmt::Write as _, fs, path::PathBuf};
+
+use crate::{
+    chip8::{
+        self,
+        cpu::{Operation, OPCODE_SIZE},
+        mem::RAM_START,
+    },
+    rom::Rom,
+};
+
+/// Executions of an instruction, and for skip instructions how often the skip was taken
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Hits {
+    pub opcode: u16,
+    pub count: u64,
+    pub skipped: u64,
+}
+
+pub struct Coverage {
+    rom: Rom,
+    pub hits: BTreeMap<u16, Hits>,
+    report: PathBuf,
+}
+
+/// A line of the disassembly
+struct Line {
+    addr: u16,
+    opcode: u16,
+    op: Option<Operation>,
+    hits: Option<Hits>,
+}
+
+impl Coverage {
+    /// Record coverage of `rom`, writing the report to `report` when finished: HTML if the path
+    /// ends in `.html`, lcov otherwise
+    pub fn new(rom: Rom, report: PathBuf) -> Coverage {
+        Coverage { rom, hits: BTreeMap::new(), report }
+    }
+
+    /// Count an executed instruction, given the program counter before and after it
+    pub fn record(&mut self, opcode: u16, pc: u16, next: u16) {
+        let hits = self.hits.entry(pc).or_default();
+        hits.opcode = opcode;
+        hits.count += 1;
+        if is_skip(opcode) && next == pc + 2 * OPCODE_SIZE {
+            hits.skipped += 1;
+        }
+    }
+
+    /// The disassembly of the ROM, each even address decoded as an instruction, along with any
+    /// instructions executed at odd addresses
+    fn lines(&self) -> Vec<Line> {
+        let end = RAM_START + self.rom.data.len() as u16;
+        let mut lines: BTreeMap<u16, Line> = (RAM_START..end)
+            .step_by(2)
+            .map(|addr| {
+                let offset = (addr - RAM_START) as usize;
+                let hi = self.rom.data[offset];
+                let lo = self.rom.data.get(offset + 1).copied().unwrap_or(0);
+                let opcode = (hi as u16) << 8 | lo as u16;
+                (addr, Line { addr, opcode, op: chip8::try_decode(opcode).ok(), hits: None })
+            })
+            .collect();
+
+        for (&addr, hits) in &self.hits {
+            let line = lines.entry(addr).or_insert(Line { addr, opcode: 0, op: None, hits: None });
+            // Self-modifying code may have executed something other than what the ROM contains
+            line.opcode = hits.opcode;
+            line.op = chip8::try_decode(hits.opcode).ok();
+            line.hits = Some(*hits);
+        }
+        lines.into_values().collect()
+    }
+
+    /// Instructions executed and total, branches covered and total
+    fn summary(lines: &[Line]) -> (usize, usize, usize, usize) {
+        let code: Vec<_> = lines.iter().filter(|line| line.op.is_some()).collect();
+        let executed = code.iter().filter(|line| line.hits.is_some()).count();
+        let skips: Vec<_> = code.iter().filter(|line| is_skip(line.opcode)).collect();
+        let covered = skips
+            .iter()
+            .filter_map(|line| line.hits)
+            .map(|hits| usize::from(hits.skipped > 0) + usize::from(hits.skipped < hits.count))
+            .sum();
+        (executed, code.len(), covered, skips.len() * 2)
+    }
+
+    pub fn lcov(&self) -> String {
+        let lines = self.lines();
+        let mut out = format!("TN:\nSF:{}\n", self.rom.path.display());
+        for line in lines.iter().filter(|line| line.op.is_some()) {
+            let hits = line.hits.unwrap_or_default();
+            if is_skip(line.opcode) {
+                let branches = match line.hits {
+                    Some(hits) => {
+                        [hits.skipped.to_string(), (hits.count - hits.skipped).to_string()]
+                    }
+                    None => ["-".into(), "-".into()],
+                };
+                for (branch, taken) in branches.iter().enumerate() {
+                    let _ = writeln!(out, "BRDA:{},0,{},{}", line.addr, branch, taken);
+                }
+            }
+            let _ = writeln!(out, "DA:{},{}", line.addr, hits.count);
+        }
+
+        let (executed, total, covered, branches) = Coverage::summary(&lines);
+        let _ = write!(
+            out,
+            "BRF:{}\nBRH:{}\nLF:{}\nLH:{}\nend_of_record\n",
+            branches, covered, total, executed
+        );
+        out
+    }
+
+    pub fn html(&self) -> String {
+        let lines = self.lines();
+        let (executed, total, covered, branches) = Coverage::summary(&lines);
+        let percent = |n: usize, total: usize| n as f64 * 100.0 / total.max(1) as f64;
+
+        let mut out = String::from(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><style>\n\
+             body { font-family: monospace; }\n\
+             td { padding: 0 1em; }\n\
+             .hit { background: #cfc; }\n\
+             .miss { background: #fcc; }\n\
+             .partial { background: #ffc; }\n\
+             </style></head><body>\n",
+        );
+        let _ = writeln!(out, "<h1>{}</h1>", escape(&self.rom.path.display().to_string()));
+        let _ = writeln!(
+            out,
+            "<p>Instructions: {}/{} ({:.1}%)<br>Branches: {}/{} ({:.1}%)</p>",
+            executed,
+            total,
+            percent(executed, total),
+            covered,
+            branches,
+            percent(covered, branches)
+        );
+        out.push_str("<table>\n<tr><th>Address</th><th>Opcode</th><th>Instruction</th>");
+        out.push_str("<th>Executions</th><th>Skipped</th></tr>\n");
+
+        for line in &lines {
+            let (class, instruction) = match (&line.op, line.hits) {
+                (None, _) => ("", String::from("data")),
+                (Some(op), None) => ("miss", op.to_string()),
+                (Some(op), Some(hits)) if is_skip(line.opcode) => {
+                    let both = hits.skipped > 0 && hits.skipped < hits.count;
+                    let class = match both {
+                        true => "hit",
+                        false => "partial",
+                    };
+                    (class, op.to_string())
+                }
+                (Some(op), Some(_)) => ("hit", op.to_string()),
+            };
+            let count = line.hits.map(|hits| hits.count.to_string()).unwrap_or_default();
+            let skipped = match line.hits {
+                Some(hits) if is_skip(line.opcode) => format!("{}/{}", hits.skipped, hits.count),
+                _ => String::new(),
+            };
+            let cells =
+                format!("<td>{}</td><td>{}</td><td>{}</td>", escape(&instruction), count, skipped);
+            let _ = writeln!(
+                out,
+                "<tr class=\"{}\"><td>{:03x}</td><td>{:04x}</td>{}</tr>",
+                class, line.addr, line.opcode, cells
+            );
+        }
+        out.push_str("</table>\n</body></html>\n");
+        out
+    }
+
+    pub fn finish(self) {
+        let html = self.report.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("html"));
+        let report = if html { self.html() } else { self.lcov() };
+        match fs::write(&self.report, report) {
+            Ok(()) => eprintln!("Saved coverage to {}", self.report.display()),
+            Err(e) => eprintln!("Failed to write coverage {}: {}", self.report.display(), e),
+        }
+    }
+}
+
+fn is_skip(opcode: u16) -> bool {
+    use Operation::*;
+    matches!(
+        chip8::try_decode(opcode),
+        Ok(SkipIfEq(..) | SkipIfNotEq(..) | SkipIfKeyPressed(_) | SkipIfKeyNotPressed(_))
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[test]
+fn test_coverage() {
+    // Count V0 up to 2, skipping the jump back once it reaches 2, then loop forever. The last
+    // instruction is never executed.
+    let data = vec![0x70, 0x01, 0x30, 0x02, 0x12, 0x00, 0x12, 0x06, 0x00, 0xE0, 0xFF];
+    let rom = Rom { path: "test<1>.ch8".into(), ..crate::rom::test_rom(data.clone()) };
+    let mut emulator = chip8::Emulator::new(0);
+    emulator.load(&data);
+    let mut coverage = Coverage::new(rom, "coverage.info".into());
+    for _ in 0..8 {
+        let pc = emulator.cpu.registers().pc;
+        let opcode = emulator.mem.read_word(pc).unwrap();
+        emulator.frame().unwrap();
+        coverage.record(opcode, pc, emulator.cpu.registers().pc);
+    }
+
+    assert_eq!(coverage.hits[&0x202], Hits { opcode: 0x3002, count: 2, skipped: 1 });
+    assert_eq!(
+        coverage.lcov(),
+        "TN:\nSF:test<1>.ch8\nDA:512,2\nBRDA:514,0,0,1\nBRDA:514,0,1,1\nDA:514,2\nDA:516,1\n\
+         DA:518,3\nDA:520,0\nBRF:2\nBRH:2\nLF:5\nLH:4\nend_of_record\n"
+    );
+
+    let html = coverage.html();
+    assert!(html.contains("<h1>test&lt;1&gt;.ch8</h1>"));
+    assert!(html.contains("Instructions: 4/5 (80.0%)<br>Branches: 2/2 (100.0%)"));
+    assert!(html.contains("<tr class=\"miss\"><td>208</td><td>00e0</td><td>ClearScreen</td>"));
+    assert!(
+        html.contains("<td>202</td><td>3002</td><td>SkipIfEq V0, 0x02</td><td>2</td><td>1/2</td>")
+    );
+    assert!(html.contains("<tr class=\"\"><td>20a</td><td>ff00</td><td>data</td>"));
+}
@@ -0,0 +1,181 @@
+//! Conversion of the emulator's display into images, shared by all frontends.
+
+use std::path::Path;
+
+use crate::chip8::video::{HEIGHT, WIDTH};
+
+pub type Rgba = [u8; 4];
+
+/// Colors used to draw the display, indexed by the value of the pixel's bitplanes: unset pixels,
+/// pixels set in the first plane, pixels set in the second plane and pixels set in both planes.
+/// Only the first two colors are used while the display has a single plane.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: [Rgba; 4],
+}
+
+/// The palettes that can be selected by name, in the order they are cycled through
+pub static THEMES: [(&str, Palette); 5] = [
+    ("classic", Palette::from_rgb([0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555])),
+    ("green", Palette::from_rgb([0x0C140C, 0x33FF33, 0x1A801A, 0xB3FFB3])),
+    ("amber", Palette::from_rgb([0x140C00, 0xFFB000, 0x805800, 0xFFD980])),
+    ("octo", Palette::from_rgb([0x996600, 0xFFCC00, 0xFF6600, 0x662200])),
+    ("lcd", Palette::from_rgb([0x9BBC0F, 0x0F380F, 0x306230, 0x8BAC0F])),
+];
+
+impl Palette {
+    const fn from_rgb(colors: [u32; 4]) -> Palette {
+        const fn rgba(rgb: u32) -> Rgba {
+            [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 0xFF]
+        }
+        Palette { colors: [rgba(colors[0]), rgba(colors[1]), rgba(colors[2]), rgba(colors[3])] }
+    }
+
+    pub fn background(&self) -> Rgba {
+        self.colors[0]
+    }
+
+    /// Parse either the name of a theme, or a list of 2 to 4 colors in `#rrggbb` format separated
+    /// by spaces or commas. If only 2 or 3 colors are given, the remaining planes use the last
+    /// color.
+    pub fn parse(value: &str) -> Result<Palette, String> {
+        if let Some((_, palette)) = THEMES.iter().find(|(name, _)| name.eq_ignore_ascii_case(value))
+        {
+            return Ok(palette.clone());
+        }
+
+        let mut colors = vec![];
+        for color in value.split(|c: char| c == ',' || c.is_whitespace()).filter(|c| !c.is_empty())
+        {
+            let hex = color.strip_prefix('#').unwrap_or(color);
+            match u32::from_str_radix(hex, 16) {
+                Ok(rgb) if hex.len() == 6 => colors.push(rgb),
+                _ => return Err(format!("Invalid color `{}`", color)),
+            }
+        }
+        if !(2..=4).contains(&colors.len()) {
+            return Err(format!("Unknown palette `{}`", value));
+        }
+        while colors.len() < 4 {
+            colors.push(*colors.last().unwrap());
+        }
+
+        Ok(Palette::from_rgb([colors[0], colors[1], colors[2], colors[3]]))
+    }
+
+    /// The theme after this one, for cycling through the themes
+    pub fn next_theme(&self) -> Palette {
+        let next = match THEMES.iter().position(|(_, palette)| palette == self) {
+            Some(i) => (i + 1) % THEMES.len(),
+            None => 0,
+        };
+        THEMES[next].1.clone()
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        THEMES.iter().find(|(_, palette)| palette == self).map(|(name, _)| *name)
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        THEMES[0].1.clone()
+    }
+}
+
+/// Convert the 1 bit per pixel display data into one byte per pixel (the pixel's index in the
+/// palette), with each pixel scaled to a `scale` x `scale` square. The result is stored row by row,
+/// starting from the top left.
+pub fn to_indexed(display: &[u8], scale: u32) -> Vec<u8> {
+    let scale = scale as usize;
+    let width = WIDTH as usize * scale;
+
+    let mut out = Vec::with_capacity(width * HEIGHT as usize * scale);
+    for row in display.chunks(WIDTH as usize / 8) {
+        let start = out.len();
+        for &block in row {
+            for bit in (0..8).rev() {
+                let index = (block >> bit) & 0x1;
+                out.extend(std::iter::repeat_n(index, scale));
+            }
+        }
+        for _ in 1..scale {
+            out.extend_from_within(start..start + width);
+        }
+    }
+    out
+}
+
+/// Convert the display data into RGBA pixels, laid out the same way as `to_indexed`
+pub fn to_rgba(display: &[u8], palette: &Palette, scale: u32) -> Vec<u8> {
+    to_indexed(display, scale)
+        .into_iter()
+        .flat_map(|index| palette.colors[index as usize])
+        .collect()
+}
+
+/// The size in pixels of an image produced by `to_rgba`
+pub fn image_size(scale: u32) -> (u32, u32) {
+    (WIDTH as u32 * scale, HEIGHT as u32 * scale)
+}
+
+pub fn write_png(path: &Path, display: &[u8], palette: &Palette, scale: u32) -> Result<(), String> {
+    let (width, height) = image_size(scale);
+    image::save_buffer(
+        path,
+        &to_rgba(display, palette, scale),
+        width,
+        height,
+        image::ColorType::Rgba8,
+    )
+    .map_err(|e| format!("Failed to write screenshot {}: {}", path.display(), e))
+}
+
+#[test]
+fn test_to_rgba() {
+    let palette = Palette { colors: [[0, 0, 0, 0], [1, 2, 3, 4], [5, 5, 5, 5], [6, 6, 6, 6]] };
+    let mut display = [0; WIDTH as usize / 8 * HEIGHT as usize];
+    display[0] = 0b1000_0001;
+
+    let image = to_rgba(&display, &palette, 1);
+    assert_eq!(image.len(), WIDTH as usize * HEIGHT as usize * 4);
+    assert_eq!(image[0..4], [1, 2, 3, 4]);
+    assert_eq!(image[4..8], [0, 0, 0, 0]);
+    assert_eq!(image[7 * 4..8 * 4], [1, 2, 3, 4]);
+
+    let image = to_rgba(&display, &palette, 2);
+    let (width, height) = image_size(2);
+    assert_eq!(image.len(), (width * height * 4) as usize);
+    let pixel = |x: usize, y: usize| &image[(y * width as usize + x) * 4..][..4];
+    assert_eq!(pixel(0, 0), [1, 2, 3, 4]);
+    assert_eq!(pixel(1, 1), [1, 2, 3, 4]);
+    assert_eq!(pixel(2, 1), [0, 0, 0, 0]);
+    assert_eq!(pixel(15, 1), [1, 2, 3, 4]);
+    assert_eq!(pixel(0, 2), [0, 0, 0, 0]);
+}
+
+#[test]
+fn test_parse_palette() {
+    assert_eq!(Palette::parse("Amber").unwrap().name(), Some("amber"));
+    assert_eq!(Palette::parse("#000000, #FFFFFF, #AAAAAA, #555555").unwrap(), Palette::default());
+
+    let palette = Palette::parse("102030 #405060").unwrap();
+    assert_eq!(palette.colors[0], [0x10, 0x20, 0x30, 0xFF]);
+    assert_eq!(palette.colors[1], [0x40, 0x50, 0x60, 0xFF]);
+    assert_eq!(palette.colors[3], [0x40, 0x50, 0x60, 0xFF]);
+    assert_eq!(palette.name(), None);
+
+    assert!(Palette::parse("unknown").is_err());
+    assert!(Palette::parse("#000000").is_err());
+    assert!(Palette::parse("#000000 #FFF").is_err());
+    assert!(Palette::parse("#000000 #GGGGGG").is_err());
+}
+
+#[test]
+fn test_next_theme() {
+    let mut palette = Palette::parse("#000001 #000002").unwrap();
+    for (name, _) in THEMES.iter().chain(&THEMES[..1]) {
+        palette = palette.next_theme();
+        assert_eq!(palette.name(), Some(*name));
+    }
+}
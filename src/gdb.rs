@@ -0,0 +1,555 @@
+//! A GDB remote serial protocol server, so that GDB (and debugger frontends built on it) can
+//! debug CHIP-8 programs: `target remote localhost:<PORT>`.
+//!
+//! The registers are V0-VF, I, PC and the delay and sound timers, described to the debugger in a
+//! target description. Memory is the CHIP-8 address space, `0x000`-`0xfff`. Software and hardware
+//! breakpoints, single stepping, continuing and interrupting are supported, as well as stepping and
+//! continuing backwards through the execution history.
+
+use std::{
+    collections::BTreeSet,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+};
+
+use crate::{
+    chip8::{self, cpu::Registers},
+    session::Session,
+};
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const FEATURES: &str =
+    "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;ReverseStep+;ReverseContinue+";
+
+/// The size of the registers in the order of the target description, in bytes
+const REGISTER_SIZES: [usize; 20] = [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 1, 1];
+
+pub struct Stub {
+    listener: TcpListener,
+    connection: Option<Connection>,
+    breakpoints: BTreeSet<u16>,
+    /// The program only runs while the debugger lets it continue
+    running: bool,
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// Received bytes that don't form a complete packet yet
+    input: Vec<u8>,
+    /// Cleared once the debugger switches to no acknowledgement mode
+    ack: bool,
+}
+
+impl Stub {
+    /// Listen for a debugger on `port` of the loopback interface. The program is stopped until a
+    /// debugger connects and continues it.
+    pub fn listen(port: u16) -> Result<Stub, String> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+            .map_err(|e| format!("Failed to listen for GDB on port {}: {}", port, e))?;
+        Ok(Stub { listener, connection: None, breakpoints: BTreeSet::new(), running: false })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Accept a new debugger and handle the packets received since the last call
+    pub fn poll(&mut self, session: &mut Session) {
+        if let Ok((stream, addr)) = self.listener.accept() {
+            if self.connection.is_some() {
+                eprintln!("Rejecting GDB connection from {}, already connected", addr);
+            }
+            else if let Err(e) = stream.set_nonblocking(true) {
+                eprintln!("Failed to accept GDB connection: {}", e);
+            }
+            else {
+                eprintln!("GDB connected from {}", addr);
+                self.connection = Some(Connection { stream, input: vec![], ack: true });
+                self.running = false;
+            }
+        }
+
+        let Some(connection) = &mut self.connection
+        else {
+            return;
+        };
+        if let Err(e) = connection.receive() {
+            match e.kind() {
+                io::ErrorKind::UnexpectedEof => eprintln!("GDB disconnected"),
+                _ => eprintln!("GDB connection failed: {}", e),
+            }
+            self.detach();
+            return;
+        }
+
+        while let Some(packet) = self.connection.as_mut().and_then(Connection::next_packet) {
+            match packet {
+                Packet::Interrupt => {
+                    if self.running {
+                        self.stop(SIGINT);
+                    }
+                }
+                Packet::Invalid => {}
+                Packet::Command(command) => {
+                    if let Some(reply) = self.handle(&command, session) {
+                        self.send(&reply);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Called before each instruction is executed. Returns true if the program stopped at a
+    /// breakpoint.
+    pub fn breakpoint(&mut self, pc: u16) -> bool {
+        let hit = self.running && self.breakpoints.contains(&pc);
+        if hit {
+            self.stop(SIGTRAP);
+        }
+        hit
+    }
+
+    /// Report a fault that stopped the program to the debugger
+    pub fn fault(&mut self, fault: chip8::Error) {
+        if self.running {
+            self.stop(signal(fault));
+        }
+    }
+
+    fn stop(&mut self, signal: u8) {
+        self.running = false;
+        self.send(&format!("S{:02x}", signal));
+    }
+
+    fn detach(&mut self) {
+        self.connection = None;
+        self.breakpoints.clear();
+        self.running = true;
+    }
+
+    /// Handle a command, returning the reply. Commands that resume the program reply once it
+    /// stops again.
+    fn handle(&mut self, command: &str, session: &mut Session) -> Option<String> {
+        let emulator = &mut session.emulator;
+        // Most commands are a single letter followed by their arguments
+        let (name, args) = match command.as_bytes().first() {
+            Some(b'q' | b'Q' | b'v') => command.split_once([':', ';']).unwrap_or((command, "")),
+            Some(b'b') if command.len() >= 2 => command.split_at(2),
+            Some(_) => command.split_at(1),
+            None => ("", ""),
+        };
+
+        let reply = match name {
+            "?" => stop_reply(session),
+            "g" => encode(&registers_to_bytes(&emulator.cpu.registers())),
+            "G" => match decode(args) {
+                Some(bytes) if bytes.len() == REGISTER_SIZES.iter().sum::<usize>() => {
+                    emulator.cpu.set_registers(&bytes_to_registers(&bytes));
+                    "OK".into()
+                }
+                _ => "E01".into(),
+            },
+            "p" => {
+                let n = usize::from_str_radix(args, 16).ok();
+                match n.and_then(|n| register(&emulator.cpu.registers(), n)) {
+                    Some(bytes) => encode(&bytes),
+                    None => "E01".into(),
+                }
+            }
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(n, value)| {
+                    Some((usize::from_str_radix(n, 16).ok()?, decode(value)?))
+                });
+                let mut registers = emulator.cpu.registers();
+                match parsed {
+                    Some((n, value)) if set_register(&mut registers, n, &value) => {
+                        emulator.cpu.set_registers(&registers);
+                        "OK".into()
+                    }
+                    _ => "E01".into(),
+                }
+            }
+            "m" => match parse_range(args) {
+                Some((addr, len)) => {
+                    // Reads stop at the first address that can't be read
+                    let bytes: Vec<u8> = (addr..addr.saturating_add(len))
+                        .map_while(|addr| emulator.mem.read_byte(addr).ok())
+                        .collect();
+                    match bytes.is_empty() && len > 0 {
+                        true => "E14".into(),
+                        false => encode(&bytes),
+                    }
+                }
+                None => "E01".into(),
+            },
+            "M" => {
+                let parsed = args
+                    .split_once(':')
+                    .and_then(|(range, data)| Some((parse_range(range)?, decode(data)?)));
+                match parsed {
+                    Some(((addr, len), data)) if data.len() == len as usize => {
+                        // Writes past the end of the address space fail like any other address
+                        // that can't be written
+                        let written = addr.checked_add(len).ok_or(()).and_then(|end| {
+                            (addr..end)
+                                .zip(data)
+                                .try_for_each(|(addr, byte)| emulator.mem.write_byte(addr, byte))
+                                .map_err(|_| ())
+                        });
+                        match written {
+                            Ok(()) => "OK".into(),
+                            Err(()) => "E14".into(),
+                        }
+                    }
+                    _ => "E01".into(),
+                }
+            }
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                let kind = fields.next();
+                let addr = fields.next().and_then(|addr| u16::from_str_radix(addr, 16).ok());
+                match (kind, addr) {
+                    // Software and hardware breakpoints are the same, as memory isn't modified
+                    (Some("0" | "1"), Some(addr)) => {
+                        match name {
+                            "Z" => self.breakpoints.insert(addr),
+                            _ => self.breakpoints.remove(&addr),
+                        };
+                        "OK".into()
+                    }
+                    (Some("0" | "1"), None) => "E01".into(),
+                    _ => String::new(),
+                }
+            }
+            "c" | "s" => {
+                if let Ok(addr) = u16::from_str_radix(args, 16) {
+                    let mut registers = emulator.cpu.registers();
+                    registers.pc = addr;
+                    emulator.cpu.set_registers(&registers);
+                }
+                // The instruction at the current address is executed even if it has a breakpoint,
+                // otherwise continuing from a breakpoint would stop straight away
+                session.step();
+                if name == "c" && session.fault().is_none() {
+                    self.running = true;
+                    return None;
+                }
+                stop_reply(session)
+            }
+            "bs" => match session.step_back() {
+                true => stop_reply(session),
+                false => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            },
+            "bc" => loop {
+                if !session.step_back() {
+                    break format!("T{:02x}replaylog:begin;", SIGTRAP);
+                }
+                if self.breakpoints.contains(&session.emulator.cpu.registers().pc) {
+                    break stop_reply(session);
+                }
+            },
+            "D" => {
+                eprintln!("GDB detached");
+                self.send("OK");
+                self.detach();
+                return None;
+            }
+            "k" => {
+                eprintln!("GDB killed the program, resuming");
+                self.detach();
+                return None;
+            }
+            "H" | "T" => "OK".into(),
+            "qSupported" => FEATURES.into(),
+            "QStartNoAckMode" => {
+                self.send("OK");
+                if let Some(connection) = &mut self.connection {
+                    connection.ack = false;
+                }
+                return None;
+            }
+            "qAttached" => "1".into(),
+            "qC" => "QC1".into(),
+            "qfThreadInfo" => "m1".into(),
+            "qsThreadInfo" => "l".into(),
+            "qXfer" => match args.strip_prefix("features:read:target.xml:").and_then(parse_range) {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + len as usize).min(xml.len());
+                    let more = if end < xml.len() { 'm' } else { 'l' };
+                    format!("{}{}", more, &xml[start..end])
+                }
+                None => "E00".into(),
+            },
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn send(&mut self, data: &str) {
+        let Some(connection) = &mut self.connection
+        else {
+            return;
+        };
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        if let Err(e) = connection.write(packet.as_bytes()) {
+            eprintln!("GDB connection failed: {}", e);
+            self.detach();
+        }
+    }
+}
+
+enum Packet {
+    Command(String),
+    /// Ctrl-C sent outside of a packet
+    Interrupt,
+    /// A packet with a wrong checksum, which the debugger is asked to send again
+    Invalid,
+}
+
+impl Connection {
+    /// Read everything the debugger sent so far
+    fn receive(&mut self) -> io::Result<()> {
+        let mut buffer = [0; 1024];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Take the next complete packet from the received bytes
+    fn next_packet(&mut self) -> Option<Packet> {
+        loop {
+            match *self.input.first()? {
+                b'$' => break,
+                0x03 => {
+                    self.input.remove(0);
+                    return Some(Packet::Interrupt);
+                }
+                // Acknowledgements, and anything else outside of a packet
+                _ => {
+                    self.input.remove(0);
+                }
+            }
+        }
+
+        let end = self.input.iter().position(|&b| b == b'#')?;
+        if self.input.len() < end + 3 {
+            return None;
+        }
+        let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let valid = std::str::from_utf8(&packet[end + 1..])
+            .ok()
+            .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+            .is_some_and(|sum| sum == checksum(data));
+
+        if self.ack {
+            let _ = self.write(if valid { b"+" } else { b"-" });
+        }
+        match valid {
+            true => Some(Packet::Command(String::from_utf8_lossy(data).into())),
+            false => Some(Packet::Invalid),
+        }
+    }
+
+    /// Write all of `data`, waiting for the socket if needed
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        let result = self.stream.write_all(data);
+        self.stream.set_nonblocking(true)?;
+        result
+    }
+}
+
+fn stop_reply(session: &Session) -> String {
+    format!("S{:02x}", session.fault().map_or(SIGTRAP, signal))
+}
+
+fn signal(fault: chip8::Error) -> u8 {
+    match fault {
+        chip8::Error::InvalidOpcode(_) | chip8::Error::RcaCall(_) => SIGILL,
+        _ => SIGSEGV,
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode pairs of hex digits, an odd number of digits is invalid
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Parse `addr,length` in hex
+fn parse_range(args: &str) -> Option<(u16, u16)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((u16::from_str_radix(addr, 16).ok()?, u16::from_str_radix(len, 16).ok()?))
+}
+
+/// The registers in the order of the target description, with 16 bit registers in little endian
+fn registers_to_bytes(registers: &Registers) -> Vec<u8> {
+    let mut bytes = registers.v.to_vec();
+    bytes.extend_from_slice(&registers.i.to_le_bytes());
+    bytes.extend_from_slice(&registers.pc.to_le_bytes());
+    bytes.push(registers.delay);
+    bytes.push(registers.sound);
+    bytes
+}
+
+fn bytes_to_registers(bytes: &[u8]) -> Registers {
+    let mut v = [0; 16];
+    v.copy_from_slice(&bytes[..16]);
+    Registers {
+        v,
+        i: u16::from_le_bytes([bytes[16], bytes[17]]),
+        pc: u16::from_le_bytes([bytes[18], bytes[19]]),
+        delay: bytes[20],
+        sound: bytes[21],
+    }
+}
+
+/// The bytes of register `n`
+fn register(registers: &Registers, n: usize) -> Option<Vec<u8>> {
+    let start: usize = REGISTER_SIZES.get(..n)?.iter().sum();
+    let size = *REGISTER_SIZES.get(n)?;
+    Some(registers_to_bytes(registers)[start..start + size].to_vec())
+}
+
+/// Set register `n`, returning false if there is no such register or `value` has the wrong size
+fn set_register(registers: &mut Registers, n: usize, value: &[u8]) -> bool {
+    let Some(&size) = REGISTER_SIZES.get(n)
+    else {
+        return false;
+    };
+    if value.len() != size {
+        return false;
+    }
+    let start: usize = REGISTER_SIZES[..n].iter().sum();
+    let mut bytes = registers_to_bytes(registers);
+    bytes[start..start + size].copy_from_slice(value);
+    *registers = bytes_to_registers(&bytes);
+    true
+}
+
+fn target_xml() -> String {
+    let mut registers: Vec<String> =
+        (0..16).map(|i| format!("<reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\"/>", i)).collect();
+    registers.push("<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>".into());
+    registers.push("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>".into());
+    registers.push("<reg name=\"dt\" bitsize=\"8\" type=\"uint8\"/>".into());
+    registers.push("<reg name=\"st\" bitsize=\"8\" type=\"uint8\"/>".into());
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\">\
+         <feature name=\"org.chip8.cpu\">{}</feature></target>",
+        registers.join("")
+    )
+}
+
+#[test]
+fn test_scripted_client() {
+    use std::time::Duration;
+
+    use crate::rom::test_rom;
+
+    /// Wait for the next reply, running the session meanwhile
+    fn reply(client: &mut TcpStream, session: &mut Session) -> String {
+        let mut received = vec![];
+        for _ in 0..1000 {
+            session.run(0.001);
+            let mut buffer = [0; 1024];
+            if let Ok(n) = client.read(&mut buffer) {
+                received.extend_from_slice(&buffer[..n]);
+            }
+            let text = String::from_utf8_lossy(&received).trim_start_matches('+').to_string();
+            if let Some((data, sum)) = text.strip_prefix('$').and_then(|t| t.split_once('#')) {
+                if sum.len() >= 2 {
+                    assert_eq!(u8::from_str_radix(&sum[..2], 16), Ok(checksum(data.as_bytes())));
+                    return data.into();
+                }
+            }
+        }
+        panic!("No reply");
+    }
+    fn exchange(client: &mut TcpStream, session: &mut Session, packet: &str) -> String {
+        send(client, packet);
+        reply(client, session)
+    }
+    fn send(client: &mut TcpStream, packet: &str) {
+        let packet = format!("${}#{:02x}", packet, checksum(packet.as_bytes()));
+        client.write_all(packet.as_bytes()).unwrap();
+    }
+
+    // Add 1 to V0 in a loop
+    let program = vec![0x70, 0x01, 0x12, 0x00];
+    let mut session = Session::new(test_rom(program), 0);
+    session.keep_history(100);
+    let stub = Stub::listen(0).unwrap();
+    let mut client = TcpStream::connect(stub.local_addr().unwrap()).unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+    session.serve_gdb(stub);
+
+    let v0 = |session: &Session| session.emulator.cpu.registers().v[0];
+    let pc = |session: &Session| session.emulator.cpu.registers().pc;
+
+    assert!(exchange(&mut client, &mut session, "qSupported:multiprocess+")
+        .contains("qXfer:features:read+"));
+    assert!(exchange(&mut client, &mut session, "qXfer:features:read:target.xml:0,fff")
+        .starts_with("l<?xml"));
+    assert_eq!(exchange(&mut client, &mut session, "?"), "S05");
+    assert_eq!(
+        exchange(&mut client, &mut session, "g"),
+        format!("{}000000020000", "00".repeat(16))
+    );
+    assert_eq!(exchange(&mut client, &mut session, "m200,4"), "70011200");
+    assert_eq!(exchange(&mut client, &mut session, "M300,2:abcd"), "OK");
+    assert_eq!(session.emulator.mem.ram[0x100..0x102], [0xAB, 0xCD]);
+    assert_eq!(exchange(&mut client, &mut session, "M000,1:00"), "E14");
+    assert_eq!(exchange(&mut client, &mut session, "Mffff,1:00"), "E14");
+    assert_eq!(exchange(&mut client, &mut session, "Mfffe,2:0000"), "E14");
+    assert_eq!(exchange(&mut client, &mut session, "P10=0003"), "OK");
+    assert_eq!(exchange(&mut client, &mut session, "p10"), "0003");
+
+    assert_eq!(exchange(&mut client, &mut session, "s"), "S05");
+    assert_eq!((pc(&session), v0(&session)), (0x202, 1));
+    assert_eq!(exchange(&mut client, &mut session, "Z0,202,2"), "OK");
+    assert_eq!(exchange(&mut client, &mut session, "c"), "S05");
+    assert_eq!((pc(&session), v0(&session)), (0x202, 2));
+    assert_eq!(exchange(&mut client, &mut session, "bs"), "S05");
+    assert_eq!((pc(&session), v0(&session)), (0x200, 1));
+
+    // Run until interrupted
+    assert_eq!(exchange(&mut client, &mut session, "z0,202,2"), "OK");
+    send(&mut client, "c");
+    for _ in 0..10 {
+        session.run(0.001);
+    }
+    client.write_all(&[0x03]).unwrap();
+    assert_eq!(reply(&mut client, &mut session), "S02");
+    assert!(v0(&session) > 2);
+
+    assert_eq!(exchange(&mut client, &mut session, "D"), "OK");
+    let before = v0(&session);
+    session.run(0.01);
+    assert_ne!(v0(&session), before);
+}
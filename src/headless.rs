@@ -0,0 +1,34 @@
+//! Runs the emulator without a window, for scripted runs (e.g. in CI).
+
+use crate::{
+    display::{self, Palette},
+    options::Options,
+    session::Session,
+};
+
+pub fn run(
+    mut session: Session,
+    frames: u64,
+    palette: &Palette,
+    options: &Options,
+) -> Result<(), String> {
+    for _ in 0..frames {
+        session.run_frame();
+        for message in session.take_messages() {
+            eprintln!("{}", message);
+        }
+        if session.fault().is_some() {
+            break;
+        }
+    }
+    session.finish();
+
+    if let Some(path) = &options.screenshot {
+        display::write_png(path, session.emulator.display(), palette, options.screenshot_scale)?;
+    }
+
+    match session.fault_report() {
+        Some(report) => Err(report),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,354 @@
+//! Warnings about likely bugs in a ROM, found by following its control flow (see `analysis`) and
+//! what is known about I at each instruction: executing data, accessing memory that faults, calls
+//! that don't return or nest too deep, reliance on quirks that differ between interpreters, and
+//! code that is never executed.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use crate::{
+    analysis::{successors, Analysis, Edge, Instruction},
+    chip8::{
+        self,
+        cpu::Operation,
+        mem::{Region, RAM_START, STACK_SIZE},
+    },
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Warning {
+    pub addr: u16,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:03x}: {}", self.addr, self.message)
+    }
+}
+
+/// What is known about I before an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AddrReg {
+    Known(u16),
+    /// Pointing at a glyph, after `LoadGlyph`
+    Glyph,
+    /// After `LoadBytes` or `StoreBytes`, which some interpreters advance I past the bytes in.
+    /// Holds the value of I before, if known.
+    Bulk(Option<u16>),
+    Unknown,
+}
+
+impl AddrReg {
+    fn join(self, other: AddrReg) -> AddrReg {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (AddrReg::Bulk(_), AddrReg::Bulk(_)) => AddrReg::Bulk(None),
+            _ => AddrReg::Unknown,
+        }
+    }
+
+    /// The value of I in this emulator, which doesn't advance I in bulk loads and stores
+    fn value(self) -> Option<u16> {
+        match self {
+            AddrReg::Known(addr) | AddrReg::Bulk(Some(addr)) => Some(addr),
+            _ => None,
+        }
+    }
+}
+
+pub fn lint(rom: &[u8]) -> Vec<Warning> {
+    let analysis = Analysis::new(rom);
+    let mut warnings = vec![];
+    let mut warn = |addr: u16, message: String| warnings.push(Warning { addr, message });
+
+    let states = addr_states(&analysis);
+    for instruction in analysis.instructions.values() {
+        let state = states.get(&instruction.addr).copied().unwrap_or(AddrReg::Unknown);
+        check_instruction(&analysis, instruction, state, &mut warn);
+    }
+    check_subroutines(&analysis, &mut warn);
+    check_unreachable(rom, &analysis, &mut warn);
+
+    warnings.sort();
+    warnings.dedup();
+    warnings
+}
+
+/// Follow I through the program until nothing more is learned about it
+fn addr_states(analysis: &Analysis) -> BTreeMap<u16, AddrReg> {
+    let mut states = BTreeMap::from([(RAM_START, AddrReg::Known(0))]);
+    let mut pending = vec![RAM_START];
+    while let Some(addr) = pending.pop() {
+        let Some(instruction) = analysis.instructions.get(&addr)
+        else {
+            continue;
+        };
+        let state = states[&addr];
+        let after = match instruction.op {
+            Ok(Operation::SetAddr(addr)) => AddrReg::Known(addr),
+            Ok(Operation::LoadGlyph(_)) => AddrReg::Glyph,
+            Ok(Operation::AddAddr(_)) => AddrReg::Unknown,
+            Ok(Operation::LoadBytes(_) | Operation::StoreBytes(_)) => AddrReg::Bulk(state.value()),
+            _ => state,
+        };
+        let call = matches!(instruction.op, Ok(Operation::Call(_)));
+        for (to, edge) in successors(instruction) {
+            // Subroutines may change I before returning
+            let incoming = match edge {
+                Edge::Next if call => AddrReg::Unknown,
+                _ => after,
+            };
+            let joined = states.get(&to).map_or(incoming, |&old| old.join(incoming));
+            if states.insert(to, joined) != Some(joined) {
+                pending.push(to);
+            }
+        }
+    }
+    states
+}
+
+fn check_instruction(
+    analysis: &Analysis,
+    instruction: &Instruction,
+    state: AddrReg,
+    warn: &mut impl FnMut(u16, String),
+) {
+    let addr = instruction.addr;
+    if addr >= analysis.end {
+        // Only the first instruction of a run past the end
+        if !analysis.instructions.contains_key(&addr.wrapping_sub(2)) || addr == analysis.end {
+            warn(addr, "Execution runs past the end of the ROM".into());
+        }
+        return;
+    }
+    if let Some(loaders) = analysis.data.get(&addr) {
+        warn(addr, format!("Executes data loaded into I at {}", list(loaders)));
+    }
+    if analysis.instructions.contains_key(&(addr + 1)) {
+        warn(addr, format!("Overlaps the instruction at {:03x}", addr + 1));
+    }
+    for (to, edge) in successors(instruction) {
+        if !to.is_multiple_of(2) && matches!(edge, Edge::Jump | Edge::Call) {
+            warn(addr, format!("{:?} to the odd address {:03x}", edge, to));
+        }
+    }
+
+    let op = match &instruction.op {
+        Ok(op) => op,
+        Err(e) => return warn(addr, format!("Reaches an invalid instruction ({})", e)),
+    };
+    let bulk_quirk = "which only some interpreters advance past the bytes (the load/store quirk)";
+    let (len, write) = match *op {
+        Operation::CallRCA(to) => {
+            return warn(addr, format!("Calls machine code at {:03x}, which isn't supported", to))
+        }
+        Operation::Unimplemented(opcode) => {
+            return warn(addr, format!("Uses the unsupported instruction {:04x}", opcode))
+        }
+        Operation::Shr(x, y) | Operation::Shl(x, y) if x != y => {
+            let message =
+                "Shifts VY into VX, but CHIP-48 and SUPER-CHIP shift VX (the shift quirk)";
+            return warn(addr, message.into());
+        }
+        Operation::JumpWithOffset(to) if to >> 8 != 0 => {
+            let message = format!(
+                "Jumps to {:03x} + V0, but CHIP-48 and SUPER-CHIP add V{:X} (the jump quirk)",
+                to,
+                to >> 8
+            );
+            return warn(addr, message);
+        }
+        Operation::AddAddr(_) if matches!(state, AddrReg::Bulk(_)) => {
+            return warn(
+                addr,
+                format!("Adds to I right after a bulk load or store, {}", bulk_quirk),
+            );
+        }
+        Operation::Draw(_, _, n) => (n as u16, false),
+        Operation::LoadBytes(r) => (r as u16 + 1, false),
+        Operation::StoreBytes(r) => (r as u16 + 1, true),
+        Operation::StoreBcd(_) => (3, true),
+        _ => return,
+    };
+
+    if let AddrReg::Bulk(_) = state {
+        warn(addr, format!("Uses I right after a bulk load or store, {}", bulk_quirk));
+    }
+    if state == AddrReg::Glyph {
+        match op {
+            _ if write => warn(addr, "Writes to a glyph, which is read only".into()),
+            Operation::Draw(..) if len != 5 => {
+                warn(addr, format!("Draws {} rows of a glyph, which is 5 rows tall", len))
+            }
+            _ => {}
+        }
+    }
+    let Some(start) = state.value()
+    else {
+        return;
+    };
+    // I is 12 bits wide, so this can't overflow
+    for accessed in start..start + len {
+        let problem = match chip8::mem::region(accessed) {
+            None => "past the end of memory",
+            Some(Region::Reserved) => "reserved memory",
+            Some(Region::Glyphs) if write => "the glyphs, which are read only",
+            _ => continue,
+        };
+        let verb = match write {
+            true => "Writes to",
+            false => "Reads from",
+        };
+        return warn(addr, format!("{} {} at {:03x}", verb, problem, accessed));
+    }
+}
+
+/// Returns in the main program, subroutines without returns, recursion and calls nesting deeper
+/// than the stack
+fn check_subroutines(analysis: &Analysis, warn: &mut impl FnMut(u16, String)) {
+    for (&entry, subroutine) in &analysis.subroutines {
+        if entry == RAM_START {
+            for addr in &subroutine.instructions {
+                if let Some(Ok(Operation::Return)) = analysis.instructions.get(addr).map(|i| &i.op)
+                {
+                    warn(*addr, "Returns from the main program, with nothing on the stack".into());
+                }
+            }
+        }
+        else if !subroutine.returns {
+            let message = "Subroutine never returns, so each call leaves an address on the stack";
+            warn(entry, message.into());
+        }
+    }
+
+    let mut depths = BTreeMap::new();
+    let depth = call_depth(analysis, RAM_START, &mut BTreeSet::new(), &mut depths, warn);
+    if depth > STACK_SIZE {
+        // Point at the call starting the deepest chain
+        let main = &analysis.subroutines[&RAM_START];
+        let deepest = main.calls.iter().max_by_key(|(_, to)| depths.get(*to).copied().unwrap_or(0));
+        if let Some((&site, _)) = deepest {
+            let message = format!(
+                "Calls nest {} deep, more than the {} entries of the stack",
+                depth, STACK_SIZE
+            );
+            warn(site, message);
+        }
+    }
+}
+
+/// The deepest the calls from the subroutine at `entry` nest, warning about recursive calls
+fn call_depth(
+    analysis: &Analysis,
+    entry: u16,
+    active: &mut BTreeSet<u16>,
+    depths: &mut BTreeMap<u16, usize>,
+    warn: &mut impl FnMut(u16, String),
+) -> usize {
+    if let Some(&depth) = depths.get(&entry) {
+        return depth;
+    }
+    active.insert(entry);
+    let mut depth = 0;
+    for (&site, &to) in &analysis.subroutines[&entry].calls {
+        if active.contains(&to) {
+            warn(site, format!("Recursive call to {:03x}, which can overflow the stack", to));
+            continue;
+        }
+        depth = depth.max(1 + call_depth(analysis, to, active, depths, warn));
+    }
+    active.remove(&entry);
+    depths.insert(entry, depth);
+    depth
+}
+
+/// Runs of bytes in the ROM that are neither executed nor loaded into I, but look like code
+fn check_unreachable(rom: &[u8], analysis: &Analysis, warn: &mut impl FnMut(u16, String)) {
+    let executed: BTreeSet<u16> =
+        analysis.instructions.keys().flat_map(|&addr| [addr, addr.wrapping_add(1)]).collect();
+    // Jump tables following an indirect jump can't be followed
+    let tables: Vec<u16> = analysis
+        .indirect
+        .iter()
+        .filter_map(|addr| match analysis.instructions[addr].op {
+            Ok(Operation::JumpWithOffset(base)) => Some(base),
+            _ => None,
+        })
+        .collect();
+
+    let mut addr = RAM_START;
+    while addr < analysis.end {
+        if executed.contains(&addr) {
+            addr += 1;
+            continue;
+        }
+        let start = addr;
+        while addr < analysis.end && !executed.contains(&addr) {
+            addr += 1;
+        }
+
+        let bytes = &rom[(start - RAM_START) as usize..(addr - RAM_START) as usize];
+        let referenced = analysis.data.range(start..addr).next().is_some();
+        let table = tables.iter().any(|&base| base <= addr && start <= base.saturating_add(0xFF));
+        let padding = bytes.iter().all(|&b| b == 0);
+        let code = match bytes {
+            [hi, lo, ..] => chip8::try_decode(u16::from_be_bytes([*hi, *lo])).is_ok(),
+            _ => false,
+        };
+        if code && !referenced && !table && !padding {
+            warn(start, format!("{} bytes are never executed or loaded into I", bytes.len()));
+        }
+    }
+}
+
+fn list(addrs: &BTreeSet<u16>) -> String {
+    addrs.iter().map(|addr| format!("{:03x}", addr)).collect::<Vec<_>>().join(", ")
+}
+
+#[test]
+fn test_lint() {
+    let messages = |rom: &[u8]| lint(rom).iter().map(|w| w.to_string()).collect::<Vec<_>>();
+
+    let quirks = [
+        0x60, 0x01, 0x80, 0x16, 0xF0, 0x29, 0xF0, 0x33, 0xAE, 0xA0, 0xD0, 0x15, 0xA3, 0x00, 0xF1,
+        0x65, 0xD0, 0x15, 0xB2, 0x10,
+    ];
+    assert_eq!(messages(&quirks), [
+        "202: Shifts VY into VX, but CHIP-48 and SUPER-CHIP shift VX (the shift quirk)",
+        "206: Writes to a glyph, which is read only",
+        "20a: Reads from reserved memory at ea0",
+        "210: Uses I right after a bulk load or store, which only some interpreters advance past \
+         the bytes (the load/store quirk)",
+        "212: Jumps to 210 + V0, but CHIP-48 and SUPER-CHIP add V2 (the jump quirk)",
+    ]);
+
+    let control = [
+        0x22, 0x08, 0x22, 0x0C, 0x00, 0xEE, 0x12, 0x06, 0xA2, 0x0E, 0x00, 0xEE, 0x12, 0x0E, 0xF0,
+        0x90,
+    ];
+    assert_eq!(messages(&control), [
+        "204: Returns from the main program, with nothing on the stack",
+        "206: 2 bytes are never executed or loaded into I",
+        "20c: Subroutine never returns, so each call leaves an address on the stack",
+        "20e: Executes data loaded into I at 208",
+        "20e: Reaches an invalid instruction (Invalid opcode: f090)",
+    ]);
+
+    // 17 subroutines, each calling the next, then a jump to an odd address past the end
+    let mut nested = vec![0x22, 0x04, 0x13, 0x01];
+    for i in 0..16 {
+        nested.extend_from_slice(&[0x22, 0x06 + i * 2]);
+    }
+    nested.extend_from_slice(&[0x00, 0xEE]);
+    assert_eq!(messages(&nested), [
+        "200: Calls nest 17 deep, more than the 16 entries of the stack",
+        "202: Jump to the odd address 301",
+        "301: Execution runs past the end of the ROM",
+    ]);
+
+    let recursive = [0x22, 0x04, 0x12, 0x02, 0x22, 0x04, 0x00, 0xEE];
+    assert_eq!(messages(&recursive), ["204: Recursive call to 204, which can overflow the stack"]);
+}
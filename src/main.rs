@@ -1,23 +1,200 @@
-use std::{fs, io::Read};
-
+mod analysis;
+mod capture;
+mod cfg;
+mod cheat;
 mod chip8;
 mod client;
+mod config;
+mod coverage;
+mod display;
+mod gdb;
+mod headless;
+mod lint;
+mod movie;
+mod options;
+mod patch;
+mod profile;
+mod rom;
+mod session;
+mod smc;
+mod trace;
+mod tui;
+mod watch;
+
+fn main() {
+    let options = match options::Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, options::USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let rom = match &options.patch {
+        Some(patch) => rom::Rom::load_patched(&options.rom, Some(patch)),
+        None => rom::Rom::load(&options.rom),
+    };
+    let rom = match rom {
+        Ok(rom) => rom,
+        Err(e) => panic!("{}", e),
+    };
+
+    if options.lint {
+        let warnings = lint::lint(&rom.data);
+        for warning in &warnings {
+            println!("{}:{}", rom.path.display(), warning);
+        }
+        if !warnings.is_empty() {
+            std::process::exit(1);
+        }
+        eprintln!("No problems found");
+        return;
+    }
+    if let Some(path) = &options.cfg {
+        let graph = cfg::Graph::new(&rom.data);
+        let json = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        let out = if json { graph.json() } else { graph.dot() };
+        match std::fs::write(path, out) {
+            Ok(()) => eprintln!("Saved control-flow graph to {}", path.display()),
+            Err(e) => {
+                eprintln!("Failed to write control-flow graph {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-#[macroquad::main("CHIP8 Emulator")]
-async fn main() {
-    let filename = std::env::args().nth(1).unwrap();
-    let mut file = match fs::File::open(filename) {
-        Ok(f) => f,
-        Err(e) => panic!("Failed to open input program: {}", e),
+    let database = rom::Database::load();
+    let entry = database.lookup(&rom);
+    if let Some(title) = entry.get("title") {
+        eprintln!("Loading: {}", title);
+    }
+
+    let settings = config::load_user();
+    let palette = options.palette.clone().unwrap_or_else(|| {
+        let value = entry.get("palette").or_else(|| settings.section("")?.get("palette"));
+        match value.map(display::Palette::parse) {
+            Some(Ok(palette)) => palette,
+            Some(Err(e)) => {
+                eprintln!("Ignoring palette setting: {}", e);
+                display::Palette::default()
+            }
+            None => display::Palette::default(),
+        }
+    });
+
+    let movie = options.play.as_ref().map(|path| match movie::Movie::load(path) {
+        Ok(movie) => movie,
+        Err(e) => panic!("{}", e),
+    });
+    if let Some(movie) = &movie {
+        if movie.rom_hash != rom.hash {
+            eprintln!("Warning: movie was recorded with a different ROM ({:08x})", movie.rom_hash);
+        }
+    }
+
+    let seed = match &movie {
+        Some(movie) => movie.seed,
+        None => options.seed.unwrap_or_else(rand::random),
     };
 
-    let mut emulator = chip8::Emulator::new();
-    match file.read(&mut emulator.mem.ram) {
-        Ok(n) => println!("Loaded program of size: {}", n),
-        Err(e) => panic!("Failed to read file: {}", e),
+    if let Some(patch) = &rom.patch {
+        eprintln!("Applied patch {}", patch.display());
+    }
+    eprintln!("Loaded program of size: {}", rom.data.len());
+    let mut session = session::Session::new(rom, seed);
+    if options.random_ram {
+        session.randomize_ram();
+    }
+    // Cheats are turned on and off in the window's menu, so saved ones are only used there
+    if options.headless.is_none() && options.tui.is_none() {
+        session.set_cheats(cheat::load(session.rom.hash));
+    }
+    if let Some(movie) = movie {
+        session.play(movie::Player::new(movie));
+    }
+    if let Some(path) = &options.record {
+        let (hash, cheats) = (session.rom.hash, session.cheats());
+        match movie::Recorder::create(path, hash, seed, options.random_ram, cheats) {
+            Ok(recorder) => session.record(recorder),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    match options.fast {
+        true => session.run_fast(),
+        false => session.keep_history(options.history()),
+    }
+    if options.smc {
+        session.detect_code_writes();
+    }
+    if let Some(path) = &options.trace {
+        match trace::Tracer::create(path, options.trace_filter.clone()) {
+            Ok(tracer) => session.trace(tracer),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    if let Some(path) = &options.profile {
+        session.profile(profile::Profiler::new(Some(path.clone())));
+    }
+
+    if let Some(path) = &options.coverage {
+        session.cover(coverage::Coverage::new(session.rom.clone(), path.clone()));
+    }
+
+    if let Some(port) = options.gdb {
+        match gdb::Stub::listen(port) {
+            Ok(stub) => {
+                if let Ok(addr) = stub.local_addr() {
+                    eprintln!("Waiting for GDB on {}", addr);
+                }
+                session.serve_gdb(stub);
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    if let Some(reload) = options.watch {
+        session.watch(reload);
+    }
+
+    if let Some(path) = &options.video {
+        match capture::VideoRecorder::create(path, palette.clone(), options.video_scale) {
+            Ok(video) => session.start_video(video),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    if let Some(frames) = options.headless {
+        if let Err(e) = headless::run(session, frames, &palette, &options) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(mode) = options.tui {
+        if let Err(e) = tui::run(session, palette, mode) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
     }
 
-    if let Err(e) = client::run(emulator).await {
-        panic!("Client experienced a fatal error and had to close: {}", e);
+    let view = client::view::View::from_config(&settings);
+    let (window_width, window_height) = view.window_size();
+    let conf = macroquad::window::Conf {
+        window_title: "CHIP8 Emulator".into(),
+        window_width,
+        window_height,
+        window_resizable: true,
+        fullscreen: view.fullscreen,
+        ..Default::default()
     };
+    macroquad::Window::from_config(conf, async move {
+        if let Err(e) = client::run(session, database, palette, view, options).await {
+            panic!("Client experienced a fatal error and had to close: {}", e);
+        };
+    });
 }
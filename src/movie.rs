@@ -0,0 +1,258 @@
+//! Movie files: a recording of every input to the emulator, which can be played back to reproduce a
+//! session exactly.
+//!
+//! The format is plain text. A header identifies the ROM and the settings the movie was recorded
+//! with, followed by one line per input event:
+//!
+//! ```text
+//! chip8-movie 1
+//! rom crc32:0123abcd
+//! seed 1234
+//! ram zero
+//! cheat 2f0 03 freeze on Infinite lives
+//! clock 1000
+//! tick 60
+//! 120 down 5
+//! 135 up 5
+//! end 600
+//! ```
+//!
+//! `ram` is `random` for movies recorded with `--random-ram`, and defaults to `zero`. There is a
+//! `cheat` line for each cheat that was on, in the format of the cheat files (see `cheat`). Each
+//! event is applied just before the timer tick that starts the given frame. The `end` line is
+//! written when recording stops and is optional, so that movies of sessions that crashed can still
+//! be played back.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{
+    cheat::{self, Cheat},
+    chip8,
+};
+
+const MAGIC: &str = "chip8-movie 1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputEvent {
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: u32,
+    pub seed: u64,
+    /// Whether the RAM not used by the ROM was filled with random bytes, see `--random-ram`
+    pub random_ram: bool,
+    /// The cheats that were on
+    pub cheats: Vec<Cheat>,
+    pub events: Vec<InputEvent>,
+    pub end: Option<u64>,
+}
+
+impl Movie {
+    pub fn load(path: &Path) -> Result<Movie, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read movie {}: {}", path.display(), e))?;
+        Movie::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line.trim()));
+
+        match lines.next() {
+            Some((_, MAGIC)) => {}
+            _ => return Err("not a movie file".into()),
+        }
+
+        let mut movie = Movie {
+            rom_hash: 0,
+            seed: 0,
+            random_ram: false,
+            cheats: vec![],
+            events: vec![],
+            end: None,
+        };
+        for (n, line) in lines.filter(|(_, line)| !line.is_empty()) {
+            let invalid = || format!("line {}: invalid entry `{}`", n, line);
+            if let Some(cheat) = line.strip_prefix("cheat ") {
+                let cheat = cheat::parse_line(cheat).map_err(|e| format!("line {}: {}", n, e))?;
+                movie.cheats.push(cheat);
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            match fields[..] {
+                ["rom", hash] => {
+                    let hash = hash.strip_prefix("crc32:").ok_or_else(invalid)?;
+                    movie.rom_hash = u32::from_str_radix(hash, 16).map_err(|_| invalid())?;
+                }
+                ["seed", seed] => movie.seed = seed.parse().map_err(|_| invalid())?,
+                ["ram", "zero"] => movie.random_ram = false,
+                ["ram", "random"] => movie.random_ram = true,
+                ["clock", hz] => check_setting("clock", hz, chip8::CLOCK_HZ)?,
+                ["tick", hz] => check_setting("tick", hz, chip8::TICK_HZ)?,
+                ["end", frame] => movie.end = Some(frame.parse().map_err(|_| invalid())?),
+                [frame, kind @ ("down" | "up"), key] => {
+                    let frame = frame.parse().map_err(|_| invalid())?;
+                    let key = crate::config::parse_key(key).ok_or_else(invalid)?;
+                    let event = InputEvent { frame, key, pressed: kind == "down" };
+                    if movie.events.last().is_some_and(|last| last.frame > frame) {
+                        return Err(format!("line {}: events are out of order", n));
+                    }
+                    movie.events.push(event);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(movie)
+    }
+}
+
+fn check_setting(name: &str, value: &str, expected: u64) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(value) if value == expected => Ok(()),
+        _ => Err(format!(
+            "movie was recorded with {} = {}, but the emulator uses {}",
+            name, value, expected
+        )),
+    }
+}
+
+/// Writes a movie as it is being recorded. Events are flushed to disk as soon as they happen.
+pub struct Recorder {
+    out: io::BufWriter<fs::File>,
+}
+
+impl Recorder {
+    /// Start a movie, recording the cheats in `cheats` that are on
+    pub fn create(
+        path: &Path,
+        rom_hash: u32,
+        seed: u64,
+        random_ram: bool,
+        cheats: &[Cheat],
+    ) -> Result<Recorder, String> {
+        let file = fs::File::create(path)
+            .map_err(|e| format!("Failed to create movie {}: {}", path.display(), e))?;
+
+        let mut recorder = Recorder { out: io::BufWriter::new(file) };
+        recorder.write(&format!(
+            "{}\nrom crc32:{:08x}\nseed {}\nram {}\n",
+            MAGIC,
+            rom_hash,
+            seed,
+            if random_ram { "random" } else { "zero" },
+        ));
+        for cheat in cheats.iter().filter(|cheat| cheat.enabled) {
+            recorder.write(&format!("cheat {}\n", cheat::format_line(cheat)));
+        }
+        recorder.write(&format!("clock {}\ntick {}\n", chip8::CLOCK_HZ, chip8::TICK_HZ));
+        Ok(recorder)
+    }
+
+    pub fn record(&mut self, event: InputEvent) {
+        let kind = if event.pressed { "down" } else { "up" };
+        self.write(&format!("{} {} {:X}\n", event.frame, kind, event.key));
+    }
+
+    pub fn finish(mut self, frame: u64) {
+        self.write(&format!("end {}\n", frame));
+    }
+
+    fn write(&mut self, text: &str) {
+        if let Err(e) = self.out.write_all(text.as_bytes()).and_then(|_| self.out.flush()) {
+            eprintln!("Failed to write movie: {}", e);
+        }
+    }
+}
+
+/// Replays the events of a movie
+pub struct Player {
+    movie: Movie,
+    next: usize,
+}
+
+impl Player {
+    pub fn new(movie: Movie) -> Player {
+        Player { movie, next: 0 }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Returns the events that should be applied before the tick that starts `frame`
+    pub fn events(&mut self, frame: u64) -> &[InputEvent] {
+        let start = self.next;
+        while self.movie.events.get(self.next).is_some_and(|e| e.frame <= frame) {
+            self.next += 1;
+        }
+        &self.movie.events[start..self.next]
+    }
+
+    pub fn finished(&self, frame: u64) -> bool {
+        match self.movie.end {
+            Some(end) => frame >= end,
+            None => self.next >= self.movie.events.len(),
+        }
+    }
+}
+
+#[test]
+fn test_parse_movie() {
+    let movie = Movie::parse(
+        "chip8-movie 1\nrom crc32:0123abcd\nseed 42\nram random\ncheat 2f0 03 freeze on Lives\n\
+         clock 1000\ntick 60\n\n3 down a\n5 up A\nend 9\n",
+    )
+    .unwrap();
+
+    assert_eq!(movie.rom_hash, 0x0123_abcd);
+    assert_eq!(movie.seed, 42);
+    assert!(movie.random_ram);
+    assert_eq!(movie.cheats.len(), 1);
+    assert_eq!(movie.cheats[0].name, "Lives");
+    assert_eq!(movie.end, Some(9));
+    assert_eq!(movie.events, vec![InputEvent { frame: 3, key: 0xA, pressed: true }, InputEvent {
+        frame: 5,
+        key: 0xA,
+        pressed: false
+    },]);
+
+    assert!(Movie::parse("rom crc32:0123abcd").is_err());
+    assert!(!Movie::parse("chip8-movie 1\nseed 42\n").unwrap().random_ram);
+    assert!(Movie::parse("chip8-movie 1\nclock 500\n").is_err());
+    assert!(Movie::parse("chip8-movie 1\nram full\n").is_err());
+    assert!(Movie::parse("chip8-movie 1\ncheat 100 03 freeze on\n").is_err());
+    assert!(Movie::parse("chip8-movie 1\n5 down 1\n3 up 1\n").is_err());
+    assert!(Movie::parse("chip8-movie 1\n5 sideways 1\n").is_err());
+}
+
+#[test]
+fn test_player() {
+    let mut player = Player::new(Movie {
+        rom_hash: 0,
+        seed: 0,
+        random_ram: false,
+        cheats: vec![],
+        events: vec![
+            InputEvent { frame: 1, key: 0x1, pressed: true },
+            InputEvent { frame: 1, key: 0x2, pressed: true },
+            InputEvent { frame: 4, key: 0x1, pressed: false },
+        ],
+        end: None,
+    });
+
+    assert!(player.events(0).is_empty());
+    assert_eq!(player.events(1).len(), 2);
+    assert!(player.events(2).is_empty());
+    assert!(!player.finished(2));
+    assert_eq!(player.events(4).len(), 1);
+    assert!(player.finished(4));
+}
@@ -0,0 +1,266 @@
+use std::path::PathBuf;
+
+use crate::{display::Palette, session::Reload, trace, tui};
+
+pub const USAGE: &str = "\
+Usage: chip8_emu [OPTIONS] <ROM>
+
+Options:
+    --patch <FILE>     Apply an IPS or BPS patch to the ROM (by default, a patch next to the ROM
+                       with the same name is applied, e.g. `PONG.ips` for `PONG.ch8`)
+    --seed <N>         Seed for the random number generator (random by default)
+    --random-ram       Fill the RAM not used by the ROM with random bytes instead of zeros, to find
+                       programs that rely on zeroed memory
+    --record <FILE>    Record all input to a movie file
+    --play <FILE>      Play back input from a movie file
+    --headless <N>     Run for N frames without opening a window, then exit
+    --screenshot <FILE>
+                       Save a PNG screenshot to FILE when exiting (headless mode), or when F12 is
+                       pressed (defaults to `<ROM>-<FRAME>.png`)
+    --screenshot-scale <N>
+                       Size of each CHIP-8 pixel in screenshots (default: 8, 1 for native size)
+    --video <FILE>     Record a video to FILE from the start (`.gif` for an animated GIF, raw RGBA
+                       frames otherwise, `-` for raw frames to stdout). In the window, F10 starts
+                       and stops recording (defaults to `<ROM>-<FRAME>.gif`)
+    --video-scale <N>  Size of each CHIP-8 pixel in videos (default: 8)
+    --trace <FILE>     Log every executed instruction to FILE (binary if FILE ends in `.bin`)
+    --trace-addr <RANGE>
+                       Only log instructions at these addresses, in hex (e.g. `200-2ff`)
+    --trace-frames <RANGE>
+                       Only log instructions executed during these frames (e.g. `60-120`, `600-`)
+    --trace-ops <CLASSES>
+                       Only log these classes of operations, separated by commas: system,
+                       control, arithmetic, address, memory, special, keyboard, timer
+    --profile <FILE>   Count executions per address and cycles per subroutine, writing a report of
+                       the hotspots to FILE when exiting
+    --coverage <FILE>  Record which instructions and skip branches are executed, writing a coverage
+                       report to FILE when exiting (HTML if FILE ends in `.html`, lcov otherwise)
+    --lint             Check the ROM for likely bugs without running it, printing a warning for
+                       each and exiting with an error if there are any
+    --cfg <FILE>       Write the control-flow graph of the ROM to FILE without running it (JSON if
+                       FILE ends in `.json`, Graphviz DOT otherwise)
+    --fast             Run instructions through a cached interpreter, for batch runs. No history is
+                       kept; tracing, profiling, coverage, --smc and GDB use the regular
+                       interpreter
+    --smc              Report instructions that write into code (always on in the window and
+                       with --trace)
+    --history <N>      Number of executed instructions kept for stepping backwards in the debugger
+                       and for reporting how the program arrived at a fault (default: 1000, or 0
+                       with --headless). Each kept instruction copies the emulator state
+    --gdb <PORT>       Wait for GDB to connect on localhost PORT, and let it debug the program
+    --palette <P>      Display colors: a theme (classic, green, amber, octo, lcd) or a list of 2
+                       to 4 colors, e.g. \"#000000,#FFFFFF\". F7 cycles through the themes
+    --tui              Run in the terminal instead of opening a window, drawing two pixels per
+                       character
+    --braille          Run in the terminal, drawing 2x4 pixels per character with braille patterns
+    --watch            Restart the ROM whenever its file changes
+    --watch-restore <WHAT>
+                       Like `--watch`, but restore the previous `registers` (and stack), or replay
+                       the `input` so far to bring the new version up to the same frame";
+
+pub struct Options {
+    pub rom: PathBuf,
+    pub patch: Option<PathBuf>,
+    pub seed: Option<u64>,
+    pub random_ram: bool,
+    pub record: Option<PathBuf>,
+    pub play: Option<PathBuf>,
+    pub headless: Option<u64>,
+    pub screenshot: Option<PathBuf>,
+    pub screenshot_scale: u32,
+    pub video: Option<PathBuf>,
+    pub video_scale: u32,
+    pub palette: Option<Palette>,
+    pub trace: Option<PathBuf>,
+    pub trace_filter: trace::Filter,
+    pub profile: Option<PathBuf>,
+    pub coverage: Option<PathBuf>,
+    pub lint: bool,
+    pub cfg: Option<PathBuf>,
+    /// Set with `--history`, see `history` for the default
+    pub history: Option<usize>,
+    pub fast: bool,
+    pub smc: bool,
+    pub gdb: Option<u16>,
+    pub tui: Option<tui::Mode>,
+    pub watch: Option<Reload>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            rom: PathBuf::new(),
+            patch: None,
+            seed: None,
+            random_ram: false,
+            record: None,
+            play: None,
+            headless: None,
+            screenshot: None,
+            screenshot_scale: 8,
+            video: None,
+            video_scale: 8,
+            palette: None,
+            trace: None,
+            trace_filter: trace::Filter::default(),
+            profile: None,
+            coverage: None,
+            lint: false,
+            cfg: None,
+            history: None,
+            fast: false,
+            smc: false,
+            gdb: None,
+            tui: None,
+            watch: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut rom = None;
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("Missing value for `{}`", arg));
+            let invalid = || format!("Invalid value for `{}`", arg);
+            match arg.as_str() {
+                "--patch" => options.patch = Some(value()?.into()),
+                "--seed" => options.seed = Some(value()?.parse().map_err(|_| invalid())?),
+                "--random-ram" => options.random_ram = true,
+                "--record" => options.record = Some(value()?.into()),
+                "--play" => options.play = Some(value()?.into()),
+                "--headless" => options.headless = Some(value()?.parse().map_err(|_| invalid())?),
+                "--screenshot" => options.screenshot = Some(value()?.into()),
+                "--screenshot-scale" => options.screenshot_scale = parse_scale(&value()?, &arg)?,
+                "--video" => options.video = Some(value()?.into()),
+                "--video-scale" => options.video_scale = parse_scale(&value()?, &arg)?,
+                "--palette" => options.palette = Some(Palette::parse(&value()?)?),
+                "--trace" => options.trace = Some(value()?.into()),
+                "--trace-addr" => {
+                    options.trace_filter.addresses =
+                        Some(trace::parse_addresses(&value()?).ok_or_else(invalid)?)
+                }
+                "--trace-frames" => {
+                    options.trace_filter.frames =
+                        Some(trace::parse_frames(&value()?).ok_or_else(invalid)?)
+                }
+                "--trace-ops" => {
+                    options.trace_filter.classes =
+                        trace::parse_classes(&value()?).ok_or_else(invalid)?
+                }
+                "--profile" => options.profile = Some(value()?.into()),
+                "--coverage" => options.coverage = Some(value()?.into()),
+                "--lint" => options.lint = true,
+                "--cfg" => options.cfg = Some(value()?.into()),
+                "--history" => options.history = Some(value()?.parse().map_err(|_| invalid())?),
+                "--fast" => options.fast = true,
+                "--smc" => options.smc = true,
+                "--gdb" => options.gdb = Some(value()?.parse().map_err(|_| invalid())?),
+                "--tui" => options.tui = Some(tui::Mode::HalfBlock),
+                "--braille" => options.tui = Some(tui::Mode::Braille),
+                "--watch" => options.watch = options.watch.or(Some(Reload::Reset)),
+                "--watch-restore" => {
+                    options.watch = match value()?.as_str() {
+                        "registers" => Some(Reload::Registers),
+                        "input" => Some(Reload::Input),
+                        _ => return Err(invalid()),
+                    }
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option `{}`", arg)),
+                _ if rom.is_none() => rom = Some(arg.into()),
+                _ => return Err(format!("Unexpected argument `{}`", arg)),
+            }
+        }
+
+        if options.record.is_some() && options.play.is_some() {
+            return Err("`--record` and `--play` can't be used together".into());
+        }
+        if options.headless.is_some() && options.tui.is_some() {
+            return Err("`--headless` can't be used with a terminal frontend".into());
+        }
+        if options.headless.is_some() && options.gdb.is_some() {
+            return Err("`--headless` can't be used with `--gdb`".into());
+        }
+
+        options.rom = rom.ok_or("No ROM specified")?;
+        Ok(options)
+    }
+
+    /// The number of executed instructions to keep. Keeping them copies the emulator state on
+    /// every instruction, so headless runs, which can't step back, keep none unless asked to.
+    pub fn history(&self) -> usize {
+        match (self.history, self.headless) {
+            (Some(history), _) => history,
+            (None, Some(_)) => 0,
+            (None, None) => 1000,
+        }
+    }
+}
+
+fn parse_scale(value: &str, arg: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(scale) if scale > 0 => Ok(scale),
+        _ => Err(format!("Invalid value for `{}`", arg)),
+    }
+}
+
+#[test]
+fn test_parse_options() {
+    let parse = |args: &[&str]| Options::parse(args.iter().map(|s| s.to_string()));
+
+    let options = parse(&["--seed", "12", "pong.ch8", "--record", "pong.movie"]).unwrap();
+    assert_eq!(options.rom, PathBuf::from("pong.ch8"));
+    assert_eq!(options.seed, Some(12));
+    assert_eq!(options.record, Some(PathBuf::from("pong.movie")));
+    assert!(!options.random_ram);
+    assert!(parse(&["pong.ch8", "--random-ram"]).unwrap().random_ram);
+
+    assert!(parse(&[]).is_err());
+    assert!(parse(&["pong.ch8", "--seed"]).is_err());
+    assert!(parse(&["pong.ch8", "--seed", "x"]).is_err());
+    let options = parse(&["pong.ch8", "--patch", "fix.bps"]).unwrap();
+    assert_eq!(options.patch, Some(PathBuf::from("fix.bps")));
+    assert!(parse(&["pong.ch8", "--bogus"]).is_err());
+    assert!(parse(&["a.ch8", "b.ch8"]).is_err());
+    assert!(parse(&["a.ch8", "--record", "a", "--play", "b"]).is_err());
+    assert!(parse(&["a.ch8", "--screenshot-scale", "0"]).is_err());
+
+    let options = parse(&["a.ch8", "--headless", "600", "--screenshot", "a.png"]).unwrap();
+    assert_eq!(options.headless, Some(600));
+    assert_eq!(options.screenshot, Some(PathBuf::from("a.png")));
+    assert_eq!(options.screenshot_scale, 8);
+
+    let options = parse(&["a.ch8", "--palette", "#102030,#405060"]).unwrap();
+    assert_eq!(options.palette.unwrap().colors[1], [0x40, 0x50, 0x60, 0xFF]);
+    assert!(parse(&["a.ch8", "--palette", "mauve"]).is_err());
+
+    assert_eq!(parse(&["a.ch8", "--tui"]).unwrap().tui, Some(tui::Mode::HalfBlock));
+    assert_eq!(parse(&["a.ch8", "--braille"]).unwrap().tui, Some(tui::Mode::Braille));
+    assert!(parse(&["a.ch8", "--tui", "--headless", "10"]).is_err());
+
+    assert_eq!(parse(&["a.ch8", "--watch"]).unwrap().watch, Some(Reload::Reset));
+    let options = parse(&["a.ch8", "--watch-restore", "input", "--watch"]).unwrap();
+    assert_eq!(options.watch, Some(Reload::Input));
+    assert!(parse(&["a.ch8", "--watch-restore", "memory"]).is_err());
+
+    let options = parse(&["a.ch8", "--trace", "a.log", "--trace-ops", "memory"]).unwrap();
+    assert_eq!(options.trace, Some(PathBuf::from("a.log")));
+    assert_eq!(options.trace_filter.classes, ["memory"]);
+    assert!(parse(&["a.ch8", "--trace-addr", "zz"]).is_err());
+    assert_eq!(parse(&["a.ch8", "--history", "0"]).unwrap().history(), 0);
+    assert_eq!(parse(&["a.ch8"]).unwrap().history(), 1000);
+    assert_eq!(parse(&["a.ch8", "--headless", "60"]).unwrap().history(), 0);
+    assert_eq!(parse(&["a.ch8", "--headless", "60", "--history", "5"]).unwrap().history(), 5);
+    assert!(parse(&["--fast", "a.ch8"]).unwrap().fast);
+    assert!(parse(&["a.ch8", "--smc"]).unwrap().smc);
+    let options = parse(&["a.ch8", "--coverage", "a.html"]).unwrap();
+    assert_eq!(options.coverage, Some(PathBuf::from("a.html")));
+    assert!(!options.lint);
+    assert!(parse(&["--lint", "a.ch8"]).unwrap().lint);
+    let options = parse(&["a.ch8", "--cfg", "a.dot"]).unwrap();
+    assert_eq!(options.cfg, Some(PathBuf::from("a.dot")));
+    assert_eq!(parse(&["a.ch8", "--gdb", "1234"]).unwrap().gdb, Some(1234));
+    assert!(parse(&["a.ch8", "--gdb", "1234", "--headless", "1"]).is_err());
+}
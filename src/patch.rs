@@ -0,0 +1,254 @@
+//! IPS and BPS patches, the formats fan translations and fixes of ROMs are distributed in.
+
+use crate::{chip8::mem::RAM_SIZE, rom::crc32};
+
+/// The file extensions of the supported patch formats
+pub const EXTENSIONS: [&str; 2] = ["ips", "bps"];
+
+/// Apply an IPS or BPS patch to `source`, detecting the format from its header
+pub fn apply(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if let Some(records) = patch.strip_prefix(b"PATCH") {
+        ips(source, records)
+    }
+    else if patch.starts_with(b"BPS1") {
+        bps(source, patch)
+    }
+    else {
+        Err("Unknown patch format".into())
+    }
+}
+
+/// Records of a 24 bit offset and 16 bit size followed by the data, or by a 16 bit count and a
+/// byte to repeat if the size is 0. The records end with `EOF`, optionally followed by the size
+/// to truncate the output to.
+fn ips(source: &[u8], mut records: &[u8]) -> Result<Vec<u8>, String> {
+    let mut target = source.to_vec();
+    let mut take = |n: usize| -> Result<&[u8], String> {
+        let (field, rest) = records.split_at_checked(n).ok_or("Truncated IPS patch")?;
+        records = rest;
+        Ok(field)
+    };
+    let be = |bytes: &[u8]| bytes.iter().fold(0, |n, &b| n << 8 | b as usize);
+
+    loop {
+        let offset = take(3)?;
+        if offset == b"EOF" {
+            break;
+        }
+        let offset = be(offset);
+        let (len, fill) = match be(take(2)?) {
+            0 => (be(take(2)?), Some(take(1)?[0])),
+            len => (len, None),
+        };
+        if target.len() < offset + len {
+            target.resize(offset + len, 0);
+        }
+        match fill {
+            Some(byte) => target[offset..offset + len].fill(byte),
+            None => target[offset..offset + len].copy_from_slice(take(len)?),
+        }
+    }
+    if let Ok(size) = take(3) {
+        target.truncate(be(size));
+    }
+    Ok(target)
+}
+
+/// A header with the sizes and metadata, then actions that each copy bytes from the source, the
+/// patch or the output so far, and a footer with the CRC-32s of the source, output and patch.
+fn bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    const FOOTER: usize = 12;
+    let body =
+        patch.len().checked_sub(FOOTER).filter(|&len| len >= 4).ok_or("Truncated BPS patch")?;
+    let crc =
+        |i: usize| u32::from_le_bytes(patch[body + i * 4..body + i * 4 + 4].try_into().unwrap());
+    if crc32(&patch[..body + 8]) != crc(2) {
+        return Err("BPS patch is corrupted".into());
+    }
+    if crc32(source) != crc(0) {
+        return Err(format!(
+            "BPS patch is for a different ROM (CRC-32 {:08x}, this ROM is {:08x})",
+            crc(0),
+            crc32(source)
+        ));
+    }
+
+    let mut reader = Reader { data: &patch[..body], pos: 4 };
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata = reader.number()?;
+    reader.pos =
+        reader.pos.checked_add(metadata).filter(|&pos| pos <= body).ok_or("Truncated BPS patch")?;
+    if source_size != source.len() {
+        return Err("BPS patch has the wrong source size".into());
+    }
+    // The size comes from the patch, so don't trust it with more memory than a program can use
+    if target_size > RAM_SIZE as usize {
+        return Err("BPS patch output is larger than the program memory".into());
+    }
+
+    let mut target = Vec::with_capacity(target_size);
+    let (mut source_offset, mut target_offset) = (0_isize, 0_isize);
+    let out_of_range = || String::from("BPS patch copies from outside of the ROM");
+    while reader.pos < body {
+        let action = reader.number()?;
+        let len = (action >> 2) + 1;
+        if target.len() + len > target_size {
+            return Err("BPS patch writes past the end of its output".into());
+        }
+        match action & 3 {
+            // Source read, from the same position in the source
+            0 => {
+                let start = target.len();
+                target.extend_from_slice(source.get(start..start + len).ok_or_else(out_of_range)?);
+            }
+            // Target read, from the patch
+            1 => {
+                let bytes =
+                    reader.data.get(reader.pos..reader.pos + len).ok_or("Truncated BPS patch")?;
+                target.extend_from_slice(bytes);
+                reader.pos += len;
+            }
+            // Source copy, from anywhere in the source
+            2 => {
+                source_offset =
+                    source_offset.checked_add(reader.offset()?).ok_or_else(out_of_range)?;
+                let start = usize::try_from(source_offset).map_err(|_| out_of_range())?;
+                target.extend_from_slice(source.get(start..start + len).ok_or_else(out_of_range)?);
+                source_offset += len as isize;
+            }
+            // Target copy, from the output so far, which may overlap the bytes being written
+            _ => {
+                target_offset =
+                    target_offset.checked_add(reader.offset()?).ok_or_else(out_of_range)?;
+                for _ in 0..len {
+                    let index = usize::try_from(target_offset).map_err(|_| out_of_range())?;
+                    let byte = *target.get(index).ok_or_else(out_of_range)?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size || crc32(&target) != crc(1) {
+        return Err("BPS patch produced the wrong output".into());
+    }
+    Ok(target)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    /// A variable length number, 7 bits per byte with the last byte marked by the high bit
+    fn number(&mut self) -> Result<usize, String> {
+        let (mut number, mut shift) = (0_usize, 1_usize);
+        loop {
+            let byte = *self.data.get(self.pos).ok_or("Truncated BPS patch")?;
+            self.pos += 1;
+            number = (byte as usize & 0x7f)
+                .checked_mul(shift)
+                .and_then(|n| n.checked_add(number))
+                .ok_or("Invalid BPS patch")?;
+            if byte & 0x80 != 0 {
+                return Ok(number);
+            }
+            shift = shift.checked_mul(0x80).ok_or("Invalid BPS patch")?;
+            number = number.checked_add(shift).ok_or("Invalid BPS patch")?;
+        }
+    }
+
+    /// A relative offset, a number with the sign in its lowest bit
+    fn offset(&mut self) -> Result<isize, String> {
+        let number = self.number()?;
+        let magnitude = (number >> 1) as isize;
+        Ok(match number & 1 {
+            0 => magnitude,
+            _ => -magnitude,
+        })
+    }
+}
+
+#[test]
+fn test_ips() {
+    let source = [0, 1, 2, 3, 4, 5];
+    // Write 2 bytes at 1, 3 bytes of 0xAA at 5 past the end, then truncate to 7 bytes
+    let patch = b"PATCH\x00\x00\x01\x00\x02\x10\x11\x00\x00\x05\x00\x00\x00\x03\xAAEOF\x00\x00\x07";
+    assert_eq!(apply(&source, patch).unwrap(), [0, 0x10, 0x11, 3, 4, 0xAA, 0xAA]);
+    assert!(apply(&source, b"PATCH\x00\x00\x01\x00\x02\x10").is_err());
+    assert!(apply(&source, b"NOT A PATCH").is_err());
+}
+
+#[test]
+fn test_bps() {
+    fn number(out: &mut Vec<u8>, mut n: usize) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(byte | 0x80);
+                return;
+            }
+            out.push(byte);
+            n -= 1;
+        }
+    }
+
+    let source = b"ABCDEFGH";
+    let target = b"ABCEFGHxyxyxyx";
+    let mut patch = b"BPS1".to_vec();
+    number(&mut patch, source.len());
+    number(&mut patch, target.len());
+    number(&mut patch, 0);
+    // Source read "ABC", source copy "EFGH", target read "xy", then target copy "xyxyx" from the
+    // "xy" just written
+    number(&mut patch, 2 << 2);
+    number(&mut patch, 3 << 2 | 2);
+    number(&mut patch, 4 << 1);
+    number(&mut patch, 1 << 2 | 1);
+    patch.extend_from_slice(b"xy");
+    number(&mut patch, 4 << 2 | 3);
+    number(&mut patch, 7 << 1);
+    patch.extend_from_slice(&crc32(source).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+
+    assert_eq!(apply(source, &patch).unwrap(), target);
+    let error = apply(b"ABCDEFGX", &patch).unwrap_err();
+    assert!(error.contains("different ROM"), "{}", error);
+    let mut corrupted = patch.clone();
+    corrupted[8] ^= 1;
+    assert_eq!(apply(source, &corrupted).unwrap_err(), "BPS patch is corrupted");
+
+    // Sizes, lengths and offsets from the patch are checked before anything is allocated or
+    // copied
+    let with_body = |target_size: usize, metadata: usize, body: &[usize]| {
+        let mut patch = b"BPS1".to_vec();
+        number(&mut patch, source.len());
+        number(&mut patch, target_size);
+        number(&mut patch, metadata);
+        for &n in body {
+            number(&mut patch, n);
+        }
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        patch
+    };
+    let error = apply(source, &with_body(usize::MAX >> 8, 0, &[0])).unwrap_err();
+    assert!(error.contains("larger than the program memory"), "{}", error);
+    let error = apply(source, &with_body(4, 0, &[7 << 2])).unwrap_err();
+    assert!(error.contains("past the end"), "{}", error);
+    let error = apply(source, &with_body(4, usize::MAX - 2, &[0])).unwrap_err();
+    assert_eq!(error, "Truncated BPS patch");
+    // A source copy of 1 byte from 0, then one from isize::MAX bytes further, and the same for
+    // target copies after a target read
+    let far = (isize::MAX as usize) << 1;
+    let error = apply(source, &with_body(4, 0, &[2, 0, 2, far])).unwrap_err();
+    assert!(error.contains("outside of the ROM"), "{}", error);
+    let error = apply(source, &with_body(4, 0, &[1, 0, 3, 0, 3, far])).unwrap_err();
+    assert!(error.contains("outside of the ROM"), "{}", error);
+}
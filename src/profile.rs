@@ -0,0 +1,190 @@
+//! Counts how often each address is executed, read and written, and how many cycles each
+//! subroutine takes, for finding the hotspots of a program. Every instruction counts as one cycle.
+
+use std::{collections::BTreeMap, fmt::Write as _, fs, path::PathBuf};
+
+use crate::chip8::{
+    self,
+    cpu::{Operation, Registers},
+    mem::TOTAL_MEMORY,
+};
+
+/// The number of addresses listed in the hotspots of a report
+const HOTSPOTS: usize = 20;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Subroutine {
+    pub calls: u64,
+    /// Cycles from the call until the return, including the subroutines it calls
+    pub cycles: u64,
+    /// Cycles spent in the subroutine itself
+    pub self_cycles: u64,
+}
+
+pub struct Profiler {
+    /// Executions, reads and writes of each address
+    pub executed: Vec<u64>,
+    pub read: Vec<u64>,
+    pub written: Vec<u64>,
+    /// The last opcode executed at each address, for the report
+    opcodes: Vec<u16>,
+    pub subroutines: BTreeMap<u16, Subroutine>,
+    /// The subroutines being executed, following the emulator's stack: the entry address and the
+    /// cycle count when each was called
+    frames: Vec<(u16, u64)>,
+    pub cycles: u64,
+    /// Where the report is written when the profiler is finished
+    report: Option<PathBuf>,
+}
+
+impl Profiler {
+    pub fn new(report: Option<PathBuf>) -> Profiler {
+        let size = TOTAL_MEMORY as usize;
+        Profiler {
+            executed: vec![0; size],
+            read: vec![0; size],
+            written: vec![0; size],
+            opcodes: vec![0; size],
+            subroutines: BTreeMap::new(),
+            frames: vec![],
+            cycles: 0,
+            report,
+        }
+    }
+
+    /// Count an executed instruction, given the registers and the stack depth before it was
+    /// executed and the emulator after
+    pub fn record(
+        &mut self,
+        opcode: u16,
+        before: &Registers,
+        depth: usize,
+        emulator: &chip8::Emulator,
+    ) {
+        self.cycles += 1;
+        let pc = before.pc as usize;
+        self.executed[pc] += 1;
+        self.opcodes[pc] = opcode;
+
+        let i = before.i;
+        match chip8::try_decode(opcode) {
+            Ok(Operation::Draw(_, _, n)) => count(&mut self.read, i, n as u16),
+            Ok(Operation::LoadBytes(r)) => count(&mut self.read, i, r as u16 + 1),
+            Ok(Operation::StoreBytes(r)) => count(&mut self.written, i, r as u16 + 1),
+            Ok(Operation::StoreBcd(_)) => count(&mut self.written, i, 3),
+            _ => {}
+        }
+
+        if let Some(&(entry, _)) = self.frames.last() {
+            self.subroutines.entry(entry).or_default().self_cycles += 1;
+        }
+
+        // Calls and returns are followed through the stack, so that resets (which clear the stack)
+        // end the subroutines that were running
+        let stack = emulator.mem.stack.len();
+        if stack > depth && self.frames.len() < stack {
+            let entry = emulator.cpu.registers().pc;
+            self.frames.push((entry, self.cycles));
+            self.subroutines.entry(entry).or_default().calls += 1;
+        }
+        while self.frames.len() > stack {
+            let (entry, start) = self.frames.pop().unwrap();
+            self.subroutines.entry(entry).or_default().cycles += self.cycles - start;
+        }
+    }
+
+    /// A text report of the most executed addresses and the subroutines, by cycles
+    pub fn report(&self) -> String {
+        let percent = |n: u64| n as f64 * 100.0 / self.cycles.max(1) as f64;
+        let mut out = format!("Profile of {} cycles\n\nHotspots\n", self.cycles);
+        out.push_str("address  executions       %  instruction\n");
+
+        let mut hotspots: Vec<usize> =
+            (0..self.executed.len()).filter(|&a| self.executed[a] > 0).collect();
+        hotspots.sort_by_key(|&addr| std::cmp::Reverse(self.executed[addr]));
+        for &addr in hotspots.iter().take(HOTSPOTS) {
+            let op =
+                chip8::try_decode(self.opcodes[addr]).map(|op| op.to_string()).unwrap_or_default();
+            let _ = writeln!(
+                out,
+                "    {:03x}  {:>10}  {:>5.1}%  {:04x} {}",
+                addr,
+                self.executed[addr],
+                percent(self.executed[addr]),
+                self.opcodes[addr],
+                op
+            );
+        }
+
+        out.push_str("\nSubroutines\n");
+        out.push_str("  entry       calls      cycles       %  self cycles       %\n");
+        let mut subroutines: Vec<_> = self.subroutines.iter().collect();
+        subroutines.sort_by_key(|(_, s)| std::cmp::Reverse(s.cycles));
+        for (entry, s) in subroutines {
+            let _ = writeln!(
+                out,
+                "    {:03x}  {:>10}  {:>10}  {:>5.1}%  {:>11}  {:>5.1}%",
+                entry,
+                s.calls,
+                s.cycles,
+                percent(s.cycles),
+                s.self_cycles,
+                percent(s.self_cycles)
+            );
+        }
+        out
+    }
+
+    /// Write the report, if the profiler was created with a path for it
+    pub fn finish(self) {
+        let Some(path) = &self.report
+        else {
+            return;
+        };
+        match fs::write(path, self.report()) {
+            Ok(()) => eprintln!("Saved profile to {}", path.display()),
+            Err(e) => eprintln!("Failed to write profile {}: {}", path.display(), e),
+        }
+    }
+}
+
+fn count(counts: &mut [u64], start: u16, len: u16) {
+    for addr in start..start.saturating_add(len) {
+        if let Some(count) = counts.get_mut(addr as usize) {
+            *count += 1;
+        }
+    }
+}
+
+#[test]
+fn test_profiler() {
+    // Call a subroutine that draws a sprite and calls another that stores V0, in a loop
+    let program = [
+        0xA3, 0x00, 0x22, 0x06, 0x12, 0x02, 0xD0, 0x15, 0x22, 0x0C, 0x00, 0xEE, 0xF0, 0x55, 0x00,
+        0xEE,
+    ];
+    let mut emulator = chip8::Emulator::new(0);
+    emulator.load(&program);
+    let mut profiler = Profiler::new(None);
+    for _ in 0..71 {
+        let before = emulator.cpu.registers();
+        let opcode = emulator.mem.read_word(before.pc).unwrap();
+        let depth = emulator.mem.stack.len();
+        emulator.frame().unwrap();
+        profiler.record(opcode, &before, depth, &emulator);
+    }
+
+    // SetAddr, then 10 iterations of Call, Draw, Call, StoreBytes, Return, Return, Jump
+    assert_eq!(profiler.cycles, 71);
+    assert_eq!(profiler.executed[0x200], 1);
+    assert_eq!(profiler.executed[0x206], 10);
+    assert_eq!(profiler.read[0x300..0x306], [10, 10, 10, 10, 10, 0]);
+    assert_eq!(profiler.written[0x300..0x302], [10, 0]);
+    assert_eq!(profiler.subroutines[&0x206], Subroutine { calls: 10, cycles: 50, self_cycles: 30 });
+    assert_eq!(profiler.subroutines[&0x20C], Subroutine { calls: 10, cycles: 20, self_cycles: 20 });
+
+    let report = profiler.report();
+    assert!(report.starts_with("Profile of 71 cycles\n"));
+    assert!(report.contains("    206          10   14.1%  d015 Draw V0, V1, 5\n"), "{}", report);
+    assert!(report.contains("    206          10          50   70.4%           30   42.3%\n"));
+}
@@ -0,0 +1,209 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{self, Config, Section},
+    patch,
+};
+
+/// The ROM database that ships with the emulator, see `roms.ini` for the format
+static BUILTIN_DATABASE: &str = include_str!("../roms.ini");
+
+#[derive(Clone)]
+pub struct Rom {
+    pub path: PathBuf,
+    /// The program, with the patch applied
+    pub data: Vec<u8>,
+    pub hash: u32,
+    /// The IPS or BPS patch applied to the file, if any
+    pub patch: Option<PathBuf>,
+}
+
+impl Rom {
+    /// Load a ROM, applying the patch next to it with the same name (e.g. `PONG.ips` for
+    /// `PONG.ch8`) if there is one
+    pub fn load(path: &Path) -> Result<Rom, String> {
+        let patch =
+            patch::EXTENSIONS.iter().map(|ext| path.with_extension(ext)).find(|p| p.is_file());
+        Rom::load_patched(path, patch.as_deref())
+    }
+
+    /// Load a ROM and apply `patch` to it
+    pub fn load_patched(path: &Path, patch: Option<&Path>) -> Result<Rom, String> {
+        let mut data =
+            fs::read(path).map_err(|e| format!("Failed to open input program: {}", e))?;
+        if let Some(patch) = patch {
+            let error = |e| format!("Failed to apply patch {}: {}", patch.display(), e);
+            let patch_data = fs::read(patch).map_err(|e| error(e.to_string()))?;
+            data = patch::apply(&data, &patch_data).map_err(error)?;
+        }
+        Ok(Rom { path: path.into(), hash: crc32(&data), data, patch: patch.map(Path::to_path_buf) })
+    }
+
+    /// The name of the ROM as used for database lookups, see `name`
+    pub fn name(&self) -> String {
+        name(&self.path)
+    }
+}
+
+/// The name of a ROM file as used for database lookups (the lowercase file name without extension)
+pub fn name(path: &Path) -> String {
+    path.file_stem().map(|s| s.to_string_lossy().to_lowercase()).unwrap_or_default()
+}
+
+/// Per-ROM settings, read from the builtin database and then the user's `roms.ini`
+pub struct Database {
+    config: Config,
+}
+
+impl Database {
+    pub fn load() -> Database {
+        let mut config = Config::parse(BUILTIN_DATABASE).expect("Invalid builtin ROM database");
+
+        if let Some(dir) = config::dir() {
+            match Config::load(&dir.join("roms.ini")) {
+                Ok(user) => config.merge(&user),
+                Err(e) => eprintln!("Ignoring user ROM database: {}", e),
+            }
+        }
+
+        Database { config }
+    }
+
+    /// Find the settings for a ROM. Entries matching the ROM's hash take priority over entries
+    /// matching its name.
+    pub fn lookup(&self, rom: &Rom) -> Section {
+        let mut entry = Section::new(&rom.name());
+        if let Some(section) = self.by_name(&rom.name()) {
+            entry.merge(section);
+        }
+        if let Some(section) = self.by_hash(rom.hash) {
+            entry.merge(section);
+        }
+        entry
+    }
+
+    /// The entry matching a ROM's name (see `name`), which doesn't need the ROM to be read
+    pub fn by_name(&self, name: &str) -> Option<&Section> {
+        self.config.section(name)
+    }
+
+    /// The entry matching the CRC-32 of a ROM's data
+    pub fn by_hash(&self, hash: u32) -> Option<&Section> {
+        self.config.section(&format!("crc32:{:08x}", hash))
+    }
+}
+
+/// Recently played ROMs, most recent first, stored one path per line in `recent.txt` in the
+/// configuration directory
+pub struct Recent {
+    pub paths: Vec<PathBuf>,
+}
+
+impl Recent {
+    const MAX: usize = 10;
+
+    pub fn load() -> Recent {
+        let text = config::dir().and_then(|dir| fs::read_to_string(dir.join("recent.txt")).ok());
+        let text = text.unwrap_or_default();
+        let paths = text.lines().filter(|l| !l.is_empty()).map(PathBuf::from);
+        Recent { paths: paths.take(Recent::MAX).collect() }
+    }
+
+    /// Move `path` to the top of the list
+    pub fn add(&mut self, path: &Path) {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.into());
+        self.paths.retain(|p| *p != path);
+        self.paths.insert(0, path);
+        self.paths.truncate(Recent::MAX);
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let dir = config::dir().ok_or("No configuration directory")?;
+        let path = dir.join("recent.txt");
+        let text: String = self.paths.iter().map(|p| format!("{}\n", p.display())).collect();
+        fs::create_dir_all(&dir)
+            .and_then(|_| fs::write(&path, text))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+/// CRC-32 (ISO-HDLC), as used by zip, png and most ROM databases
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0x0000_0000);
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
+
+/// A ROM that isn't read from a file, for tests
+#[cfg(test)]
+pub fn test_rom(data: Vec<u8>) -> Rom {
+    Rom { path: "test.ch8".into(), hash: 0, data, patch: None }
+}
+
+/// The builtin database without the user's entries, for tests
+#[cfg(test)]
+pub fn builtin_database() -> Database {
+    Database { config: Config::parse(BUILTIN_DATABASE).unwrap() }
+}
+
+#[test]
+fn test_builtin_database() {
+    let database = builtin_database();
+    let rom = Rom { path: "roms/PONG.ch8".into(), data: vec![], hash: 0, patch: None };
+    assert_eq!(rom.name(), "pong");
+    assert!(database.lookup(&rom).get("title").is_some());
+}
+
+#[test]
+fn test_recent() {
+    let mut recent = Recent { paths: vec![] };
+    for i in 0..12 {
+        recent.add(Path::new(&format!("/roms/{}.ch8", i)));
+    }
+    assert_eq!(recent.paths.len(), Recent::MAX);
+    assert_eq!(recent.paths[0], Path::new("/roms/11.ch8"));
+
+    recent.add(Path::new("/roms/5.ch8"));
+    assert_eq!(recent.paths.len(), Recent::MAX);
+    assert_eq!(recent.paths[0], Path::new("/roms/5.ch8"));
+    assert_eq!(recent.paths[1], Path::new("/roms/11.ch8"));
+    assert_eq!(recent.paths.iter().filter(|p| p.ends_with("5.ch8")).count(), 1);
+}
+
+#[test]
+fn test_load_patched() {
+    let dir = std::env::temp_dir().join(format!("chip8_emu_test_patch_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("game.ch8"), [0x12, 0x00]).unwrap();
+    fs::write(dir.join("other.ch8"), [0x12, 0x00]).unwrap();
+    fs::write(dir.join("game.ips"), b"PATCH\x00\x00\x01\x00\x01\x02EOF").unwrap();
+
+    let rom = Rom::load(&dir.join("game.ch8"));
+    let other = Rom::load(&dir.join("other.ch8"));
+    let explicit = Rom::load_patched(&dir.join("other.ch8"), Some(&dir.join("game.ips")));
+    let missing = Rom::load_patched(&dir.join("other.ch8"), Some(&dir.join("missing.bps")));
+    fs::remove_dir_all(&dir).unwrap();
+
+    let rom = rom.unwrap();
+    assert_eq!(rom.data, [0x12, 0x02]);
+    assert_eq!(rom.hash, crc32(&[0x12, 0x02]));
+    assert_eq!(rom.patch, Some(dir.join("game.ips")));
+    assert_eq!(other.unwrap().patch, None);
+    assert_eq!(explicit.unwrap().data, [0x12, 0x02]);
+    assert!(missing.is_err_and(|e| e.starts_with("Failed to apply patch")));
+}
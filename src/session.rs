@@ -0,0 +1,939 @@
+//! Drives an emulator in real time, independent of the frontend used to display it.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    capture::VideoRecorder,
+    cheat::{self, Cheat},
+    chip8,
+    coverage::Coverage,
+    gdb,
+    movie::{InputEvent, Movie, Player, Recorder},
+    profile::Profiler,
+    rom::Rom,
+    smc::{self, CodeWrite},
+    trace::Tracer,
+    watch::Watcher,
+};
+
+pub struct Session {
+    pub rom: Rom,
+    pub emulator: chip8::Emulator,
+    seed: u64,
+    /// Fill unused RAM with random bytes when powering on, see `Emulator::power_cycle`
+    random_ram: bool,
+    timers: Timers,
+
+    /// Input received since the last timer tick. Input is only applied to the emulator on tick
+    /// boundaries so that a session can be reproduced from the frame numbers of its input.
+    pending: Vec<(u8, bool)>,
+    /// All input applied since the program was started
+    inputs: Vec<InputEvent>,
+
+    /// The last executed instructions, for stepping backwards and reporting faults
+    history: Option<chip8::History>,
+    /// The fault that stopped the program, if any. The emulator doesn't run until it is cleared.
+    fault: Option<chip8::Error>,
+    cheats: Vec<Cheat>,
+    /// Writes the program made into its own code, while detecting them, see `detect_code_writes`
+    code_writes: Option<smc::Detector>,
+    /// Run instructions through the cached interpreter when nothing needs to see them one by one
+    fast: bool,
+    /// Messages for the user that haven't been shown yet, see `take_messages`
+    messages: Vec<String>,
+
+    recorder: Option<Recorder>,
+    player: Option<Player>,
+    video: Option<VideoRecorder>,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    /// Watchers for the ROM and its patch, if any
+    watcher: Option<(Vec<Watcher>, Reload)>,
+    gdb: Option<gdb::Stub>,
+}
+
+impl Session {
+    pub fn new(rom: Rom, seed: u64) -> Session {
+        let mut session = Session {
+            rom,
+            emulator: chip8::Emulator::new(seed),
+            seed,
+            random_ram: false,
+            timers: Timers::default(),
+            pending: vec![],
+            inputs: vec![],
+            history: None,
+            fault: None,
+            cheats: vec![],
+            code_writes: None,
+            fast: false,
+            messages: vec![],
+            recorder: None,
+            player: None,
+            video: None,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            watcher: None,
+            gdb: None,
+        };
+        session.power_cycle();
+        session
+    }
+
+    /// Replace the running program with `rom` and its `cheats`, see `power_cycle`
+    pub fn load(&mut self, rom: Rom, cheats: Vec<Cheat>) {
+        if let Some((watchers, _)) = &mut self.watcher {
+            *watchers = watch_files(&rom);
+        }
+        if self.code_writes.is_some() {
+            self.code_writes = Some(smc::Detector::new(&rom.data));
+        }
+        self.rom = rom;
+        self.cheats = cheats;
+        self.power_cycle();
+    }
+
+    /// Start the program again, keeping the contents of RAM. See `Emulator::reset`.
+    pub fn reset(&mut self) {
+        self.stop_movie();
+        self.clear_fault();
+        self.emulator.reset();
+    }
+
+    /// Start the program again on a freshly powered on emulator
+    pub fn power_cycle(&mut self) {
+        self.stop_movie();
+        self.clear_fault();
+        self.timers = Timers::default();
+        let n = self.emulator.power_cycle(&self.rom.data, self.random_ram);
+        if n < self.rom.data.len() {
+            self.log(format!("Program too large, only the first {} bytes were loaded", n));
+        }
+        if let Some(code_writes) = &mut self.code_writes {
+            code_writes.clear();
+        }
+        self.apply_cheats(true);
+    }
+
+    /// Fill the RAM not used by the program with random bytes whenever the emulator is powered
+    /// on, starting now
+    pub fn randomize_ram(&mut self) {
+        self.random_ram = true;
+        self.power_cycle();
+    }
+
+    /// Movie recording and playback are stopped on resets, since the movie format has no way to
+    /// represent them
+    fn stop_movie(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            recorder.finish(self.emulator.ticks);
+            self.log("Stopped recording movie".into());
+        }
+        self.player = None;
+        self.pending.clear();
+        self.inputs.clear();
+    }
+
+    fn clear_fault(&mut self) {
+        self.fault = None;
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+
+    /// Replace the running program with a new version of it, carrying over the cheats and the
+    /// state selected by `reload`
+    pub fn reload(&mut self, rom: Rom, reload: Reload) {
+        let registers = self.emulator.cpu.registers();
+        let stack = self.emulator.mem.stack.clone();
+        let frame = self.emulator.ticks;
+        let events = std::mem::take(&mut self.inputs);
+        let recording = self.recorder.is_some();
+        let cheats = std::mem::take(&mut self.cheats);
+        self.load(rom, cheats);
+        if recording {
+            self.log("Warning: reloading the ROM ended the movie recording".into());
+        }
+
+        match reload {
+            Reload::Reset => {}
+            Reload::Registers => {
+                self.emulator.cpu.set_registers(&registers);
+                self.emulator.mem.stack = stack;
+            }
+            Reload::Input => {
+                let movie = Movie {
+                    rom_hash: self.rom.hash,
+                    seed: self.seed,
+                    random_ram: self.random_ram,
+                    cheats: self.enabled_cheats(),
+                    events,
+                    end: None,
+                };
+                self.player = Some(Player::new(movie));
+
+                // Catch up without recording the replayed frames
+                let video = self.video.take();
+                for _ in 0..frame {
+                    self.run_frame();
+                }
+                self.video = video;
+                self.player = None;
+            }
+        }
+    }
+
+    /// Reload the program whenever its file, or the patch applied to it, changes
+    pub fn watch(&mut self, reload: Reload) {
+        self.watcher = Some((watch_files(&self.rom), reload));
+    }
+
+    /// Let a debugger control the program over the GDB remote protocol, see `gdb::Stub`
+    pub fn serve_gdb(&mut self, stub: gdb::Stub) {
+        self.gdb = Some(stub);
+    }
+
+    /// Record all input to a movie
+    pub fn record(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Replay input from a movie instead of the user. Live input is ignored until the movie ends.
+    /// The emulator is powered on again with the movie's cheats if it was recorded with different
+    /// RAM contents or cheats.
+    pub fn play(&mut self, player: Player) {
+        let movie = player.movie();
+        if movie.random_ram != self.random_ram || movie.cheats != self.enabled_cheats() {
+            self.random_ram = movie.random_ram;
+            self.cheats = movie.cheats.clone();
+            self.power_cycle();
+        }
+        self.player = Some(player);
+    }
+
+    /// Keep the last `capacity` executed instructions, so that they can be undone and shown when
+    /// the program faults
+    pub fn keep_history(&mut self, capacity: usize) {
+        self.history = (capacity > 0).then(|| chip8::History::new(capacity));
+    }
+
+    pub fn history(&self) -> Option<&chip8::History> {
+        self.history.as_ref()
+    }
+
+    /// Detect instructions writing into code from now on, reporting the first write by each one.
+    /// It is off by default, as it analyses the ROM and checks every executed instruction.
+    pub fn detect_code_writes(&mut self) {
+        if self.code_writes.is_none() {
+            self.code_writes = Some(smc::Detector::new(&self.rom.data));
+        }
+    }
+
+    /// The latest write into code by each instruction that wrote into code since the emulator was
+    /// powered on, if they are being detected
+    pub fn code_writes(&self) -> Option<&BTreeMap<u16, CodeWrite>> {
+        self.code_writes.as_ref().map(|code_writes| &code_writes.writes)
+    }
+
+    /// Take the messages for the user logged since the last call, e.g. that the program was
+    /// reloaded. Frontends show them however suits them, rather than the session printing them.
+    pub fn take_messages(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.messages)
+    }
+
+    fn log(&mut self, message: String) {
+        self.messages.push(message);
+    }
+
+    pub fn fault(&self) -> Option<chip8::Error> {
+        self.fault
+    }
+
+    /// Describe the fault that stopped the program and the instructions leading up to it
+    pub fn fault_report(&self) -> Option<String> {
+        let fault = self.fault?;
+        let registers = self.emulator.cpu.registers();
+        let mut report =
+            format!("{} at {:#05x} in frame {}", fault, registers.pc, self.emulator.ticks);
+
+        if let Some(history) = &self.history {
+            report.push_str("\nLast executed instructions:");
+            for step in history.steps().rev().take(REPORT_STEPS).rev() {
+                report.push_str(&format!("\n  {}", step));
+            }
+        }
+        let stack: Vec<_> = self.emulator.mem.stack.iter().map(|a| format!("{:03x}", a)).collect();
+        report.push_str(&format!("\nStack: [{}]", stack.join(" ")));
+        Some(report)
+    }
+
+    /// Execute a single instruction, for stepping through the program while it is paused
+    pub fn step(&mut self) {
+        self.cycle();
+    }
+
+    /// Undo the last executed instruction, clearing any fault. Returns false if there is no
+    /// history to undo. Movies are stopped, as they can't represent going back in time.
+    pub fn step_back(&mut self) -> bool {
+        let Some(history) = &mut self.history
+        else {
+            return false;
+        };
+        if !history.undo(&mut self.emulator) {
+            return false;
+        }
+        self.fault = None;
+        if self.recorder.is_some() || self.player.is_some() {
+            self.stop_movie();
+        }
+        true
+    }
+
+    /// Run instructions through the cached interpreter (see `Emulator::run`), unless they are
+    /// traced, profiled, recorded for coverage or history, checked for writes into code, or
+    /// debugged with GDB
+    pub fn run_fast(&mut self) {
+        self.fast = true;
+    }
+
+    /// Log every executed instruction, along with its writes into code
+    pub fn trace(&mut self, tracer: Tracer) {
+        self.detect_code_writes();
+        self.tracer = Some(tracer);
+    }
+
+    /// Count executions and memory accesses per address, see `Profiler`
+    pub fn profile(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Record which instructions and branches are executed, see `Coverage`
+    pub fn cover(&mut self, coverage: Coverage) {
+        self.coverage = Some(coverage);
+    }
+
+    /// Replace the cheats, applying the enabled pokes
+    pub fn set_cheats(&mut self, cheats: Vec<Cheat>) {
+        self.cheats = cheats;
+        self.cheats_changed();
+        self.apply_cheats(true);
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    fn enabled_cheats(&self) -> Vec<Cheat> {
+        self.cheats.iter().filter(|cheat| cheat.enabled).cloned().collect()
+    }
+
+    /// Turn a cheat on or off, writing it straight away when turned on
+    pub fn toggle_cheat(&mut self, index: usize) {
+        let Some(cheat) = self.cheats.get_mut(index)
+        else {
+            return;
+        };
+        cheat.enabled = !cheat.enabled;
+        if cheat.enabled {
+            cheat.write(&mut self.emulator.mem);
+        }
+        self.cheats_changed();
+    }
+
+    pub fn add_cheat(&mut self, cheat: Cheat) {
+        if cheat.enabled {
+            cheat.write(&mut self.emulator.mem);
+        }
+        self.cheats.push(cheat);
+        self.cheats_changed();
+    }
+
+    /// Movies only record the cheats that were on when they started, so changing them stops the
+    /// movie being recorded or played
+    fn cheats_changed(&mut self) {
+        if self.recorder.is_some() || self.player.is_some() {
+            self.log("Cheats changed, stopping the movie".into());
+            self.stop_movie();
+        }
+    }
+
+    /// Write the enabled freezes, and the enabled pokes if `pokes`
+    fn apply_cheats(&mut self, pokes: bool) {
+        for cheat in &self.cheats {
+            if cheat.enabled && (pokes || cheat.kind == cheat::Kind::Freeze) {
+                cheat.write(&mut self.emulator.mem);
+            }
+        }
+    }
+
+    /// Start recording a video of the display, replacing any video currently being recorded
+    pub fn start_video(&mut self, video: VideoRecorder) {
+        self.stop_video();
+        self.video = Some(video);
+    }
+
+    pub fn stop_video(&mut self) {
+        if let Some(video) = self.video.take() {
+            video.finish();
+        }
+    }
+
+    pub fn is_recording_video(&self) -> bool {
+        self.video.is_some()
+    }
+
+    pub fn keydown(&mut self, key: u8) {
+        self.pending.push((key, true));
+    }
+
+    pub fn keyup(&mut self, key: u8) {
+        self.pending.push((key, false));
+    }
+
+    /// Advance the emulator by `elapsed` seconds
+    pub fn run(&mut self, elapsed: f64) {
+        if let Some((watchers, reload)) = &mut self.watcher {
+            // Poll every file, so that changes to both are reported together
+            let changed = watchers.iter_mut().fold(false, |changed, w| w.changed() | changed);
+            if changed {
+                let reload = *reload;
+                match Rom::load_patched(&self.rom.path, self.rom.patch.as_deref()) {
+                    Ok(rom) => {
+                        self.log(format!("Reloading {}", rom.path.display()));
+                        self.reload(rom, reload);
+                    }
+                    Err(e) => self.log(e),
+                }
+            }
+        }
+
+        if let Some(mut gdb) = self.gdb.take() {
+            gdb.poll(self);
+            self.gdb = Some(gdb);
+        }
+
+        // Time doesn't pass while the program is stopped by a fault or the debugger
+        if !self.stopped() {
+            self.timers.elapsed(elapsed);
+            self.run_pending();
+        }
+    }
+
+    /// Advance the emulator by exactly one frame (1 / TICK_HZ seconds)
+    pub fn run_frame(&mut self) {
+        self.timers.now += Timers::TICK_PERIOD;
+        self.run_pending();
+    }
+
+    fn stopped(&self) -> bool {
+        self.fault.is_some() || self.gdb.as_ref().is_some_and(|gdb| !gdb.is_running())
+    }
+
+    fn run_pending(&mut self) {
+        while !self.stopped() {
+            match self.timers.next() {
+                TimeEvent::Tick => self.tick(),
+                TimeEvent::Cycle if self.observed() => self.cycle(),
+                TimeEvent::Cycle => {
+                    let cycles = 1 + self.timers.take_cycles();
+                    self.run_cycles(cycles);
+                }
+                TimeEvent::None => break,
+            }
+        }
+    }
+
+    /// Whether the instructions need to be executed one by one
+    fn observed(&self) -> bool {
+        !self.fast
+            || self.history.is_some()
+            || self.tracer.is_some()
+            || self.profiler.is_some()
+            || self.coverage.is_some()
+            || self.code_writes.is_some()
+            || self.gdb.is_some()
+    }
+
+    /// Execute instructions through the cached interpreter, stopping at a fault
+    fn run_cycles(&mut self, cycles: u64) {
+        let (executed, result) = self.emulator.run(cycles);
+        self.report_unimplemented();
+        if let Err(fault) = result {
+            // Leave the cycles after the fault to run once it is cleared, as `cycle` does
+            self.timers.cycle -= (cycles - executed) * Timers::CYCLE_PERIOD;
+            self.fault = Some(fault);
+        }
+    }
+
+    fn cycle(&mut self) {
+        if self.fault.is_some() {
+            return;
+        }
+        let pc = self.emulator.cpu.registers().pc;
+        if self.gdb.as_mut().is_some_and(|gdb| gdb.breakpoint(pc)) {
+            return;
+        }
+        if let Some(history) = &mut self.history {
+            history.record(&self.emulator);
+        }
+
+        let before = self.emulator.cpu.registers();
+        let opcode = self.emulator.mem.read_word(before.pc).unwrap_or(0);
+        let depth = self.emulator.mem.stack.len();
+
+        if let Err(fault) = self.emulator.frame() {
+            // Undo whatever the faulting instruction did before it faulted, so that the emulator is
+            // left in the state just before it
+            if let Some(history) = &mut self.history {
+                history.undo(&mut self.emulator);
+            }
+            if let Some(gdb) = &mut self.gdb {
+                gdb.fault(fault);
+            }
+            self.fault = Some(fault);
+            return;
+        }
+
+        self.report_unimplemented();
+        let frame = self.emulator.ticks;
+        let code_write = match &mut self.code_writes {
+            Some(code_writes) => code_writes.record(frame, opcode, &before).copied(),
+            None => None,
+        };
+        if let Some(write) = code_write.filter(|write| write.count == 1) {
+            self.log(format!("Self-modifying code in frame {}: {}", frame, write));
+        }
+        if let Some(tracer) = &mut self.tracer {
+            let after = self.emulator.cpu.registers();
+            tracer.record(frame, opcode, &before, &after, code_write.as_ref());
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(opcode, &before, depth, &self.emulator);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(opcode, before.pc, self.emulator.cpu.registers().pc);
+        }
+    }
+
+    fn report_unimplemented(&mut self) {
+        if let Some(opcode) = self.emulator.mem.unimplemented.take() {
+            self.log(format!("Unimplemented opcode: {:04x}", opcode));
+        }
+    }
+
+    fn tick(&mut self) {
+        let frame = self.emulator.ticks;
+        self.apply_cheats(false);
+
+        if let Some(video) = &mut self.video {
+            video.frame(self.emulator.display());
+        }
+
+        let input = std::mem::take(&mut self.pending);
+        match &mut self.player {
+            Some(player) => {
+                for event in player.events(frame) {
+                    apply(&mut self.emulator, event.key, event.pressed);
+                    self.inputs.push(*event);
+                }
+                if player.finished(frame) {
+                    self.player = None;
+                    self.log(format!("Movie playback finished at frame {}", frame));
+                }
+            }
+            None => {
+                for (key, pressed) in input {
+                    apply(&mut self.emulator, key, pressed);
+                    let event = InputEvent { frame, key, pressed };
+                    self.inputs.push(event);
+                    if let Some(recorder) = &mut self.recorder {
+                        recorder.record(event);
+                    }
+                }
+            }
+        }
+
+        self.emulator.tick();
+    }
+
+    /// Stop the session, finishing any recording in progress
+    pub fn finish(&mut self) {
+        self.stop_video();
+        if let Some(tracer) = self.tracer.take() {
+            tracer.finish();
+        }
+        if let Some(profiler) = self.profiler.take() {
+            profiler.finish();
+        }
+        if let Some(coverage) = self.coverage.take() {
+            coverage.finish();
+        }
+        if let Some(recorder) = self.recorder.take() {
+            recorder.finish(self.emulator.ticks);
+        }
+    }
+}
+
+/// The number of executed instructions shown in a fault report
+const REPORT_STEPS: usize = 16;
+
+/// What is carried over when a program is reloaded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reload {
+    /// Nothing, the new program starts from the beginning
+    Reset,
+    /// The CPU registers and the stack
+    Registers,
+    /// The input applied so far, which is replayed to bring the new program up to the same frame
+    Input,
+}
+
+/// Watchers for the files a ROM is loaded from: the ROM and its patch, if any
+fn watch_files(rom: &Rom) -> Vec<Watcher> {
+    std::iter::once(&rom.path).chain(&rom.patch).map(|path| Watcher::new(path)).collect()
+}
+
+fn apply(emulator: &mut chip8::Emulator, key: u8, pressed: bool) {
+    match pressed {
+        true => emulator.keydown(key),
+        false => emulator.keyup(key),
+    }
+}
+
+enum TimeEvent {
+    Tick,
+    Cycle,
+    None,
+}
+
+/// Schedules timer ticks and CPU cycles. Time is kept as an integer number of units of
+/// `1 / (TICK_HZ * CLOCK_HZ)` seconds, so that both events fall exactly on unit boundaries and the
+/// order they are emitted in doesn't depend on how the elapsed time was split between calls.
+#[derive(Default)]
+struct Timers {
+    now: u64,
+    tick: u64,
+    cycle: u64,
+
+    /// The part of the elapsed time that is smaller than a single unit
+    remainder: f64,
+}
+
+impl Timers {
+    const UNITS_PER_SECOND: u64 = chip8::TICK_HZ * chip8::CLOCK_HZ;
+    const TICK_PERIOD: u64 = Timers::UNITS_PER_SECOND / chip8::TICK_HZ;
+    const CYCLE_PERIOD: u64 = Timers::UNITS_PER_SECOND / chip8::CLOCK_HZ;
+
+    pub fn next(&mut self) -> TimeEvent {
+        if self.tick < self.cycle && self.tick < self.now {
+            self.tick += Timers::TICK_PERIOD;
+            TimeEvent::Tick
+        }
+        else if self.cycle < self.now {
+            self.cycle += Timers::CYCLE_PERIOD;
+            TimeEvent::Cycle
+        }
+        else {
+            TimeEvent::None
+        }
+    }
+
+    /// Take the cycles due before the next tick, returning how many there were
+    pub fn take_cycles(&mut self) -> u64 {
+        let mut cycles = 0;
+        while self.cycle < self.now && !(self.tick < self.cycle && self.tick < self.now) {
+            self.cycle += Timers::CYCLE_PERIOD;
+            cycles += 1;
+        }
+        cycles
+    }
+
+    pub fn elapsed(&mut self, time: f64) {
+        let units = time * Timers::UNITS_PER_SECOND as f64 + self.remainder;
+        self.now += units as u64;
+        self.remainder = units.fract();
+    }
+}
+
+#[test]
+fn test_timers_split_independent() {
+    fn events(steps: &[f64]) -> Vec<bool> {
+        let mut timers = Timers::default();
+        let mut out = vec![];
+        for &step in steps {
+            timers.elapsed(step);
+            loop {
+                match timers.next() {
+                    TimeEvent::Tick => out.push(true),
+                    TimeEvent::Cycle => out.push(false),
+                    TimeEvent::None => break,
+                }
+            }
+        }
+        out
+    }
+
+    let whole = events(&[0.5]);
+    let mut steps = vec![0.0123; 40];
+    steps.push(0.5 - 0.0123 * 40.0);
+    let split = events(&steps);
+    assert_eq!(whole.len(), split.len());
+    assert_eq!(whole, split);
+    assert_eq!(whole.iter().filter(|&&tick| tick).count(), 30);
+}
+
+#[test]
+fn test_movie_playback_is_deterministic() {
+    use crate::movie::Movie;
+
+    // Wait for a key, then draw a random number of pixels based on it
+    let program = [0xF0, 0x0A, 0xC1, 0xFF, 0xA2, 0x10, 0xD0, 0x11, 0x12, 0x00];
+    let run = |seed: u64, movie: Option<Movie>| {
+        let mut data = vec![0; 0x11];
+        data[..program.len()].copy_from_slice(&program);
+        data[0x10] = 0xFF;
+
+        let rom = crate::rom::test_rom(data);
+        let mut session = Session::new(rom, seed);
+        let live = movie.is_none();
+        if let Some(movie) = movie {
+            session.play(Player::new(movie));
+        }
+
+        session.run(0.1);
+        if live {
+            session.keydown(0x5);
+        }
+        session.run(0.05);
+        if live {
+            session.keyup(0x5);
+        }
+        session.run(0.5);
+        (session.emulator.display().to_vec(), session.emulator.mem.ram)
+    };
+
+    let movie = Movie {
+        rom_hash: 0,
+        seed: 7,
+        random_ram: false,
+        cheats: vec![],
+        events: vec![InputEvent { frame: 6, key: 0x5, pressed: true }, InputEvent {
+            frame: 9,
+            key: 0x5,
+            pressed: false,
+        }],
+        end: None,
+    };
+    assert_eq!(run(7, Some(movie)), run(7, None));
+}
+
+#[test]
+fn test_reload_replays_input() {
+    // Wait for a key, then draw a random number of pixels based on it
+    let program = vec![0xF0, 0x0A, 0xC1, 0xFF, 0xA2, 0x10, 0xD0, 0x11, 0x12, 0x00];
+    let rom = crate::rom::test_rom(program.clone());
+    let mut session = Session::new(rom, 3);
+
+    session.run(0.1);
+    session.keydown(0x5);
+    session.run(0.05);
+    session.keyup(0x5);
+    session.run(0.5);
+    let display = session.emulator.display().to_vec();
+    let registers = session.emulator.cpu.registers();
+
+    session.reload(crate::rom::test_rom(program.clone()), Reload::Input);
+    assert_eq!(session.emulator.display(), display);
+    assert_eq!(session.emulator.cpu.registers(), registers);
+
+    session.reload(crate::rom::test_rom(program), Reload::Registers);
+    assert_eq!(session.emulator.cpu.registers(), registers);
+    assert_eq!(session.emulator.ticks, 0);
+}
+
+#[test]
+fn test_fault_and_step_back() {
+    // Call a subroutine, then return from the top level
+    let program = vec![0x60, 0x01, 0x22, 0x06, 0x00, 0xEE, 0x71, 0x01, 0x00, 0xEE];
+    let mut session = Session::new(crate::rom::test_rom(program), 0);
+    session.keep_history(100);
+
+    session.run(0.1);
+    assert_eq!(session.fault(), Some(chip8::Error::StackUnderflow));
+    assert_eq!(session.emulator.cpu.registers().pc, 0x204);
+    let report = session.fault_report().unwrap();
+    assert!(report.starts_with("Stack underflow at 0x204 in frame 1\n"), "{}", report);
+    assert!(report.ends_with("  1 208 00ee Return\nStack: []"), "{}", report);
+
+    // The program stays stopped until the fault is cleared
+    session.run(0.1);
+    assert_eq!(session.emulator.cpu.registers().pc, 0x204);
+
+    assert!(session.step_back());
+    assert_eq!(session.fault(), None);
+    assert_eq!(session.emulator.cpu.registers().pc, 0x208);
+    assert_eq!(session.emulator.mem.stack, [0x204]);
+    assert!(session.step_back());
+    assert!(session.step_back());
+    assert_eq!(session.emulator.cpu.registers().pc, 0x202);
+    assert_eq!(session.emulator.cpu.registers().v[0], 1);
+
+    session.step();
+    assert_eq!(session.emulator.cpu.registers().pc, 0x206);
+    session.power_cycle();
+    assert!(!session.step_back());
+}
+
+#[test]
+fn test_cheats() {
+    use crate::cheat::Kind;
+
+    // Count V0 up and store it to 0x300 and 0x301 forever
+    let program = vec![0x70, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0xA3, 0x01, 0xF0, 0x55, 0x12, 0x00];
+    let rom = crate::rom::test_rom(program);
+    let mut session = Session::new(rom, 0);
+    let cheat = |addr, kind| Cheat { addr, value: 0x42, kind, enabled: true, name: String::new() };
+    session.set_cheats(vec![cheat(0x300, Kind::Freeze), cheat(0x310, Kind::Poke)]);
+    assert_eq!(session.emulator.mem.read_byte(0x310), Ok(0x42));
+
+    // The freeze is written at the start of each frame, after which the program overwrites it
+    session.run_frame();
+    session.run_frame();
+    assert_ne!(session.emulator.mem.read_byte(0x300), Ok(0x42));
+    session.tick();
+    assert_eq!(session.emulator.mem.read_byte(0x300), Ok(0x42));
+
+    session.toggle_cheat(0);
+    session.emulator.mem.write_byte(0x310, 0).unwrap();
+    session.run_frame();
+    assert_ne!(session.emulator.mem.read_byte(0x300), Ok(0x42));
+    session.power_cycle();
+    assert_eq!(session.emulator.mem.read_byte(0x310), Ok(0x42));
+
+    // Reloading a new version of the program keeps them
+    session.reload(crate::rom::test_rom(vec![0x12, 0x00]), Reload::Reset);
+    assert_eq!(session.cheats().len(), 2);
+    assert_eq!(session.emulator.mem.read_byte(0x310), Ok(0x42));
+}
+
+#[test]
+fn test_fast() {
+    // Wait for the delay timer, count in V2, draw the glyph of a key, and fault by returning from
+    // the program after 5 rounds
+    let program = [
+        0x60, 0x05, 0xF0, 0x15, 0xF1, 0x07, 0x31, 0x00, 0x12, 0x04, 0x72, 0x01, 0xF3, 0x0A, 0xF3,
+        0x29, 0xD2, 0x35, 0x32, 0x05, 0x12, 0x00, 0x00, 0xEE,
+    ];
+    let run = |fast: bool| {
+        let rom = crate::rom::test_rom(program.to_vec());
+        let mut session = Session::new(rom, 3);
+        if fast {
+            session.run_fast();
+        }
+        for i in 0..60 {
+            session.run(0.0337);
+            match i % 3 {
+                0 => session.keydown(i % 16),
+                _ => session.keyup(i % 16),
+            }
+        }
+        let emulator = &session.emulator;
+        let registers = emulator.cpu.registers();
+        (registers, emulator.mem.ram, emulator.display().to_vec(), emulator.ticks, session.fault)
+    };
+
+    let normal = run(false);
+    assert_eq!(normal.4, Some(chip8::Error::StackUnderflow));
+    assert!(run(true) == normal);
+}
+
+#[test]
+fn test_messages() {
+    // Execute an unimplemented opcode, then loop
+    let program = vec![0xF0, 0x75, 0x12, 0x02];
+    let rom = crate::rom::test_rom(program);
+    let mut session = Session::new(rom, 0);
+    assert!(session.take_messages().is_empty());
+
+    session.step();
+    assert_eq!(session.take_messages(), ["Unimplemented opcode: f075"]);
+    session.run(0.1);
+    assert!(session.take_messages().is_empty());
+
+    // Reloading stops recording
+    let path = std::env::temp_dir().join(format!("chip8_emu_test_{}.movie", std::process::id()));
+    session.record(Recorder::create(&path, 0, 0, false, &[]).unwrap());
+    session.reload(crate::rom::test_rom(vec![0x12, 0x00]), Reload::Reset);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(session.take_messages(), [
+        "Stopped recording movie",
+        "Warning: reloading the ROM ended the movie recording"
+    ]);
+}
+
+#[test]
+fn test_movie_random_ram() {
+    let mut session = Session::new(crate::rom::test_rom(vec![0x12, 0x00]), 5);
+    let movie =
+        Movie { rom_hash: 0, seed: 5, random_ram: true, cheats: vec![], events: vec![], end: None };
+    session.play(Player::new(movie));
+    assert!(session.emulator.mem.ram[2..].iter().any(|&byte| byte != 0));
+    assert!(session.player.is_some());
+}
+
+#[test]
+fn test_movie_cheats() {
+    use crate::cheat::Kind;
+
+    let mut session = Session::new(crate::rom::test_rom(vec![0x12, 0x00]), 5);
+    let poke = Cheat { addr: 0x310, value: 0x42, kind: Kind::Poke, enabled: true, name: "".into() };
+    let movie = Movie {
+        rom_hash: 0,
+        seed: 5,
+        random_ram: false,
+        cheats: vec![poke],
+        events: vec![],
+        end: None,
+    };
+    session.play(Player::new(movie));
+    assert_eq!(session.emulator.mem.read_byte(0x310), Ok(0x42));
+    assert!(session.player.is_some());
+
+    // The movie can't represent cheats changing while it plays
+    session.toggle_cheat(0);
+    assert!(session.player.is_none());
+    assert_eq!(session.take_messages(), ["Cheats changed, stopping the movie"]);
+}
+
+#[test]
+fn test_code_writes() {
+    // Store V0 into the jump at 0x20a, then its BCD over the first instructions
+    let program = vec![0xA2, 0x0A, 0x60, 0x13, 0xF0, 0x55, 0xA2, 0x00, 0xF0, 0x33, 0x12, 0x0A];
+    let mut session = Session::new(crate::rom::test_rom(program), 0);
+    for _ in 0..5 {
+        session.step();
+    }
+    assert!(session.code_writes().is_none());
+    assert!(session.take_messages().is_empty());
+
+    session.detect_code_writes();
+    session.power_cycle();
+    for _ in 0..5 {
+        session.step();
+    }
+    assert_eq!(session.code_writes().map(BTreeMap::len), Some(2));
+    assert_eq!(session.take_messages(), [
+        "Self-modifying code in frame 0: 204 wrote 20a-20a, which is reachable",
+        "Self-modifying code in frame 0: 208 wrote 200-202, which has been executed"
+    ]);
+
+    // Powering on again forgets the writes
+    session.power_cycle();
+    assert_eq!(session.code_writes().map(BTreeMap::len), Some(0));
+}
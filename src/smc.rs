@@ -0,0 +1,128 @@
+//! Detects self-modifying code: `StoreBytes` and `StoreBcd` instructions writing to addresses that
+//! have been executed, or that analysis of the ROM found to be reachable.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    analysis::Analysis,
+    chip8::{
+        self,
+        cpu::{Operation, Registers},
+        mem::TOTAL_MEMORY,
+    },
+};
+
+/// The latest write into code by an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CodeWrite {
+    /// The address of the writing instruction
+    pub pc: u16,
+    /// The first address written, and the number of bytes
+    pub addr: u16,
+    pub len: u16,
+    pub frame: u64,
+    /// Whether any of the bytes had been executed, rather than only being reachable
+    pub executed: bool,
+    /// The number of times the instruction has written into code
+    pub count: u64,
+}
+
+impl fmt::Display for CodeWrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = match self.executed {
+            true => "which has been executed",
+            false => "which is reachable",
+        };
+        let end = self.addr + self.len - 1;
+        write!(f, "{:03x} wrote {:03x}-{:03x}, {}", self.pc, self.addr, end, code)
+    }
+}
+
+pub struct Detector {
+    /// Whether each address has been executed, as either byte of an instruction
+    executed: Vec<bool>,
+    /// Whether each address is part of an instruction reachable from the entry point
+    reachable: Vec<bool>,
+    /// The writes into code so far, by the address of the writing instruction
+    pub writes: BTreeMap<u16, CodeWrite>,
+}
+
+impl Detector {
+    pub fn new(rom: &[u8]) -> Detector {
+        let mut reachable = vec![false; TOTAL_MEMORY as usize];
+        for &addr in Analysis::new(rom).instructions.keys() {
+            for byte in [addr, addr.wrapping_add(1)] {
+                if let Some(reachable) = reachable.get_mut(byte as usize) {
+                    *reachable = true;
+                }
+            }
+        }
+        Detector {
+            executed: vec![false; TOTAL_MEMORY as usize],
+            reachable,
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Forget what was executed and written, when the emulator is powered on again. What is
+    /// reachable only depends on the ROM.
+    pub fn clear(&mut self) {
+        self.executed.fill(false);
+        self.writes.clear();
+    }
+
+    /// Record an executed instruction, given the registers before it. Returns the write if it
+    /// wrote into code.
+    pub fn record(&mut self, frame: u64, opcode: u16, before: &Registers) -> Option<&CodeWrite> {
+        let pc = before.pc;
+        for byte in [pc, pc.wrapping_add(1)] {
+            if let Some(executed) = self.executed.get_mut(byte as usize) {
+                *executed = true;
+            }
+        }
+
+        let len = match chip8::try_decode(opcode) {
+            Ok(Operation::StoreBytes(r)) => r as u16 + 1,
+            Ok(Operation::StoreBcd(_)) => 3,
+            _ => return None,
+        };
+        // The write would have faulted past the end of memory
+        let range = before.i as usize..before.i as usize + len as usize;
+        let executed = self.executed.get(range.clone())?.contains(&true);
+        if !executed && !self.reachable[range].contains(&true) {
+            return None;
+        }
+
+        let count = self.writes.get(&pc).map_or(0, |write| write.count) + 1;
+        let write = CodeWrite { pc, addr: before.i, len, frame, executed, count };
+        Some(self.writes.entry(pc).insert_entry(write).into_mut())
+    }
+}
+
+#[test]
+fn test_detector() {
+    // Store V0 into the jump at 0x20a before it is executed, then its BCD over the first
+    // instructions
+    let program = [0xA2, 0x0A, 0x60, 0x13, 0xF0, 0x55, 0xA2, 0x00, 0xF0, 0x33, 0x12, 0x0A];
+    let mut emulator = chip8::Emulator::new(0);
+    emulator.load(&program);
+    let mut detector = Detector::new(&program);
+    let mut writes = vec![];
+    for _ in 0..5 {
+        let before = emulator.cpu.registers();
+        let opcode = emulator.mem.read_word(before.pc).unwrap();
+        emulator.frame().unwrap();
+        writes.extend(detector.record(0, opcode, &before).map(|write| write.to_string()));
+    }
+    assert_eq!(writes, [
+        "204 wrote 20a-20a, which is reachable",
+        "208 wrote 200-202, which has been executed"
+    ]);
+    assert_eq!(emulator.mem.read_word(0x20A).unwrap(), 0x130A);
+
+    // Data isn't code
+    let before = Registers { v: [0; 16], i: 0x300, pc: 0x204, delay: 0, sound: 0 };
+    assert_eq!(detector.record(1, 0xF055, &before), None);
+    let before = Registers { i: 0x20A, ..before };
+    assert_eq!(detector.record(1, 0xF055, &before).map(|write| write.count), Some(2));
+}
@@ -0,0 +1,256 @@
+//! Logs executed instructions, for comparing the emulator's behavior against other emulators.
+//!
+//! The text format has one line per instruction:
+//!
+//! ```text
+//! 12 206 d015 Draw V0, V1, 5         I=300 VF=01  VF:00>01
+//! ```
+//!
+//! The line starts with the frame number, the address and raw opcode of the instruction, and the
+//! decoded operation. It is followed by the values of I and VF after the instruction was executed,
+//! and the registers the instruction changed.
+//!
+//! Files ending in `.bin` use a binary format instead: the magic bytes `C8TRACE1` followed by a
+//! 36 byte little endian record per instruction:
+//!
+//! ```text
+//! frame: u64, pc: u16, opcode: u16, changed: u32, V0-VF: [u8; 16], I: u16, delay: u8, sound: u8
+//! ```
+//!
+//! The register values are those after the instruction was executed, and `changed` has a bit set
+//! for each register the instruction changed: bits 0-15 for V0-VF, then I, delay and sound.
+//!
+//! In the text format, instructions writing into code (see `smc`) are followed by a comment:
+//!
+//! ```text
+//! 12 208 f033 StoreBcd V0            I=200 VF=00
+//! # 208 wrote 200-202, which has been executed
+//! ```
+
+use std::{
+    fs,
+    io::{self, Write},
+    ops::RangeInclusive,
+    path::Path,
+};
+
+use crate::{
+    chip8::{
+        self,
+        cpu::{Operation, Registers, CLASSES},
+    },
+    smc::CodeWrite,
+};
+
+const MAGIC: &[u8; 8] = b"C8TRACE1";
+
+/// Selects the instructions that are logged. Instructions are logged if they match all filters.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    pub addresses: Option<RangeInclusive<u16>>,
+    pub frames: Option<RangeInclusive<u64>>,
+    /// The classes of operations to log (see `cpu::CLASSES`), or all if empty
+    pub classes: Vec<&'static str>,
+}
+
+impl Filter {
+    pub fn matches(&self, frame: u64, pc: u16, op: &Operation) -> bool {
+        self.addresses.as_ref().is_none_or(|range| range.contains(&pc))
+            && self.frames.as_ref().is_none_or(|range| range.contains(&frame))
+            && (self.classes.is_empty() || self.classes.contains(&op.class()))
+    }
+}
+
+/// Parse a range of addresses in hex, e.g. `200-2ff`, `0x300-` or `0x2a0`
+pub fn parse_addresses(value: &str) -> Option<RangeInclusive<u16>> {
+    parse_range(value, u16::MAX, |s| u16::from_str_radix(s.trim_start_matches("0x"), 16).ok())
+}
+
+/// Parse a range of frames, e.g. `60-120`, `600-` or `60`
+pub fn parse_frames(value: &str) -> Option<RangeInclusive<u64>> {
+    parse_range(value, u64::MAX, |s| s.parse().ok())
+}
+
+fn parse_range<T: Copy + PartialOrd>(
+    value: &str,
+    max: T,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Option<RangeInclusive<T>> {
+    let (start, end) = match value.split_once('-') {
+        Some((start, "")) => (parse(start)?, max),
+        Some((start, end)) => (parse(start)?, parse(end)?),
+        None => (parse(value)?, parse(value)?),
+    };
+    (start <= end).then_some(start..=end)
+}
+
+/// Parse a comma separated list of operation classes
+pub fn parse_classes(value: &str) -> Option<Vec<&'static str>> {
+    value.split(',').map(|class| CLASSES.iter().find(|&&c| c == class.trim()).copied()).collect()
+}
+
+pub struct Tracer {
+    out: io::BufWriter<fs::File>,
+    binary: bool,
+    filter: Filter,
+    /// Set after a write fails, so that the error is only reported once
+    failed: bool,
+}
+
+impl Tracer {
+    /// Start a trace, in the binary format if `path` ends in `.bin` and in text otherwise
+    pub fn create(path: &Path, filter: Filter) -> Result<Tracer, String> {
+        let file = fs::File::create(path)
+            .map_err(|e| format!("Failed to create trace {}: {}", path.display(), e))?;
+        let binary = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("bin"));
+
+        let mut tracer = Tracer { out: io::BufWriter::new(file), binary, filter, failed: false };
+        if binary {
+            tracer.write(MAGIC);
+        }
+        Ok(tracer)
+    }
+
+    /// Log an instruction, given the registers before and after it was executed and its write
+    /// into code, if any
+    pub fn record(
+        &mut self,
+        frame: u64,
+        opcode: u16,
+        before: &Registers,
+        after: &Registers,
+        code_write: Option<&CodeWrite>,
+    ) {
+        let Ok(op) = chip8::try_decode(opcode)
+        else {
+            return;
+        };
+        if self.failed || !self.filter.matches(frame, before.pc, &op) {
+            return;
+        }
+
+        match self.binary {
+            true => self.write(&binary_record(frame, opcode, before, after)),
+            false => {
+                let mut line = text_line(frame, opcode, &op, before, after);
+                if let Some(write) = code_write {
+                    line.push_str(&format!("# {}\n", write));
+                }
+                self.write(line.as_bytes())
+            }
+        }
+    }
+
+    pub fn finish(mut self) {
+        if let Err(e) = self.out.flush() {
+            eprintln!("Failed to write trace: {}", e);
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        if let Err(e) = self.out.write_all(data) {
+            eprintln!("Failed to write trace: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+/// The registers as a list of `(name, value)` pairs, in the order of the bits of the `changed` mask
+fn named_registers(registers: &Registers) -> Vec<(String, u16)> {
+    let mut named: Vec<_> =
+        registers.v.iter().enumerate().map(|(i, &v)| (format!("V{:X}", i), v as u16)).collect();
+    named.push(("I".into(), registers.i));
+    named.push(("DT".into(), registers.delay as u16));
+    named.push(("ST".into(), registers.sound as u16));
+    named
+}
+
+fn text_line(
+    frame: u64,
+    opcode: u16,
+    op: &Operation,
+    before: &Registers,
+    after: &Registers,
+) -> String {
+    let mut line = format!(
+        "{} {:03x} {:04x} {:<22} I={:03x} VF={:02x}",
+        frame,
+        before.pc,
+        opcode,
+        op.to_string(),
+        after.i,
+        after.v[0xF]
+    );
+
+    let changes = named_registers(before).into_iter().zip(named_registers(after));
+    for (i, ((name, old), (_, new))) in
+        changes.filter(|((_, old), (_, new))| old != new).enumerate()
+    {
+        let width = if name == "I" { 3 } else { 2 };
+        let separator = if i == 0 { "  " } else { " " };
+        line.push_str(&format!("{}{}:{:0w$x}>{:0w$x}", separator, name, old, new, w = width));
+    }
+    line.push('\n');
+    line
+}
+
+fn binary_record(frame: u64, opcode: u16, before: &Registers, after: &Registers) -> Vec<u8> {
+    let changed = named_registers(before)
+        .into_iter()
+        .zip(named_registers(after))
+        .enumerate()
+        .filter(|(_, ((_, old), (_, new)))| old != new)
+        .fold(0_u32, |mask, (bit, _)| mask | 1 << bit);
+
+    let mut record = Vec::with_capacity(36);
+    record.extend_from_slice(&frame.to_le_bytes());
+    record.extend_from_slice(&before.pc.to_le_bytes());
+    record.extend_from_slice(&opcode.to_le_bytes());
+    record.extend_from_slice(&changed.to_le_bytes());
+    record.extend_from_slice(&after.v);
+    record.extend_from_slice(&after.i.to_le_bytes());
+    record.push(after.delay);
+    record.push(after.sound);
+    record
+}
+
+#[test]
+fn test_filter() {
+    assert_eq!(parse_addresses("200-2ff"), Some(0x200..=0x2FF));
+    assert_eq!(parse_addresses("0x300-"), Some(0x300..=0xFFFF));
+    assert_eq!(parse_addresses("0x2A0"), Some(0x2A0..=0x2A0));
+    assert_eq!(parse_addresses("300-200"), None);
+    assert_eq!(parse_frames("60-120"), Some(60..=120));
+    assert_eq!(parse_frames("x"), None);
+    assert_eq!(parse_classes("memory, arithmetic"), Some(vec!["memory", "arithmetic"]));
+    assert_eq!(parse_classes("memory,bogus"), None);
+
+    let filter = Filter {
+        addresses: parse_addresses("200-2ff"),
+        frames: parse_frames("10-"),
+        classes: vec!["special"],
+    };
+    let draw = chip8::decode(0xD015);
+    assert!(filter.matches(10, 0x200, &draw));
+    assert!(!filter.matches(9, 0x200, &draw));
+    assert!(!filter.matches(10, 0x300, &draw));
+    assert!(!filter.matches(10, 0x200, &chip8::decode(0x1200)));
+    assert!(Filter::default().matches(0, 0, &draw));
+}
+
+#[test]
+fn test_trace_formats() {
+    let before = Registers { v: [0; 16], i: 0x300, pc: 0x206, delay: 0, sound: 0 };
+    let mut after = Registers { pc: 0x208, ..before.clone() };
+    after.v[0xF] = 1;
+
+    let line = text_line(12, 0xD015, &chip8::decode(0xD015), &before, &after);
+    assert_eq!(line, "12 206 d015 Draw V0, V1, 5         I=300 VF=01  VF:00>01\n");
+
+    let record = binary_record(12, 0xD015, &before, &after);
+    assert_eq!(record.len(), 36);
+    assert_eq!(record[..12], [12, 0, 0, 0, 0, 0, 0, 0, 0x06, 0x02, 0x15, 0xD0]);
+    assert_eq!(record[12..16], (1_u32 << 15).to_le_bytes());
+    assert_eq!(record[31], 1);
+    assert_eq!(record[32..34], [0x00, 0x03]);
+}
@@ -0,0 +1,372 @@
+//! Runs the emulator in a terminal, for play-testing over SSH on machines without a display.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute, queue, terminal,
+};
+
+use crate::{
+    chip8::{
+        self,
+        video::{HEIGHT, WIDTH},
+    },
+    display::{self, Palette, Rgba},
+    session::Session,
+};
+
+/// How long a key stays pressed after the last press or repeat reported by the terminal, when the
+/// terminal doesn't report key releases. This needs to cover the gap between key repeats.
+const HOLD_TIME: Duration = Duration::from_millis(200);
+
+/// How long messages from the session stay in the status line
+const MESSAGE_TIME: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Two pixels per character using `▀`, in full color
+    HalfBlock,
+    /// 2x4 pixels per character using braille patterns, in two colors
+    Braille,
+}
+
+pub fn run(mut session: Session, palette: Palette, mode: Mode) -> Result<(), String> {
+    let terminal = Terminal::enter().map_err(|e| format!("Failed to set up terminal: {}", e))?;
+    let hold = if terminal.reports_releases { None } else { Some(HOLD_TIME) };
+
+    let result = run_loop(&mut session, palette, mode, HeldKeys::new(hold));
+    // Leave the alternate screen first, so that messages about saved files stay visible
+    drop(terminal);
+    session.finish();
+    result.map_err(|e| format!("Terminal error: {}", e))?;
+
+    match session.fault_report() {
+        Some(report) => Err(report),
+        None => Ok(()),
+    }
+}
+
+fn run_loop(
+    session: &mut Session,
+    mut palette: Palette,
+    mode: Mode,
+    mut held: HeldKeys,
+) -> io::Result<()> {
+    let frame = Duration::from_secs(1) / chip8::TICK_HZ as u32;
+    let mut last = Instant::now();
+    let mut redraw = true;
+    let mut faulted = false;
+    let mut message: Option<(String, Instant)> = None;
+
+    loop {
+        while event::poll(frame.saturating_sub(last.elapsed()))? {
+            match event::read()? {
+                Event::Key(KeyEvent { code: KeyCode::Esc, .. }) => return Ok(()),
+                Event::Key(KeyEvent { code: KeyCode::Char('c'), modifiers, .. })
+                    if modifiers.contains(KeyModifiers::CONTROL) =>
+                {
+                    return Ok(())
+                }
+                Event::Key(KeyEvent { code: KeyCode::F(5), kind: KeyEventKind::Press, .. }) => {
+                    session.reset();
+                }
+                Event::Key(KeyEvent { code: KeyCode::F(6), kind: KeyEventKind::Press, .. }) => {
+                    session.power_cycle();
+                }
+                Event::Key(KeyEvent { code: KeyCode::F(7), kind: KeyEventKind::Press, .. }) => {
+                    palette = palette.next_theme();
+                    redraw = true;
+                }
+                Event::Key(KeyEvent { code: KeyCode::Char(c), kind, .. }) => {
+                    let Some(key) = convert_key(c)
+                    else {
+                        continue;
+                    };
+                    match kind {
+                        KeyEventKind::Press | KeyEventKind::Repeat => {
+                            if held.press(key, Instant::now()) {
+                                session.keydown(key);
+                            }
+                        }
+                        KeyEventKind::Release => {
+                            if held.release(key) {
+                                session.keyup(key);
+                            }
+                        }
+                    }
+                }
+                Event::Resize(..) => redraw = true,
+                _ => {}
+            }
+        }
+
+        for key in held.expire(Instant::now()) {
+            session.keyup(key);
+        }
+
+        // The same real time loop as the windowed client, at the terminal's frame rate
+        let now = Instant::now();
+        session.run((now - last).as_secs_f64());
+        last = now;
+
+        // The status line shows the fault that stopped the program, until it is reset
+        if session.fault().is_some() != faulted {
+            faulted = session.fault().is_some();
+            redraw = true;
+        }
+        // Otherwise it shows the latest message for a while, as anything printed would end up
+        // drawn over the display
+        if let Some(latest) = session.take_messages().pop() {
+            message = Some((latest, now));
+            redraw = true;
+        }
+        if message.as_ref().is_some_and(|(_, shown)| now - *shown > MESSAGE_TIME) {
+            message = None;
+            redraw = true;
+        }
+
+        if session.emulator.poll_screen() || redraw {
+            let status = match session.fault() {
+                Some(fault) => format!(
+                    "{} at {:#05x}  Esc: quit  F5: reset  F6: power cycle",
+                    fault,
+                    session.emulator.cpu.registers().pc
+                ),
+                None => match &message {
+                    Some((message, _)) => message.clone(),
+                    None => HELP.into(),
+                },
+            };
+            draw(&render(session.emulator.display(), &palette, mode), &status, redraw)?;
+            redraw = false;
+        }
+    }
+}
+
+/// Puts the terminal into raw mode on an alternate screen, restoring it when dropped (including
+/// when the emulator panics)
+struct Terminal {
+    reports_releases: bool,
+}
+
+impl Terminal {
+    fn enter() -> io::Result<Terminal> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)?;
+
+        // Only some terminals (those supporting the kitty keyboard protocol) report key releases
+        let reports_releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if reports_releases {
+            execute!(
+                io::stdout(),
+                event::PushKeyboardEnhancementFlags(
+                    event::KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+                )
+            )?;
+        }
+
+        Ok(Terminal { reports_releases })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        if self.reports_releases {
+            let _ = execute!(stdout, event::PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+const HELP: &str = "Esc: quit  F5: reset  F6: power cycle  F7: palette  Keys: 1234 QWER ASDF ZXCV";
+
+fn draw(rows: &[String], status: &str, clear: bool) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    if clear {
+        queue!(stdout, terminal::Clear(terminal::ClearType::All))?;
+    }
+    for (y, row) in rows.iter().enumerate() {
+        queue!(stdout, cursor::MoveTo(0, y as u16))?;
+        stdout.write_all(row.as_bytes())?;
+    }
+    queue!(
+        stdout,
+        cursor::MoveTo(0, rows.len() as u16),
+        terminal::Clear(terminal::ClearType::CurrentLine)
+    )?;
+    stdout.write_all(status.as_bytes())?;
+    stdout.flush()
+}
+
+/// Render the display into lines of text with ANSI color escapes
+fn render(display: &[u8], palette: &Palette, mode: Mode) -> Vec<String> {
+    let pixels = display::to_indexed(display, 1);
+    let pixel = |x: usize, y: usize| pixels[y * WIDTH as usize + x] as usize;
+
+    let mut rows = vec![];
+    match mode {
+        Mode::HalfBlock => {
+            for y in (0..HEIGHT as usize).step_by(2) {
+                let mut row = String::new();
+                let mut colors = None;
+                for x in 0..WIDTH as usize {
+                    let (top, bottom) = (pixel(x, y), pixel(x, y + 1));
+                    if colors != Some((top, bottom)) {
+                        set_colors(&mut row, palette.colors[top], palette.colors[bottom]);
+                        colors = Some((top, bottom));
+                    }
+                    row.push(if top == bottom { ' ' } else { '▀' });
+                }
+                row.push_str("\x1b[0m");
+                rows.push(row);
+            }
+        }
+        Mode::Braille => {
+            for y in (0..HEIGHT as usize).step_by(4) {
+                let mut row = String::new();
+                set_colors(&mut row, palette.colors[1], palette.colors[0]);
+                for x in (0..WIDTH as usize).step_by(2) {
+                    row.push(braille(|dx, dy| pixel(x + dx, y + dy) != 0));
+                }
+                row.push_str("\x1b[0m");
+                rows.push(row);
+            }
+        }
+    }
+    rows
+}
+
+fn set_colors(out: &mut String, [fr, fg, fb, _]: Rgba, [br, bg, bb, _]: Rgba) {
+    let _ = write!(out, "\x1b[38;2;{};{};{};48;2;{};{};{}m", fr, fg, fb, br, bg, bb);
+}
+
+/// The braille pattern for a 2x4 block of pixels
+fn braille(is_set: impl Fn(usize, usize) -> bool) -> char {
+    // Dots 1-6 are numbered down the left then the right column, dots 7 and 8 are the bottom row
+    const DOTS: [(usize, usize); 8] =
+        [(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (1, 2), (0, 3), (1, 3)];
+
+    let mut pattern = 0;
+    for (bit, &(dx, dy)) in DOTS.iter().enumerate() {
+        if is_set(dx, dy) {
+            pattern |= 1 << bit;
+        }
+    }
+    char::from_u32(0x2800 + pattern).unwrap()
+}
+
+fn convert_key(c: char) -> Option<u8> {
+    // Same layout as the windowed client
+    match c.to_ascii_lowercase() {
+        '1' => Some(0x1),
+        '2' => Some(0x2),
+        '3' => Some(0x3),
+        '4' => Some(0xC),
+        'q' => Some(0x4),
+        'w' => Some(0x5),
+        'e' => Some(0x6),
+        'r' => Some(0xD),
+        'a' => Some(0x7),
+        's' => Some(0x8),
+        'd' => Some(0x9),
+        'f' => Some(0xE),
+        'z' => Some(0xA),
+        'x' => Some(0x0),
+        'c' => Some(0xB),
+        'v' => Some(0xF),
+        _ => None,
+    }
+}
+
+/// Tracks which keys are held down. When `hold` is set, keys are released automatically once they
+/// haven't been pressed or repeated for that long.
+struct HeldKeys {
+    hold: Option<Duration>,
+    until: [Option<Instant>; 16],
+    pressed: u16,
+}
+
+impl HeldKeys {
+    fn new(hold: Option<Duration>) -> HeldKeys {
+        HeldKeys { hold, until: [None; 16], pressed: 0 }
+    }
+
+    /// Returns true if the key wasn't already held
+    fn press(&mut self, key: u8, now: Instant) -> bool {
+        self.until[key as usize] = self.hold.map(|hold| now + hold);
+        let new = self.pressed & (1 << key) == 0;
+        self.pressed |= 1 << key;
+        new
+    }
+
+    /// Returns true if the key was held
+    fn release(&mut self, key: u8) -> bool {
+        self.until[key as usize] = None;
+        let held = self.pressed & (1 << key) != 0;
+        self.pressed &= !(1 << key);
+        held
+    }
+
+    /// Release all keys that timed out, returning them
+    fn expire(&mut self, now: Instant) -> Vec<u8> {
+        let expired: Vec<u8> =
+            (0..16).filter(|&key| self.until[key as usize].is_some_and(|t| t <= now)).collect();
+        for &key in &expired {
+            self.release(key);
+        }
+        expired
+    }
+}
+
+#[test]
+fn test_render() {
+    let palette = Palette::default();
+    let mut display = [0; WIDTH as usize / 8 * HEIGHT as usize];
+    // Top left pixel, and the pixel below the one to its right
+    display[0] = 0b1000_0000;
+    display[WIDTH as usize / 8] = 0b0100_0000;
+
+    let rows = render(&display, &palette, Mode::HalfBlock);
+    assert_eq!(rows.len(), HEIGHT as usize / 2);
+    let white_on_black = "\x1b[38;2;255;255;255;48;2;0;0;0m";
+    let black_on_white = "\x1b[38;2;0;0;0;48;2;255;255;255m";
+    let black = "\x1b[38;2;0;0;0;48;2;0;0;0m";
+    assert!(rows[0].starts_with(&format!("{}▀{}▀{} ", white_on_black, black_on_white, black)));
+    assert!(rows[1].starts_with(&format!("{}{}", black, " ".repeat(WIDTH as usize))));
+
+    let rows = render(&display, &palette, Mode::Braille);
+    assert_eq!(rows.len(), HEIGHT as usize / 4);
+    assert!(rows[0].starts_with(&format!("{}⠑⠀", white_on_black)));
+}
+
+#[test]
+fn test_braille() {
+    assert_eq!(braille(|_, _| false), '⠀');
+    assert_eq!(braille(|_, _| true), '⣿');
+    assert_eq!(braille(|x, _| x == 0), '⡇');
+    assert_eq!(braille(|_, y| y == 3), '⣀');
+}
+
+#[test]
+fn test_held_keys() {
+    let start = Instant::now();
+    let mut keys = HeldKeys::new(Some(HOLD_TIME));
+    assert!(keys.press(0x5, start));
+    assert!(!keys.press(0x5, start + HOLD_TIME / 2));
+    assert!(keys.expire(start + HOLD_TIME).is_empty());
+    assert_eq!(keys.expire(start + HOLD_TIME * 2), [0x5]);
+    assert!(!keys.release(0x5));
+
+    let mut keys = HeldKeys::new(None);
+    assert!(keys.press(0xF, start));
+    assert!(keys.expire(start + HOLD_TIME * 100).is_empty());
+    assert!(keys.release(0xF));
+}
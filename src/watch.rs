@@ -0,0 +1,69 @@
+//! Polls a file for changes, for reloading a ROM while it is being developed.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct Watcher {
+    path: PathBuf,
+    /// The modification time and size of the file when it was last reported
+    reported: Option<(SystemTime, u64)>,
+    /// The modification time and size of the file when it was last polled
+    polled: Option<(SystemTime, u64)>,
+    last_poll: Instant,
+}
+
+impl Watcher {
+    pub fn new(path: &Path) -> Watcher {
+        let stamp = stamp(path);
+        Watcher { path: path.into(), reported: stamp, polled: stamp, last_poll: Instant::now() }
+    }
+
+    /// Returns true once the file has changed, at most checking every `POLL_INTERVAL`
+    pub fn changed(&mut self) -> bool {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = Instant::now();
+        self.poll()
+    }
+
+    /// A change is only reported once the file has stayed the same for one poll, so that a file
+    /// isn't reloaded while it is still being written
+    fn poll(&mut self) -> bool {
+        let stamp = stamp(&self.path);
+        let stable = stamp == self.polled;
+        self.polled = stamp;
+
+        if stable && stamp.is_some() && stamp != self.reported {
+            self.reported = stamp;
+            return true;
+        }
+        false
+    }
+}
+
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[test]
+fn test_watcher() {
+    let path = std::env::temp_dir().join(format!("chip8_emu_test_{}.ch8", std::process::id()));
+    fs::write(&path, [0x12, 0x00]).unwrap();
+
+    let mut watcher = Watcher::new(&path);
+    assert!(!watcher.poll());
+
+    fs::write(&path, [0x12, 0x00, 0x00, 0xE0]).unwrap();
+    let changes: Vec<bool> = (0..3).map(|_| watcher.poll()).collect();
+
+    fs::remove_file(&path).unwrap();
+    assert_eq!(changes, [false, true, false]);
+    assert!(!watcher.poll());
+}